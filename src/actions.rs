@@ -0,0 +1,335 @@
+// MIT License
+//
+// Copyright (c) 2017 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Local actions run when an ICE is triggered
+//!
+//! Commands receive the ICE metadata through the following environment
+//! variables, and the message of the ICE through stdin:
+//!
+//! - `SIMPLEICE_DESCRIPTION`: short description of the ICE
+//! - `SIMPLEICE_RECIPIENTS`: comma-separated list of recipients
+//! - `SIMPLEICE_SEND_DATE`: scheduled date of the ICE (`yyyy-mm-dd HH:MM`)
+//!
+//! Any other `SIMPLEICE_` variable of the environment, such as configuration
+//! overrides, is removed so that settings and secrets do not leak to actions.
+
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::prelude::*;
+use libc;
+
+use parser::Ice;
+use pgp::PgpSettings;
+use scheduler;
+
+/// Default timeout for actions, in seconds
+pub const DEFAULT_TIMEOUT: u64 = 60;
+
+/// Maximum number of bytes of output kept from an action
+const MAX_OUTPUT: usize = 4096;
+
+/// Prefix of the environment variables used by simpleice
+const ENV_PREFIX: &str = "SIMPLEICE_";
+
+/// What an action does when triggered
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ActionKind {
+    /// Run a command through the shell
+    Command {
+        command: String
+    },
+    /// Copy a file into a directory
+    Publish {
+        file: String,
+        directory: String
+    },
+    /// Import a revocation certificate into the PGP keyring and optionally
+    /// send the revoked key to a keyserver
+    Revoke {
+        certificate: String,
+        key: String,
+        keyserver: Option<String>
+    }
+}
+
+/// Outcome of the last execution of an action
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ActionResult {
+    date: DateTime<Local>,
+    status: Option<i32>,
+    timed_out: bool,
+    output: String
+}
+
+impl ActionResult {
+    /// Whether the action finished successfully
+    pub fn is_success(&self) -> bool {
+        !self.timed_out && self.status == Some(0)
+    }
+
+    /// Get a short line describing the result
+    pub fn get_status_line(&self) -> String {
+        let status = if self.timed_out {
+            "timed out".to_string()
+        } else {
+            match self.status {
+                Some(v) => format!("exit status {}", v),
+                None => "failed to run".to_string()
+            }
+        };
+
+        format!("{} ({})", status, self.date.format("%F %R"))
+    }
+
+    /// Get the captured output of the action
+    pub fn get_output(&self) -> &str {
+        &self.output
+    }
+}
+
+/// Action run when an ICE is triggered
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Action {
+    kind: ActionKind,
+    timeout: u64,
+    last_result: Option<ActionResult>
+}
+
+impl Action {
+    /// Create a new action
+    ///
+    /// # Arguments
+    ///
+    /// * `kind` - What the action does
+    /// * `timeout` - Maximum time (in seconds) the action may run for
+    pub fn new(kind: ActionKind, timeout: u64) -> Action {
+        Action {
+            kind,
+            timeout,
+            last_result: None
+        }
+    }
+
+    /// Get a short description of the action
+    pub fn get_description(&self) -> String {
+        match self.kind {
+            ActionKind::Command { ref command } => format!("Run `{}`", command),
+            ActionKind::Publish { ref file, ref directory } => {
+                format!("Publish '{}' to '{}'", file, directory)
+            },
+            ActionKind::Revoke { ref key, .. } => format!("Revoke key '{}'", key)
+        }
+    }
+
+    /// Get the result of the last execution, if any
+    pub fn get_last_result(&self) -> Option<&ActionResult> {
+        self.last_result.as_ref()
+    }
+
+    /// Run the action for the given ICE and record its result
+    ///
+    /// # Arguments
+    ///
+    /// * `ice` - ICE that triggered the action
+    /// * `pgp_settings` - PGP settings, needed to revoke keys
+    pub fn run(&mut self, ice: &Ice, pgp_settings: &Option<PgpSettings>) -> &ActionResult {
+        let timeout = Duration::from_secs(self.timeout);
+
+        let (status, timed_out, output) = match self.kind {
+            ActionKind::Command { ref command } => {
+                let mut process = Command::new("sh");
+                process.arg("-c").arg(command);
+                run_process(process, ice, timeout)
+            },
+            ActionKind::Publish { ref file, ref directory } => publish(file, directory),
+            ActionKind::Revoke { ref certificate, ref key, ref keyserver } => {
+                match *pgp_settings {
+                    Some(ref settings) => {
+                        let (status, timed_out, mut output) = run_process(
+                            settings.command(&["--import", certificate]), ice, timeout
+                        );
+
+                        match (status, keyserver) {
+                            (Some(0), Some(server)) => {
+                                let (status, timed_out, send_output) = run_process(
+                                    settings.command(&["--keyserver", server, "--send-keys", key]),
+                                    ice,
+                                    timeout
+                                );
+                                output.push_str(&send_output);
+                                (status, timed_out, output)
+                            },
+                            _ => (status, timed_out, output)
+                        }
+                    },
+                    None => (None, false, "There is no PGP configuration".to_string())
+                }
+            }
+        };
+
+        self.last_result = Some(ActionResult {
+            date: Local::now(),
+            status,
+            timed_out,
+            output
+        });
+
+        self.last_result.as_ref().unwrap()
+    }
+}
+
+/// Copy a file into a directory
+fn publish(file: &str, directory: &str) -> (Option<i32>, bool, String) {
+    let source = Path::new(file);
+    let name = match source.file_name() {
+        Some(v) => v,
+        None => return (None, false, "Invalid file to publish".to_string())
+    };
+
+    match fs::copy(source, Path::new(directory).join(name)) {
+        Ok(_) => (Some(0), false, String::new()),
+        Err(e) => (None, false, format!("Failed to publish file: {}", e))
+    }
+}
+
+/// Run a process for an ICE, killing it if it exceeds the timeout
+///
+/// Returns the exit status (if the process could be run and exited normally),
+/// whether it timed out and its combined output.
+fn run_process(mut command: Command, ice: &Ice, timeout: Duration) -> (Option<i32>, bool, String) {
+    for (variable, _) in env::vars_os() {
+        if variable.to_string_lossy().starts_with(ENV_PREFIX) {
+            command.env_remove(variable);
+        }
+    }
+
+    command
+        .env("SIMPLEICE_DESCRIPTION", ice.get_description())
+        .env("SIMPLEICE_RECIPIENTS", ice.get_emails().join(","))
+        .env("SIMPLEICE_SEND_DATE", ice.get_date_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0);
+
+    let mut child = match command.spawn() {
+        Ok(v) => v,
+        Err(e) => return (None, false, format!("Failed to run action: {}", e))
+    };
+
+    // Feed the message from a separate thread to avoid blocking on full pipes
+    let stdin = child.stdin.take();
+    let message = ice.get_message();
+    thread::spawn(move || {
+        if let Some(mut stdin) = stdin {
+            let _ = stdin.write_all(message.as_bytes());
+        }
+    });
+
+    let stdout = read_pipe(child.stdout.take());
+    let stderr = read_pipe(child.stderr.take());
+
+    let (status, timed_out) = wait_timeout(&mut child, timeout);
+
+    // Background processes may keep the pipes open, do not wait for them
+    let grace = Instant::now();
+    while !(stdout.1.is_finished() && stderr.1.is_finished())
+        && grace.elapsed() < Duration::from_secs(1) {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let mut buffer = stdout.0.lock().unwrap().clone();
+    buffer.extend_from_slice(&stderr.0.lock().unwrap());
+    buffer.truncate(MAX_OUTPUT);
+
+    (status, timed_out, String::from_utf8_lossy(&buffer).into_owned())
+}
+
+/// Read a pipe to completion in a separate thread
+///
+/// The output is made available as it is read, so that it can be obtained
+/// even if the pipe is never closed.
+/// Only the first `MAX_OUTPUT` bytes are kept, the rest is read and discarded
+/// so that the process does not block on a full pipe.
+fn read_pipe<R>(pipe: Option<R>) -> (Arc<Mutex<Vec<u8>>>, thread::JoinHandle<()>)
+    where R: Read + Send + 'static {
+    let buffer = Arc::new(Mutex::new(Vec::new()));
+    let shared = buffer.clone();
+
+    let handle = thread::spawn(move || {
+        let mut pipe = match pipe {
+            Some(v) => v,
+            None => return
+        };
+
+        let mut chunk = [0u8; 1024];
+        loop {
+            match pipe.read(&mut chunk) {
+                Ok(0) | Err(_) => return,
+                Ok(n) => {
+                    let mut buffer = shared.lock().unwrap();
+                    let kept = n.min(MAX_OUTPUT.saturating_sub(buffer.len()));
+                    buffer.extend_from_slice(&chunk[..kept]);
+                }
+            }
+        }
+    });
+
+    (buffer, handle)
+}
+
+/// Wait for a child process, killing it after the timeout
+fn wait_timeout(child: &mut Child, timeout: Duration) -> (Option<i32>, bool) {
+    let start = Instant::now();
+
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return (status.code(), false),
+            Ok(None) => (),
+            Err(_) => return (None, false)
+        }
+
+        // Long actions are fine as long as they end within their timeout
+        scheduler::keep_alive();
+
+        if start.elapsed() >= timeout {
+            // Kill the whole process group, including any processes spawned
+            // by the shell
+            unsafe {
+                libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+            }
+            let _ = child.wait();
+            return (None, true);
+        }
+
+        thread::sleep(Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,175 @@
+// MIT License
+//
+// Copyright (c) 2017 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+//! Source of the current date
+//!
+//! Commands obtain the date from a `Clock` instead of the system, so that
+//! `check --at` can check as of another date and the daemon can notice when
+//! the wall clock jumps. Jumps are measured against the time since boot,
+//! which keeps counting while the system is suspended and is never set.
+
+use std::time::Duration as StdDuration;
+
+use chrono::prelude::*;
+use chrono::Duration;
+use libc;
+
+/// Default number of seconds the wall clock may jump before the daemon
+/// holds the ICE mails it made due
+pub const DEFAULT_MAX_CLOCK_JUMP: u64 = 300;
+
+/// Source of the current date
+pub trait Clock {
+    /// Get the current date
+    fn now(&self) -> DateTime<Local>;
+
+    /// Get the time elapsed since an arbitrary point, unaffected by changes
+    /// of the wall clock
+    fn uptime(&self) -> StdDuration;
+}
+
+/// Wall clock of the system
+pub struct SystemClock;
+
+/// Clock stopped at a given date
+pub struct FixedClock(pub DateTime<Local>);
+
+/// Reading of both the wall clock and the uptime of a clock
+#[derive(Clone, Copy)]
+pub struct Reading {
+    pub date: DateTime<Local>,
+    uptime: StdDuration
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+
+    fn uptime(&self) -> StdDuration {
+        let mut time = libc::timespec {tv_sec: 0, tv_nsec: 0};
+
+        // Unlike the monotonic clock, it keeps counting while suspended
+        unsafe {
+            libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut time);
+        }
+
+        StdDuration::new(time.tv_sec as u64, time.tv_nsec as u32)
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Local> {
+        self.0
+    }
+
+    fn uptime(&self) -> StdDuration {
+        StdDuration::ZERO
+    }
+}
+
+/// Read the wall clock and the uptime of a clock
+///
+/// # Arguments
+///
+/// * `clock` - Clock to read
+pub fn read(clock: &dyn Clock) -> Reading {
+    Reading {
+        date: clock.now(),
+        uptime: clock.uptime()
+    }
+}
+
+/// Obtain how far the wall clock jumped between two readings
+///
+/// Positive when it went forward, negative when it went backward.
+///
+/// # Arguments
+///
+/// * `before` - Earlier reading
+/// * `after` - Later reading of the same clock
+pub fn get_jump(before: &Reading, after: &Reading) -> Duration {
+    let elapsed = Duration::from_std(after.uptime.saturating_sub(before.uptime))
+        .unwrap_or_else(|_| Duration::zero());
+
+    (after.date - before.date) - elapsed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(date: DateTime<Local>, uptime: u64) -> Reading {
+        Reading {date, uptime: StdDuration::from_secs(uptime)}
+    }
+
+    #[test]
+    fn fixed_clock_does_not_jump() {
+        let clock = FixedClock(Local.with_ymd_and_hms(2026, 10, 18, 12, 0, 0).unwrap());
+        let before = read(&clock);
+        let after = read(&clock);
+
+        assert_eq!(after.date, clock.0);
+        assert_eq!(get_jump(&before, &after), Duration::zero());
+    }
+
+    #[test]
+    fn no_jump_when_wall_clock_follows_uptime() {
+        let date = Local.with_ymd_and_hms(2026, 10, 18, 12, 0, 0).unwrap();
+        let before = reading(date, 100);
+        let after = reading(date + Duration::seconds(60), 160);
+
+        assert_eq!(get_jump(&before, &after), Duration::zero());
+    }
+
+    #[test]
+    fn forward_and_backward_jumps() {
+        let date = Local.with_ymd_and_hms(2026, 10, 18, 12, 0, 0).unwrap();
+        let before = reading(date, 100);
+
+        let forward = reading(date + Duration::hours(2), 160);
+        assert_eq!(get_jump(&before, &forward), Duration::hours(2) - Duration::seconds(60));
+
+        let backward = reading(date - Duration::hours(1), 160);
+        assert_eq!(get_jump(&before, &backward), -Duration::hours(1) - Duration::seconds(60));
+    }
+
+    #[test]
+    fn suspend_is_not_a_jump() {
+        // The time since boot keeps counting while the system is suspended
+        let date = Local.with_ymd_and_hms(2026, 10, 18, 12, 0, 0).unwrap();
+        let before = reading(date, 100);
+        let after = reading(date + Duration::hours(8), 100 + 8 * 3600);
+
+        assert_eq!(get_jump(&before, &after), Duration::zero());
+    }
+
+    #[test]
+    fn uptime_going_back_counts_as_nothing_elapsed() {
+        let date = Local.with_ymd_and_hms(2026, 10, 18, 12, 0, 0).unwrap();
+        let before = reading(date, 100);
+        let after = reading(date + Duration::seconds(30), 50);
+
+        assert_eq!(get_jump(&before, &after), Duration::seconds(30));
+    }
+}
@@ -22,148 +22,2886 @@
 
 //! Application commands
 
-use std::error::Error;
-use std::{thread, time};
+use std::env;
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time;
 
 use chrono::prelude::*;
+use chrono::Duration;
 use console::{Term, style};
-use dialoguer::{Confirmation, Editor, Input, Select};
-use ini::Ini;
-use lettre::email::EmailBuilder;
-use lettre::transport::smtp::{SecurityLevel, SmtpTransport,
-SmtpTransportBuilder};
-use lettre::transport::EmailTransport;
-
+use dialoguer::{Confirmation, Editor, Input, PasswordInput, Select};
+use lettre::email::{Email, EmailBuilder, SendableEmail};
+use libc;
+use lock;
+use lock::StoreLock;
+use logger;
+use logger::Level;
+use metrics;
+use metrics::Transport;
+use serde_json;
+
+use actions::{Action, ActionKind, DEFAULT_TIMEOUT};
+use clock;
+use clock::Clock;
+use config;
+use config::{AccountConfig, Config, StoreConfig};
+use control;
+use control::{DaemonStatus, HeldIce, Listener, ProfileStatus, Request, Response};
+use cron;
+use http;
 use parser;
-use parser::Ice;
+use oauth;
+use parser::{DeliveryRecord, Ice, PendingDelivery, SharedSecret};
+use password::{Keyring, PasswordSource};
+use pgp;
+use scheduler;
+use scheduler::Wake;
+use shamir;
+use signals;
+use systemd;
+use watchdog;
+use watchdog::Alert;
+use smtp;
+use smtp::{Mailer, TlsMode};
+use webhook;
+use webhook::{Webhook, DEFAULT_TEMPLATE};
+
+/// Times a command waits for a check to release the lock of the JSON file
+const LOCK_ATTEMPTS: u32 = 20;
+
+/// Milliseconds between two attempts to lock the JSON file
+const LOCK_RETRY_INTERVAL: u64 = 250;
+
+/// Options for the `check` command
+#[derive(Default)]
+pub struct CheckOptions {
+    /// Only report what would be done, without sending anything or updating
+    /// the JSON file
+    pub dry_run: bool,
+    /// Identifiers of the ICE mails not to send even if due
+    pub held: Vec<String>
+}
 
 /// Check if there are ICE mails to send
 ///
+/// The JSON file is locked during the check. When a daemon is processing it,
+/// the daemon is asked to check instead. Checks that go through record a
+/// heartbeat for the `watchdog` command.
+///
 /// # Arguments
 ///
 /// * `term` - Terminal abstraction
 /// * `conf` - Application configuration
-pub fn check(term: &Term, conf: &Ini) {
-    let mut ices = match parser::get_ices(&conf) {
+/// * `options` - Options for the check
+/// * `clock` - Source of the current date
+pub fn check(term: &Term, conf: &Config, options: &CheckOptions, clock: &dyn Clock) {
+    // Dry runs do not send nor write anything, no need to lock
+    if options.dry_run {
+        run_check(term, conf, options, clock);
+        return;
+    }
+
+    let store = match parser::get_store_path(conf) {
         Ok(v) => v,
         Err(e) => {
-            term.write_line(format!("Error: {}", e).as_str());
+            logger::log(Level::Error, e);
             return;
         }
     };
 
-    if ices.is_empty() {
-        term.write_line("No ICE mails to show");
-        return;
+    let _lock = match lock::try_lock(&store) {
+        Ok(Some(v)) => v,
+        Ok(None) => {
+            defer_check(&store);
+            return;
+        },
+        Err(e) => {
+            logger::log(Level::Error, &e);
+            return;
+        }
+    };
+
+    if run_check(term, conf, options, clock) {
+        record_heartbeat(&store, clock);
     }
+}
 
-    // Parse email configuration
-    let mail_section = match conf.section(Some("mail".to_owned())) {
-        Some(v) => v,
-        None => {
-            term.write_line("No mail configuration found");
+/// Record that the scheduler went through a JSON file
+///
+/// # Arguments
+///
+/// * `store` - Path of the JSON file
+/// * `clock` - Source of the current date
+fn record_heartbeat(store: &Path, clock: &dyn Clock) {
+    if let Err(e) = watchdog::write_heartbeat(store, clock.now()) {
+        logger::log(Level::Warn, &e);
+    }
+}
+
+/// Alert the owner when the scheduler stopped working
+///
+/// The heartbeat of the scheduler and the ICE mails are only read, so the
+/// command can run on another host or from another crontab, against the same
+/// JSON file.
+///
+/// # Arguments
+///
+/// * `conf` - Application configuration
+/// * `clock` - Source of the current date
+pub fn watchdog(conf: &Config, clock: &dyn Clock) {
+    let store = match parser::get_store_path(conf) {
+        Ok(v) => v,
+        Err(e) => {
+            logger::log(Level::Error, e);
             return;
         }
     };
 
-    let server = match mail_section.get("server") {
-        Some(v) => v,
-        None => {
-            term.write_line("No server address found");
-            return;
+    let mut problems = Vec::new();
+    let now = clock.now();
+
+    let heartbeat = match watchdog::read_heartbeat(&store) {
+        Ok(v) => v,
+        Err(e) => {
+            problems.push(e);
+            None
         }
     };
 
-    let port = match mail_section.get("port") {
-        Some(v) => v.parse::<u16>().unwrap(),
-        None => {
-            term.write_line("No server port found");
+    let ices = match parser::get_ices(conf) {
+        Ok(v) => v,
+        Err(e) => {
+            problems.push(format!("Cannot read the ICE mails: {}", e));
+            Vec::new()
+        }
+    };
+
+    problems.extend(watchdog::get_problems(
+        heartbeat,
+        &ices,
+        now,
+        Duration::seconds(conf.get_max_age() as i64),
+        Duration::seconds(conf.watchdog.grace as i64)
+    ));
+
+    if problems.is_empty() {
+        logger::log(Level::Info, &format!("The scheduler of {} is working", store.display()));
+
+        // Problems found again later are alerted right away
+        if let Err(e) = watchdog::clear_alert(&store) {
+            logger::log(Level::Warn, &e);
+        }
+        return;
+    }
+
+    for problem in &problems {
+        logger::log(Level::Warn, problem);
+    }
+
+    let last = match watchdog::read_alert(&store) {
+        Ok(v) => v,
+        Err(e) => {
+            logger::log(Level::Warn, &e);
+            None
+        }
+    };
+
+    let repeat = Duration::seconds(conf.watchdog.repeat as i64);
+    if let Some(ref last) = last {
+        if watchdog::is_repeated(last, &problems, now, repeat) {
+            logger::log(Level::Info, &format!(
+                "The owner was already alerted at {}, not alerting again", last.date.format("%F %R")
+            ));
             return;
         }
+    }
+
+    let message = format!(
+        "The scheduler of {} ({} profile) needs attention:\n\n{}\n\n\
+        Active ICE mails may not be sent when they are due.\n",
+        store.display(),
+        config::get_profile(),
+        problems.iter().map(|p| format!("- {}", p)).collect::<Vec<String>>().join("\n")
+    );
+
+    if send_alert(conf, "[simpleice] Watchdog alert", &message) {
+        if let Err(e) = watchdog::write_alert(&store, &Alert {date: now, problems}) {
+            logger::log(Level::Warn, &e);
+        }
+    }
+}
+
+/// Email the owner about a problem of the scheduler
+///
+/// The alert goes to `watchdog.owner` or the sender address, through the
+/// first account that accepts it. Returns whether it was sent.
+///
+/// # Arguments
+///
+/// * `conf` - Application configuration
+/// * `subject` - Subject of the mail
+/// * `message` - Body of the mail
+fn send_alert(conf: &Config, subject: &str, message: &str) -> bool {
+    let owner = match conf.watchdog.owner {
+        Some(ref v) => v.clone(),
+        None => match get_sender(conf) {
+            Ok(v) => v,
+            Err(e) => {
+                logger::log(Level::Error, e);
+                return false;
+            }
+        }
+    };
+
+    let mut mailers = match get_mailers(conf) {
+        Ok(v) => v,
+        Err(e) => {
+            logger::log(Level::Error, &e);
+            return false;
+        }
     };
 
-    let sender = match mail_section.get("address") {
+    let mut sent = false;
+    for mailer in &mut mailers {
+        let email = EmailBuilder::new()
+            .to(owner.as_str())
+            .from(mailer.get_settings().get_address())
+            .subject(subject)
+            .body(message)
+            .build();
+
+        let result = match email {
+            Ok(v) => mailer.send(&v),
+            Err(e) => Err(e.to_string())
+        };
+
+        match result {
+            Ok(_) => {
+                logger::log(Level::Info, &format!("Alert sent to {}", owner));
+                sent = true;
+                break;
+            },
+            Err(e) => logger::log(Level::Warn, &format!(
+                "Account '{}' failed: {}", mailer.get_settings().get_name(), e
+            ))
+        }
+    }
+
+    for mailer in &mut mailers {
+        mailer.close();
+    }
+
+    if !sent {
+        logger::log(Level::Error, &format!("No alert could be sent to {}", owner));
+    }
+
+    sent
+}
+
+/// Hand a check over to the process holding the lock of the JSON file
+///
+/// The daemon is asked through its control socket, or with a signal when it
+/// does not listen on one.
+///
+/// # Arguments
+///
+/// * `store` - Path of the JSON file
+fn defer_check(store: &Path) {
+    // The daemon answers once it is done checking
+    match control::send(store, &Request::TriggerCheck) {
+        Some(Ok(_)) => {
+            logger::log(Level::Info, &format!(
+                "A daemon is processing {}, it checked on request", store.display()
+            ));
+            return;
+        },
+        Some(Err(e)) => {
+            logger::log(Level::Error, &format!(
+                "A daemon is processing {}, not checking: {}", store.display(), e
+            ));
+            return;
+        },
+        None => ()
+    }
+
+    let pid = match lock::get_daemon_pid(store) {
         Some(v) => v,
         None => {
-            term.write_line("No sender address found");
+            logger::log(Level::Warn, &format!(
+                "Another check is processing {}, not checking again", store.display()
+            ));
             return;
         }
     };
 
-    let password = match mail_section.get("password") {
-        Some(v) => v,
+    match lock::request_check(pid) {
+        Ok(_) => logger::log(Level::Info, &format!(
+            "A daemon (PID {}) is processing {}, it was asked to check now",
+            pid, store.display()
+        )),
+        Err(e) => logger::log(Level::Error, &format!(
+            "A daemon (PID {}) is processing {}, not checking: {}", pid, store.display(), e
+        ))
+    };
+}
+
+/// Send the ICE mails that are due
+///
+/// The caller is responsible for locking the JSON file.
+///
+/// # Arguments
+///
+/// * `term` - Terminal abstraction
+/// * `conf` - Application configuration
+/// * `options` - Options for the check
+/// * `clock` - Source of the current date
+///
+/// Returns whether the ICE mails could be read and saved, regardless of the
+/// outcome of the deliveries.
+fn run_check(term: &Term, conf: &Config, options: &CheckOptions, clock: &dyn Clock) -> bool {
+    let mut ices = match parser::get_ices(&conf) {
+        Ok(v) => v,
+        Err(e) => {
+            logger::log(Level::Error, e);
+            return false;
+        }
+    };
+
+    if ices.is_empty() {
+        logger::log(Level::Info, "No ICE mails to check");
+        return true;
+    }
+
+    let pgp_settings = match pgp::get_settings(conf) {
+        Ok(v) => v,
+        Err(e) => {
+            logger::log(Level::Error, e);
+            return false;
+        }
+    };
+
+    // Mailers only connect when sending, so dry runs never touch the server
+    let mut mailers = match get_mailers(conf) {
+        Ok(v) => v,
+        Err(e) => {
+            logger::log(Level::Error, &e);
+            return false;
+        }
+    };
+
+    let now = clock.now();
+
+    if options.dry_run {
+        let _ = term.write_line(
+            format!("Dry run, checking as of {}\n", now.format("%F %R")).as_str()
+        );
+    }
+
+    // Send mails
+    let mut due = 0;
+    for ice in &mut ices {
+        if !ice.is_active() {continue;}
+        scheduler::keep_alive();
+
+        let send_date = ice.get_date();
+        // Date cannot be empty
+        if send_date.is_none() {
+            logger::log_ice(Level::Warn, ice, &format!(
+                "ICE '{}' is active but does not have a date", ice.get_description()
+            ));
+            continue;
+        }
+
+        // Check date
+        if send_date.unwrap() <= now {
+            if options.held.iter().any(|id| id == ice.get_id()) {
+                logger::log_ice(Level::Warn, ice, &format!(
+                    "ICE '{}' is held until the clock is confirmed", ice.get_description()
+                ));
+                continue;
+            }
+
+            // Nothing would be delivered, keep the ICE armed until it is fixed
+            if !ice.has_deliveries() {
+                logger::log_ice(Level::Warn, ice, &format!(
+                    "ICE '{}' does not send mail, call webhooks or run actions, keeping it active",
+                    ice.get_description()
+                ));
+                continue;
+            }
+
+            due += 1;
+
+            let order = account_order(&mailers, ice);
+            let sender = mailers[order[0]].get_settings().get_address().to_string();
+
+            // Build every mail before sending any of them
+            let prepared = prepare_deliveries(ice).and_then(|(planned, deliveries)| {
+                let emails = prepare_emails(&deliveries, &sender, &pgp_settings)?;
+                Ok((planned, deliveries, emails))
+            });
+
+            let (planned, deliveries, emails) = match prepared {
+                Ok(v) => v,
+                Err(e) => {
+                    logger::log_ice(Level::Error, ice, &format!(
+                        "Failed to prepare '{}': {}", ice.get_description(), e
+                    ));
+                    continue;
+                }
+            };
+
+            if options.dry_run {
+                report_dry_run(term, ice, &emails);
+                continue;
+            }
+
+            // Send mail
+            if !emails.is_empty() {
+                logger::log_ice(Level::Info, ice, &format!(
+                    "Sending mail for '{}'", ice.get_description()
+                ));
+            }
+
+            // Once triggered, only the deliveries that failed are left
+            let retry = !ice.get_pending().is_empty();
+
+            let mut sent = 0;
+            let mut failed = Vec::new();
+            for ((pending, delivery), email) in planned.iter().zip(&deliveries).zip(&emails) {
+                let record = send_delivery(
+                    &mut mailers, &order, delivery, email, &pgp_settings, None
+                );
+
+                if record.is_success() {
+                    sent += 1;
+                } else {
+                    failed.push(pending.clone());
+                }
+                ice.add_history(record);
+            }
+
+            // Retry later rather than triggering an ICE nobody was told about
+            if !retry && !emails.is_empty() && sent == 0 {
+                logger::log_ice(Level::Error, ice, &format!(
+                    "No mail could be sent for '{}', it will be retried on the next check",
+                    ice.get_description()
+                ));
+                continue;
+            }
+
+            // Webhooks and actions only run the first time the ICE is triggered
+            if !retry {
+                run_triggers(ice, &pgp_settings);
+            }
+
+            // Keep the ICE armed until every recipient got their mail
+            if !failed.is_empty() {
+                logger::log_ice(Level::Error, ice, &format!(
+                    "{} mail(s) for '{}' could not be sent, they will be retried on the next check",
+                    failed.len(),
+                    ice.get_description()
+                ));
+                ice.set_pending(failed);
+                continue;
+            }
+
+            // Reset ICE
+            ice.set_active(false);
+            ice.set_date(None);
+            logger::log_ice(Level::Info, ice, &format!(
+                "ICE '{}' triggered and deactivated", ice.get_description()
+            ));
+        }
+    }
+
+    if options.dry_run {
+        let _ = term.write_line(format!("{} ICE mail(s) would be triggered", due).as_str());
+        return true;
+    }
+
+    for mailer in &mut mailers {
+        mailer.close();
+    }
+
+    // Save any changes
+    match parser::write_ices(&conf, &ices) {
+        Ok(_) => {
+            logger::log(Level::Debug, "ICE mails updated");
+            true
+        },
+        Err(e) => {
+            logger::log(Level::Error, &format!("Cannot write the ICE mails: {}", e));
+            false
+        }
+    }
+}
+
+/// Call the webhooks and run the actions of a triggered ICE mail
+///
+/// # Arguments
+///
+/// * `ice` - Triggered ICE mail
+/// * `pgp_settings` - PGP settings, if any
+fn run_triggers(ice: &mut Ice, pgp_settings: &Option<pgp::PgpSettings>) {
+    let triggered = ice.clone();
+    for webhook in ice.get_webhooks_mut() {
+        scheduler::keep_alive();
+        logger::log_ice(Level::Info, &triggered, &format!(
+            "Calling webhook: {}", webhook.get_description()
+        ));
+
+        let response = webhook.deliver(&triggered);
+        metrics::record_send(Transport::Webhook, None, response.is_success());
+        if !response.is_success() {
+            logger::log_ice(Level::Error, &triggered, &format!(
+                "Webhook failed: {}", response.get_status_line()
+            ));
+        }
+    }
+
+    for action in ice.get_actions_mut() {
+        scheduler::keep_alive();
+        logger::log_ice(Level::Info, &triggered, &format!(
+            "Running action: {}", action.get_description()
+        ));
+
+        let result = action.run(&triggered, pgp_settings);
+        metrics::record_send(Transport::Action, None, result.is_success());
+        if !result.is_success() {
+            logger::log_ice(Level::Error, &triggered, &format!(
+                "Action failed: {}", result.get_status_line()
+            ));
+        }
+    }
+}
+
+/// Obtain the address ICE mails are sent from
+///
+/// # Arguments
+///
+/// * `conf` - Application configuration
+fn get_sender(conf: &Config) -> Result<String, &'static str> {
+    match conf.mail {
+        Some(ref account) => Ok(account.address.clone()),
+        None => Err("No mail configuration found")
+    }
+}
+
+/// Create a mailer for every configured account, in priority order
+///
+/// # Arguments
+///
+/// * `conf` - Application configuration
+fn get_mailers(conf: &Config) -> Result<Vec<Mailer>, String> {
+    Ok(smtp::get_accounts(conf)?.into_iter().map(Mailer::new).collect())
+}
+
+/// Obtain the order in which accounts are tried for an ICE mail
+///
+/// The account preferred by the ICE goes first, followed by the rest in
+/// priority order.
+///
+/// # Arguments
+///
+/// * `mailers` - Mailers of every account, in priority order
+/// * `ice` - ICE mail to send
+fn account_order(mailers: &[Mailer], ice: &Ice) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..mailers.len()).collect();
+
+    if let Some(name) = ice.get_account() {
+        match mailers.iter().position(|m| m.get_settings().get_name() == name) {
+            Some(i) => {
+                order.remove(i);
+                order.insert(0, i);
+            },
+            None => {
+                logger::log_ice(Level::Warn, ice, &format!(
+                    "Unknown account '{}' for '{}', using the default order",
+                    name,
+                    ice.get_description()
+                ));
+            }
+        }
+    }
+
+    order
+}
+
+/// Send a single delivery of an ICE mail, failing over between accounts
+///
+/// The mail is rebuilt for accounts with a different sender address.
+///
+/// # Arguments
+///
+/// * `mailers` - Mailers of every account
+/// * `order` - Order in which the accounts are tried
+/// * `delivery` - Delivery of the ICE mail to send
+/// * `email` - Mail built for the first account
+/// * `pgp_settings` - PGP settings, if any
+/// * `redirect` - Address to deliver the mail to instead of its recipients
+fn send_delivery(
+    mailers: &mut [Mailer],
+    order: &[usize],
+    delivery: &Ice,
+    email: &Email,
+    pgp_settings: &Option<pgp::PgpSettings>,
+    redirect: Option<&str>
+) -> DeliveryRecord {
+    let mut last_error = String::new();
+
+    for &i in order {
+        scheduler::keep_alive();
+
+        let mailer = &mut mailers[i];
+        let name = mailer.get_settings().get_name().to_string();
+        let sender = mailer.get_settings().get_address().to_string();
+
+        let email = if email.from_address() == sender {
+            email.clone()
+        } else {
+            match prepare_emails(std::slice::from_ref(delivery), &sender, pgp_settings) {
+                Ok(mut v) => v.remove(0),
+                Err(e) => {
+                    logger::log_ice(Level::Warn, delivery, &format!(
+                        "Account '{}' failed: {}", name, e
+                    ));
+                    last_error = e;
+                    continue;
+                }
+            }
+        };
+
+        let result = match redirect {
+            Some(to) => mailer.send(&RedirectedEmail {
+                email,
+                to: vec![to.to_string()]
+            }),
+            None => mailer.send(&email)
+        };
+
+        metrics::record_send(Transport::Smtp, Some(&name), result.is_ok());
+
+        match result {
+            Ok(v) => {
+                logger::log_ice(Level::Info, delivery, &format!("Sent through '{}': {}", name, v));
+                return DeliveryRecord::new(delivery.get_emails().clone(), Some(name), v.to_string());
+            },
+            Err(e) => {
+                logger::log_ice(Level::Warn, delivery, &format!(
+                    "Account '{}' failed: {}", name, e
+                ));
+                last_error = e;
+            }
+        }
+    }
+
+    DeliveryRecord::new(delivery.get_emails().clone(), None, last_error)
+}
+
+/// Mail delivered to addresses other than the ones in its headers
+///
+/// Used to send an ICE mail exactly as its recipients would see it, without
+/// it actually reaching them.
+struct RedirectedEmail {
+    email: Email,
+    to: Vec<String>
+}
+
+impl SendableEmail for RedirectedEmail {
+    fn from_address(&self) -> String {
+        self.email.from_address()
+    }
+
+    fn to_addresses(&self) -> Vec<String> {
+        self.to.clone()
+    }
+
+    fn message(&self) -> String {
+        self.email.message()
+    }
+
+    fn message_id(&self) -> String {
+        self.email.message_id()
+    }
+}
+
+/// Send an ICE mail to its owner only
+///
+/// The mail is rendered exactly as `check()` would render it, but it is only
+/// delivered to the sender address (or the address given). Shared secrets
+/// are left out, so a single mail is sent without any share. The ICE mail is
+/// not modified.
+///
+/// # Arguments
+///
+/// * `term` - Terminal abstraction
+/// * `conf` - Application configuration
+/// * `to` - Address to deliver the mail to instead of the sender address
+pub fn test_send(term: &Term, conf: &Config, to: Option<&str>) {
+    let ices = match read_ices(conf) {
+        Ok(v) => v,
+        Err(e) => {
+            let _ = term.write_line(format!("Error: {}", e).as_str());
+            return;
+        }
+    };
+
+    if ices.is_empty() {
+        let _ = term.write_line("No ICE mails to show");
+        return;
+    }
+
+    let sender = match get_sender(conf) {
+        Ok(v) => v,
+        Err(e) => {
+            let _ = term.write_line(e);
+            return;
+        }
+    };
+
+    let pgp_settings = match pgp::get_settings(conf) {
+        Ok(v) => v,
+        Err(e) => {
+            let _ = term.write_line(format!("Error: {}", e).as_str());
+            return;
+        }
+    };
+
+    // Select an ICE to send
+    let mut selection = Select::new();
+    for ice in &ices {
+        selection.item(ice.get_description().as_str());
+    }
+
+    let _ = term.write_line("Select an ICE mail to test\n");
+    let selected = selection.default(0).interact().unwrap();
+
+    let mut mailers = match get_mailers(conf) {
+        Ok(v) => v,
+        Err(e) => {
+            let _ = term.write_line(&e);
+            return;
+        }
+    };
+
+    // Shares would all end up in the same mailbox, so they are never sent
+    let mut ice = ices[selected].clone();
+    if ice.get_shared_secret().is_some() {
+        let _ = term.write_line("The shares of the secret are left out of test mails");
+        ice.set_shared_secret(None);
+    }
+    ice.set_pending(Vec::new());
+
+    let order = account_order(&mailers, &ice);
+    let first = mailers[order[0]].get_settings().get_address().to_string();
+
+    let prepared = prepare_deliveries(&ice).and_then(|(_, deliveries)| {
+        let emails = prepare_emails(&deliveries, &first, &pgp_settings)?;
+        Ok((deliveries, emails))
+    });
+
+    let (deliveries, emails) = match prepared {
+        Ok(v) => v,
+        Err(e) => {
+            let _ = term.write_line(format!("Failed to prepare mail: {}", e).as_str());
+            return;
+        }
+    };
+
+    if emails.is_empty() {
+        let _ = term.write_line("That ICE mail does not send any mail");
+        return;
+    }
+
+    let target = to.map(|v| v.to_string()).unwrap_or(sender);
+
+    for (delivery, email) in deliveries.iter().zip(emails.iter()) {
+        let _ = term.write_line(
+            format!(
+                "Sending mail for {} to {}",
+                email.to_addresses().join(", "),
+                target
+            ).as_str()
+        );
+
+        // Shown whatever the log settings are, the reply is what is tested
+        let record = send_delivery(
+            &mut mailers, &order, delivery, email, &pgp_settings, Some(&target)
+        );
+        let _ = term.write_line(record.get_status_line().as_str());
+    }
+
+    for mailer in &mut mailers {
+        mailer.close();
+    }
+}
+
+/// Show what would be done for a triggered ICE mail
+///
+/// # Arguments
+///
+/// * `term` - Terminal abstraction
+/// * `ice` - Triggered ICE mail
+/// * `emails` - Mails that would be sent
+fn report_dry_run(term: &Term, ice: &Ice, emails: &[Email]) {
+    let _ = term.write_line(
+        format!("ICE '{}' would be triggered", ice.get_description()).as_str()
+    );
+
+    for email in emails {
+        let _ = term.write_line(
+            format!("\nWould send mail to {}:", email.to_addresses().join(", ")).as_str()
+        );
+        let _ = term.write_line(format!("{}", email).as_str());
+    }
+
+    for webhook in ice.get_webhooks() {
+        let _ = term.write_line(
+            format!("\nWould call webhook {}:", webhook.get_description()).as_str()
+        );
+        let _ = term.write_line(webhook.render(ice).as_str());
+    }
+
+    for action in ice.get_actions() {
+        let _ = term.write_line(
+            format!("\nWould run action: {}", action.get_description()).as_str()
+        );
+    }
+
+    let _ = term.write_line("");
+}
+
+/// Obtain the deliveries of a triggered ICE mail, along with the mail sent
+/// for each of them
+///
+/// Shared secrets are split into one delivery per recipient. ICE mails that
+/// do not send mail have no deliveries.
+///
+/// # Arguments
+///
+/// * `ice` - Triggered ICE mail
+fn prepare_deliveries(ice: &Ice) -> Result<(Vec<PendingDelivery>, Vec<Ice>), String> {
+    if !ice.sends_mail() {
+        return Ok((Vec::new(), Vec::new()));
+    }
+
+    let deliveries = ice.get_deliveries()?;
+    let mails = deliveries.iter().map(|d| ice.get_delivery(d)).collect();
+
+    Ok((deliveries, mails))
+}
+
+/// Build the mails for the deliveries of an ICE mail
+///
+/// Mails are protected with OpenPGP if required by the ICE or the PGP
+/// settings.
+///
+/// # Arguments
+///
+/// * `deliveries` - Deliveries of the ICE mail
+/// * `sender` - Address the mails are sent from
+/// * `pgp_settings` - PGP settings, if any
+fn prepare_emails(
+    deliveries: &[Ice],
+    sender: &str,
+    pgp_settings: &Option<pgp::PgpSettings>
+) -> Result<Vec<Email>, String> {
+    let mut emails = Vec::new();
+    for delivery in deliveries {
+        let mut builder = build_email(delivery, pgp_settings)?;
+        builder.set_subject("[simpleice] ICE mail");
+        builder.add_from(sender);
+
+        emails.push(builder.build().map_err(|e| e.to_string())?);
+    }
+
+    Ok(emails)
+}
+
+/// Build the email for a single delivery of an ICE mail
+///
+/// The mail is protected with OpenPGP if required by the ICE or the PGP
+/// settings.
+///
+/// # Arguments
+///
+/// * `ice` - ICE mail to deliver
+/// * `pgp_settings` - PGP settings, if any
+fn build_email(ice: &Ice, pgp_settings: &Option<pgp::PgpSettings>) -> Result<EmailBuilder, String> {
+    match *pgp_settings {
+        Some(ref settings) if ice.is_encrypted() || settings.signs() => {
+            pgp::to_email(settings, ice)
+        },
+        None if ice.is_encrypted() => {
+            Err("ICE requires encryption but there is no PGP configuration".to_string())
+        },
+        _ => Ok(ice.to_email())
+    }
+}
+
+/// Rebuild a secret from the shares sent by a split ICE mail
+///
+/// The user is asked for the shares one by one until an empty line is
+/// entered.
+///
+/// # Arguments
+///
+/// * `term` - Terminal abstraction
+pub fn combine_shares(term: &Term) {
+    let _ = term.write_line("Please enter the shares one per line, finish with an empty line\n");
+
+    let mut shares = Vec::new();
+    loop {
+        let share = Input::new(format!("Share {}", shares.len() + 1).as_str())
+            .default("")
+            .show_default(false)
+            .interact().unwrap();
+
+        if share.trim().is_empty() {
+            break;
+        }
+
+        shares.push(share);
+    }
+
+    let _ = match shamir::combine(&shares) {
+        Ok(v) => {
+            let _ = term.write_line("");
+            term.write_line(String::from_utf8_lossy(&v).as_ref())
+        },
+        Err(e) => term.write_line(format!("Error: {}", e).as_str())
+    };
+}
+
+/// Move the plaintext SMTP password out of the configuration file
+///
+/// The password is stored in the Secret Service keyring, and the
+/// configuration file is updated to obtain it from there.
+///
+/// # Arguments
+///
+/// * `term` - Terminal abstraction
+/// * `conf` - Application configuration
+pub fn migrate_password(term: &Term, conf: &Config) {
+    // Accounts with a plaintext password
+    let accounts: Vec<(String, &AccountConfig)> = conf.get_accounts()
+        .into_iter()
+        .filter(|&(_, account)| account.password.as_ref().is_some_and(|v| !v.is_empty()))
+        .collect();
+
+    if accounts.is_empty() {
+        let _ = term.write_line("There is no plaintext password in the configuration file");
+        return;
+    }
+
+    if let Err(e) = config::check_writable() {
+        let _ = term.write_line(format!("Error: {}", e).as_str());
+        return;
+    }
+
+    // The kernel keyring is lost on reboot, which would leave ICE mails
+    // without a password when they are triggered
+    let keyring = Keyring::SecretService;
+    let mut new_conf = conf.clone();
+    let mut migrated = 0;
+
+    for (name, account) in accounts {
+        let table = config::get_account_table(&name);
+        let plaintext = account.password.as_ref().unwrap();
+
+        if let Err(e) = keyring.store(&account.address, plaintext) {
+            let _ = term.write_line(format!("[{}]: Error: {}", table, e).as_str());
+            continue;
+        }
+
+        // Make sure the password can be read back before removing it
+        match keyring.lookup(&account.address) {
+            Ok(ref v) if v == plaintext => (),
+            Ok(_) => {
+                let _ = term.write_line(
+                    format!("[{}]: Error: the stored password does not match", table).as_str()
+                );
+                continue;
+            },
+            Err(e) => {
+                let _ = term.write_line(format!("[{}]: Error: {}", table, e).as_str());
+                continue;
+            }
+        }
+
+        let new_account = new_conf.get_account_mut(&name).unwrap();
+        new_account.password = None;
+        new_account.password_keyring = Some(keyring);
+        migrated += 1;
+    }
+
+    if migrated == 0 {
+        let _ = term.write_line("No password was moved, keeping the configuration file as is");
+        return;
+    }
+
+    let _ = match config::write_config(&new_conf) {
+        Ok(_) => term.write_line(
+            format!("{} password(s) moved to the {} keyring", migrated, keyring.get_name()).as_str()
+        ),
+        Err(e) => term.write_line(format!("Error: {}", e).as_str())
+    };
+}
+
+/// Obtain an OAuth2 refresh token for an account using XOAUTH2
+///
+/// The user logs in through the browser and the refresh token is stored
+/// where the account expects its password.
+///
+/// # Arguments
+///
+/// * `term` - Terminal abstraction
+/// * `conf` - Application configuration
+pub fn auth_login(term: &Term, conf: &Config) {
+    let accounts: Vec<smtp::SmtpSettings> = match smtp::get_accounts(conf) {
+        Ok(v) => v.into_iter().filter(|a| a.get_oauth().is_some()).collect(),
+        Err(e) => {
+            let _ = term.write_line(format!("Error: {}", e).as_str());
+            return;
+        }
+    };
+
+    if accounts.is_empty() {
+        let _ = term.write_line("No account uses XOAUTH2 authentication");
+        return;
+    }
+
+    let selected = if accounts.len() == 1 {
+        0
+    } else {
+        let mut selection = Select::new();
+        for account in &accounts {
+            selection.item(
+                format!("{} ({})", account.get_name(), account.get_address()).as_str()
+            );
+        }
+
+        let _ = term.write_line("Select an account to log in\n");
+        selection.default(0).interact().unwrap()
+    };
+
+    let account = &accounts[selected];
+    let settings = account.get_oauth().unwrap();
+
+    let login = match oauth::start_login(settings) {
+        Ok(v) => v,
+        Err(e) => {
+            let _ = term.write_line(format!("Error: {}", e).as_str());
+            return;
+        }
+    };
+
+    let _ = term.write_line(
+        format!("Open the following URL in a browser to log in as {}:\n", account.get_address())
+        .as_str()
+    );
+    let _ = term.write_line(login.get_url());
+    let _ = term.write_line("\nWaiting for the login to complete...");
+
+    let token = match oauth::finish_login(settings, &login) {
+        Ok(v) => v,
+        Err(e) => {
+            let _ = term.write_line(format!("Error: {}", e).as_str());
+            return;
+        }
+    };
+
+    // Make sure the token can actually be used, keeping any rotated one
+    let token = match oauth::refresh(settings, &token) {
+        Ok(tokens) => tokens.refresh_token.unwrap_or(token),
+        Err(e) => {
+            let _ = term.write_line(
+                format!("Warning: cannot obtain an access token: {}", e).as_str()
+            );
+            token
+        }
+    };
+
+    match *account.get_password_source() {
+        PasswordSource::Keyring(ref keyring) if !keyring.persists() => {
+            let _ = term.write_line(format!(
+                "Error: the {} keyring is lost on reboot, use the secret-service keyring \
+                or `password_command` for this account",
+                keyring.get_name()
+            ).as_str());
+        },
+        PasswordSource::Keyring(ref keyring) => {
+            let _ = match keyring.store(account.get_address(), &token) {
+                Ok(_) => term.write_line(
+                    format!("Refresh token stored in the {} keyring", keyring.get_name()).as_str()
+                ),
+                Err(e) => term.write_line(format!("Error: {}", e).as_str())
+            };
+        },
+        PasswordSource::Command(_) => {
+            let _ = term.write_line(
+                "The account obtains its token from `password_command`, \
+                store this refresh token where the command expects it:\n"
+            );
+            let _ = term.write_line(token.as_str());
+        },
+        PasswordSource::Plaintext(_) | PasswordSource::None => {
+            let mut new_conf = conf.clone();
+            if let Some(new_account) = new_conf.get_account_mut(account.get_name()) {
+                new_account.password = Some(token);
+            }
+
+            let _ = match config::write_config(&new_conf) {
+                Ok(_) => term.write_line(
+                    "Refresh token stored in the configuration file, \
+                    use `migrate-password` to move it to the system keyring"
+                ),
+                Err(e) => term.write_line(format!("Error: {}", e).as_str())
+            };
+        }
+    }
+}
+
+/// Convert the legacy INI configuration file to TOML
+///
+/// # Arguments
+///
+/// * `term` - Terminal abstraction
+pub fn migrate_config(term: &Term) {
+    let (old_path, new_path) = match config::migrate() {
+        Ok(v) => v,
+        Err(e) => {
+            let _ = term.write_line(format!("Error: {}", e).as_str());
+            return;
+        }
+    };
+
+    let _ = term.write_line(
+        format!("Converted {} into {}", old_path.display(), new_path.display()).as_str()
+    );
+
+    // Paths given by the user are not looked up
+    if config::get_config_path().ok().as_ref() != Some(&new_path) {
+        let _ = term.write_line(
+            format!("Use `--config {}` (or SIMPLEICE_CONFIG) from now on", new_path.display())
+            .as_str()
+        );
+    }
+
+    let _ = term.write_line("The old file is kept, remove it once the new one works as expected");
+}
+
+/// Write systemd units for the daemon and for a timer running `check`
+///
+/// The units run this binary with the configuration file in use, so they
+/// keep working regardless of the environment of systemd.
+///
+/// # Arguments
+///
+/// * `term` - Terminal abstraction
+/// * `system` - Whether to write system units instead of user ones
+pub fn install_service(term: &Term, system: bool) {
+    let command = match get_service_command() {
+        Ok(v) => v,
+        Err(e) => {
+            let _ = term.write_line(format!("Error: {}", e).as_str());
+            return;
+        }
+    };
+
+    let unit_dir = match systemd::get_unit_dir(system) {
+        Ok(v) => v,
+        Err(e) => {
+            let _ = term.write_line(format!("Error: {}", e).as_str());
+            return;
+        }
+    };
+
+    if let Err(e) = fs::create_dir_all(&unit_dir) {
+        let _ = term.write_line(format!("Cannot create {}: {}", unit_dir.display(), e).as_str());
+        return;
+    }
+
+    for (name, contents) in systemd::get_units(&command, system) {
+        let path = unit_dir.join(name);
+        if let Err(e) = fs::write(&path, contents) {
+            let _ = term.write_line(format!("Cannot write {}: {}", path.display(), e).as_str());
+            return;
+        }
+
+        let _ = term.write_line(format!("Wrote {}", path.display()).as_str());
+    }
+
+    if !config::get_overrides(&Config::default()).is_empty() {
+        let _ = term.write_line(
+            "Environment overrides are not part of the units, set them in the configuration file"
+        );
+    }
+
+    let systemctl = if system {"systemctl"} else {"systemctl --user"};
+    let _ = term.write_line(
+        format!(
+            "\nRun the daemon with:\n\n    \
+            {0} daemon-reload\n    \
+            {0} enable --now {1}\n\n\
+            Or check periodically instead with:\n\n    \
+            {0} enable --now {2}",
+            systemctl, systemd::SERVICE_UNIT, systemd::TIMER_UNIT
+        ).as_str()
+    );
+}
+
+/// Obtain the binary and options the systemd units and crontab entries run
+fn get_service_command() -> Result<Vec<String>, String> {
+    let binary = env::current_exe()
+        .map_err(|e| format!("Cannot find the simpleice binary: {}", e))?;
+    let mut command = vec![binary.to_string_lossy().into_owned()];
+
+    // Units do not see the environment variables of the user
+    let conf_path = config::get_config_path()?;
+    if conf_path.exists() {
+        let conf_path = fs::canonicalize(&conf_path).map_err(|e| e.to_string())?;
+        command.push("--config".to_string());
+        command.push(conf_path.to_string_lossy().into_owned());
+    }
+
+    let profile = config::get_profile();
+    if profile != config::DEFAULT_PROFILE {
+        command.push("--profile".to_string());
+        command.push(profile);
+    }
+
+    Ok(command)
+}
+
+/// Add an entry running `check` to the crontab of the user
+///
+/// The entry runs this binary with the configuration file in use, so it does
+/// not depend on the `PATH` and `HOME` set by cron. An entry added before for
+/// the same profile is replaced.
+///
+/// # Arguments
+///
+/// * `term` - Terminal abstraction
+/// * `every` - How often to check, such as `5m` or `1h`
+pub fn cron_install(term: &Term, every: Option<&str>) {
+    let schedule = match cron::get_schedule(every.unwrap_or(cron::DEFAULT_EVERY)) {
+        Ok(v) => v,
+        Err(e) => {
+            let _ = term.write_line(e.as_str());
+            return;
+        }
+    };
+
+    let command = match get_service_command() {
+        Ok(v) => v,
+        Err(e) => {
+            let _ = term.write_line(format!("Error: {}", e).as_str());
+            return;
+        }
+    };
+
+    let crontab = match cron::read_crontab() {
+        Ok(v) => v,
+        Err(e) => {
+            let _ = term.write_line(format!("Error: {}", e).as_str());
+            return;
+        }
+    };
+
+    let tag = cron::get_tag(&config::get_profile());
+    let entry = cron::get_entry(&schedule, &command);
+
+    if let Err(e) = cron::write_crontab(&cron::add_entry(&crontab, &tag, &entry, &command[0])) {
+        let _ = term.write_line(format!("Error: {}", e).as_str());
+        return;
+    }
+
+    let _ = term.write_line(format!("Added to the crontab:\n\n    {}", entry).as_str());
+
+    if !config::get_overrides(&Config::default()).is_empty() {
+        let _ = term.write_line(
+            "Environment overrides are not part of the entry, set them in the configuration file"
+        );
+    }
+}
+
+/// Remove the entry running `check` from the crontab of the user
+///
+/// Only entries running this binary are removed, other lines following the
+/// tag are left for the user to review.
+///
+/// # Arguments
+///
+/// * `term` - Terminal abstraction
+pub fn cron_uninstall(term: &Term) {
+    let binary = match env::current_exe() {
+        Ok(v) => v.to_string_lossy().into_owned(),
+        Err(e) => {
+            let _ = term.write_line(
+                format!("Error: cannot find the simpleice binary: {}", e).as_str()
+            );
+            return;
+        }
+    };
+
+    let crontab = match cron::read_crontab() {
+        Ok(v) => v,
+        Err(e) => {
+            let _ = term.write_line(format!("Error: {}", e).as_str());
+            return;
+        }
+    };
+
+    let profile = config::get_profile();
+    let tag = cron::get_tag(&profile);
+    let entry = match cron::find_entry(&crontab, &tag) {
+        Some(v) => v,
+        None => {
+            let _ = term.write_line(
+                format!("No crontab entry for the {} profile", profile).as_str()
+            );
+            return;
+        }
+    };
+
+    if !cron::is_entry(&entry, &binary) {
+        let _ = term.write_line(format!(
+            "The line after '{}' does not run {}, edit the crontab with `crontab -e` instead",
+            tag, binary
+        ).as_str());
+        return;
+    }
+
+    let _ = match cron::write_crontab(&cron::remove_entry(&crontab, &tag, &binary)) {
+        Ok(_) => term.write_line(format!("Crontab entry of the {} profile removed", profile).as_str()),
+        Err(e) => term.write_line(format!("Error: {}", e).as_str())
+    };
+}
+
+/// Show whether an entry running `check` is in the crontab of the user
+///
+/// # Arguments
+///
+/// * `term` - Terminal abstraction
+pub fn cron_status(term: &Term) {
+    let crontab = match cron::read_crontab() {
+        Ok(v) => v,
+        Err(e) => {
+            let _ = term.write_line(format!("Error: {}", e).as_str());
+            return;
+        }
+    };
+
+    let profile = config::get_profile();
+    let entry = match cron::find_entry(&crontab, &cron::get_tag(&profile)) {
+        Some(v) => v,
+        None => {
+            let _ = term.write_line(format!("Not installed for the {} profile", profile).as_str());
+            return;
+        }
+    };
+
+    let _ = term.write_line(
+        format!("Installed for the {} profile:\n\n    {}", profile, entry).as_str()
+    );
+
+    // The binary or the configuration file may have moved since
+    let current = get_service_command()
+        .map(|command| cron::get_entry("", &command))
+        .unwrap_or_default();
+    if !entry.ends_with(current.trim_start()) {
+        let _ = term.write_line(
+            "\nThe entry does not match the binary and configuration file in use, \
+            run `cron install` again to update it"
+        );
+    }
+}
+
+/// Outcome of a single check of the `doctor` command
+enum Finding {
+    Ok,
+    Warning,
+    Error
+}
+
+/// Print the outcome of a check
+///
+/// # Arguments
+///
+/// * `term` - Terminal abstraction
+/// * `counts` - Number of warnings and errors found so far
+/// * `finding` - Outcome of the check
+/// * `message` - Description of the outcome
+fn report(term: &Term, counts: &mut (u32, u32), finding: Finding, message: &str) {
+    let label = match finding {
+        Finding::Ok => style("ok     ").green(),
+        Finding::Warning => {
+            counts.0 += 1;
+            style("warning").yellow()
+        },
+        Finding::Error => {
+            counts.1 += 1;
+            style("error  ").red()
+        }
+    };
+
+    let _ = term.write_line(format!("[{}] {}", label, message).as_str());
+}
+
+/// Check the configuration, the ICE mails and the connection to the servers
+///
+/// Nothing is sent and nothing is modified.
+///
+/// # Arguments
+///
+/// * `term` - Terminal abstraction
+/// * `conf` - Application configuration
+/// * `clock` - Source of the current date
+pub fn doctor(term: &Term, conf: &Config, clock: &dyn Clock) {
+    let mut counts = (0, 0);
+
+    let profile = config::get_profile();
+    if profile != config::DEFAULT_PROFILE {
+        report(term, &mut counts, Finding::Ok, format!("Using the {} profile", profile).as_str());
+    }
+
+    // Configuration file
+    if let Ok(path) = config::get_config_path() {
+        if !path.exists() {
+            report(term, &mut counts, Finding::Ok, format!(
+                "{} does not exist, using environment variables only", path.display()
+            ).as_str());
+        } else if let Ok(metadata) = fs::metadata(&path) {
+            if metadata.permissions().mode() & 0o077 != 0 {
+                report(term, &mut counts, Finding::Warning, format!(
+                    "{} is accessible by other users (mode {:o}), consider `chmod 600`",
+                    path.display(),
+                    metadata.permissions().mode() & 0o777
+                ).as_str());
+            } else {
+                report(term, &mut counts, Finding::Ok, format!(
+                    "{} is only accessible by its owner", path.display()
+                ).as_str());
+            }
+
+            if !config::is_toml(&path) {
+                report(term, &mut counts, Finding::Warning, format!(
+                    "{} uses the legacy INI format, convert it with `config migrate`",
+                    path.display()
+                ).as_str());
+            }
+        }
+    }
+
+    for o in config::get_overrides(conf) {
+        report(term, &mut counts, Finding::Ok, format!(
+            "`{}` set by {}", o.key, o.variable
+        ).as_str());
+    }
+
+    // Accounts
+    let accounts = match smtp::get_accounts(conf) {
+        Ok(v) => {
+            for account in &v {
+                report(term, &mut counts, Finding::Ok, format!(
+                    "Account '{}': {} as {}",
+                    account.get_name(),
+                    account.get_description(),
+                    account.get_address()
+                ).as_str());
+
+                if let PasswordSource::Keyring(ref keyring) = *account.get_password_source() {
+                    if !keyring.persists() {
+                        report(term, &mut counts, Finding::Warning, format!(
+                            "Account '{}' uses the {} keyring, which is lost on reboot",
+                            account.get_name(),
+                            keyring.get_name()
+                        ).as_str());
+                    }
+                }
+            }
+            v
+        },
+        Err(e) => {
+            report(term, &mut counts, Finding::Error, e.as_str());
+            Vec::new()
+        }
+    };
+
+    // PGP
+    let pgp_settings = match pgp::get_settings(conf) {
+        Ok(Some(v)) => {
+            report(term, &mut counts, Finding::Ok, "PGP configuration");
+            Some(v)
+        },
+        Ok(None) => None,
+        Err(e) => {
+            report(term, &mut counts, Finding::Error, e);
+            None
+        }
+    };
+
+    // Store
+    let ices = doctor_store(term, &mut counts, conf);
+
+    // ICE mails
+    let now = clock.now();
+    for ice in &ices {
+        let name = ice.get_description();
+
+        if ice.is_active() {
+            match ice.get_date() {
+                None => report(term, &mut counts, Finding::Error, format!(
+                    "'{}' is active but has no date", name
+                ).as_str()),
+                Some(date) if date <= now => report(term, &mut counts, Finding::Warning, format!(
+                    "'{}' is overdue, it will be triggered on the next check", name
+                ).as_str()),
+                Some(_) => ()
+            }
+
+            if ice.sends_mail() && ice.get_emails().is_empty() {
+                report(term, &mut counts, Finding::Error, format!(
+                    "'{}' is active but has no recipients", name
+                ).as_str());
+            }
+
+            if !ice.has_deliveries() {
+                report(term, &mut counts, Finding::Error, format!(
+                    "'{}' is active but does not send mail, call webhooks or run actions", name
+                ).as_str());
+            }
+        }
+
+        if let Some(shared) = ice.get_shared_secret() {
+            if shared.get_threshold() as usize > ice.get_emails().len() {
+                report(term, &mut counts, Finding::Error, format!(
+                    "'{}' needs {} shares but only has {} recipients",
+                    name,
+                    shared.get_threshold(),
+                    ice.get_emails().len()
+                ).as_str());
+            }
+        }
+
+        if ice.is_encrypted() || pgp_settings.as_ref().is_some_and(|s| s.signs()) {
+            match pgp_settings {
+                Some(ref settings) => {
+                    let missing = pgp::missing_keys(settings, ice);
+                    if !missing.is_empty() {
+                        report(term, &mut counts, Finding::Error, format!(
+                            "'{}' is missing PGP keys for: {}", name, missing.join(", ")
+                        ).as_str());
+                    }
+                },
+                None => report(term, &mut counts, Finding::Error, format!(
+                    "'{}' requires encryption but there is no PGP configuration", name
+                ).as_str())
+            }
+        }
+
+        if let Some(account) = ice.get_account() {
+            if !accounts.is_empty() && !accounts.iter().any(|a| a.get_name() == account) {
+                report(term, &mut counts, Finding::Warning, format!(
+                    "'{}' prefers unknown account '{}'", name, account
+                ).as_str());
+            }
+        }
+    }
+
+    // Servers
+    for account in accounts {
+        let name = account.get_name().to_string();
+        let mut mailer = Mailer::new(account);
+
+        match mailer.connect() {
+            Ok(_) => report(term, &mut counts, Finding::Ok, format!(
+                "Account '{}': connection and authentication succeeded", name
+            ).as_str()),
+            Err(e) => report(term, &mut counts, Finding::Error, format!(
+                "Account '{}': {}", name, e
+            ).as_str())
+        }
+
+        mailer.close();
+    }
+
+    let _ = term.write_line(
+        format!("\n{} warning(s), {} error(s)", counts.0, counts.1).as_str()
+    );
+}
+
+/// Check the JSON file and parse every ICE mail in it
+///
+/// The file is read directly, even with a daemon running, so that every ICE
+/// mail is parsed on its own and the file itself can be checked. Nothing is
+/// written, so the lock of the daemon is not needed.
+///
+/// Returns the ICE mails that could be parsed.
+///
+/// # Arguments
+///
+/// * `term` - Terminal abstraction
+/// * `counts` - Number of warnings and errors found so far
+/// * `conf` - Application configuration
+fn doctor_store(term: &Term, counts: &mut (u32, u32), conf: &Config) -> Vec<Ice> {
+    let path = match parser::get_store_path(conf) {
+        Ok(v) => v,
+        Err(e) => {
+            report(term, counts, Finding::Error, e);
+            return Vec::new();
+        }
+    };
+
+    // The file and its directories are created when the first ICE mail is added
+    if !path.exists() {
+        let directory = path.ancestors()
+            .skip(1)
+            .find(|p| p.exists())
+            .filter(|p| !p.as_os_str().is_empty())
+            .map(|p| p.to_path_buf())
+            .unwrap_or_else(|| PathBuf::from("."));
+
+        if is_writable(&directory) {
+            report(term, counts, Finding::Warning, format!(
+                "{} does not exist yet, it will be created", path.display()
+            ).as_str());
+        } else {
+            report(term, counts, Finding::Error, format!(
+                "{} does not exist and {} is not writable", path.display(), directory.display()
+            ).as_str());
+        }
+
+        return Vec::new();
+    }
+
+    let content = match fs::read_to_string(&path) {
+        Ok(v) => v,
+        Err(e) => {
+            report(term, counts, Finding::Error, format!(
+                "Cannot read {}: {}", path.display(), e
+            ).as_str());
+            return Vec::new();
+        }
+    };
+
+    if !is_writable(&path) {
+        report(term, counts, Finding::Error, format!(
+            "{} is not writable, ICE mails cannot be updated", path.display()
+        ).as_str());
+    }
+
+    if let Ok(metadata) = fs::metadata(&path) {
+        if metadata.permissions().mode() & 0o077 != 0 {
+            report(term, counts, Finding::Warning, format!(
+                "{} is accessible by other users (mode {:o}), consider `chmod 600`",
+                path.display(),
+                metadata.permissions().mode() & 0o777
+            ).as_str());
+        }
+    }
+
+    // Parse each ICE on its own to point at the broken ones
+    let values: Vec<serde_json::Value> = match serde_json::from_str(&content) {
+        Ok(v) => v,
+        Err(e) => {
+            report(term, counts, Finding::Error, format!(
+                "{} is not a valid list of ICE mails: {}", path.display(), e
+            ).as_str());
+            return Vec::new();
+        }
+    };
+
+    let mut ices = Vec::new();
+    for (i, value) in values.into_iter().enumerate() {
+        let description = value.get("description")
+            .and_then(|v| v.as_str())
+            .map(|v| format!("'{}'", v))
+            .unwrap_or_else(|| format!("#{}", i + 1));
+
+        match serde_json::from_value::<Ice>(value) {
+            Ok(v) => ices.push(v),
+            Err(e) => report(term, counts, Finding::Error, format!(
+                "ICE mail {} cannot be parsed: {}", description, e
+            ).as_str())
+        }
+    }
+
+    report(term, counts, Finding::Ok, format!(
+        "{}: {} ICE mail(s) parsed", path.display(), ices.len()
+    ).as_str());
+
+    ices
+}
+
+/// Check if the current user can write to a path
+fn is_writable(path: &Path) -> bool {
+    let path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(v) => v,
+        Err(_) => return false
+    };
+
+    unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 }
+}
+
+/// Create the configuration file through a series of questions
+///
+/// # Arguments
+///
+/// * `term` - Terminal abstraction
+pub fn create_config(term: &Term) {
+    let conf_path = match config::get_config_path() {
+        Ok(v) => v,
+        Err(e) => {
+            let _ = term.write_line(e);
+            return;
+        }
+    };
+
+    // Other profiles in the file are kept as they are
+    let profile = config::get_profile();
+    let exists = if profile == config::DEFAULT_PROFILE {
+        conf_path.exists()
+    } else {
+        config::read_config().is_ok()
+    };
+
+    if exists && !Confirmation::new(format!(
+        "The {} profile in {} already exists, overwrite it?", profile, conf_path.display()
+    ).as_str()).default(false).interact().unwrap() {
+        return;
+    }
+
+    if profile == config::DEFAULT_PROFILE {
+        let _ = term.write_line("Creating the configuration file");
+    } else {
+        let _ = term.write_line(format!("Creating the {} profile", profile).as_str());
+    }
+    let _ = term.write_line("Press enter to accept the value between brackets\n");
+
+    // Sender account
+    let address = ask("Sender address (also used to log in)", None, validate_address);
+    let domain = address.rsplit('@').next().unwrap_or("").to_lowercase();
+
+    let mut selection = Select::new();
+    let mut default = config::PRESETS.len();
+    for (i, preset) in config::PRESETS.iter().enumerate() {
+        selection.item(format!("{} ({}:{})", preset.name, preset.server, preset.port).as_str());
+        if preset.domains.contains(&domain.as_str()) {
+            default = i;
+        }
+    }
+    selection.item("Other server");
+
+    let _ = term.write_line("\nSelect the mail provider\n");
+    let selected = selection.default(default).interact().unwrap();
+
+    let preset = config::PRESETS.get(selected);
+    let (server, port, tls) = match preset {
+        Some(preset) => (preset.server.to_string(), preset.port, preset.tls),
+        None => {
+            let server = ask("SMTP server", None, validate_server);
+            let port = ask("SMTP port", Some("587"), validate_port).parse::<u16>().unwrap();
+
+            let modes = [TlsMode::StartTls, TlsMode::Implicit, TlsMode::Opportunistic, TlsMode::None];
+            let default = if port == 465 {1} else {0};
+
+            let _ = term.write_line("\nSelect how the connection is encrypted\n");
+            let selected = Select::new()
+                .item("STARTTLS (usually port 587)")
+                .item("Implicit TLS (usually port 465)")
+                .item("STARTTLS if available (insecure)")
+                .item("None (insecure)")
+                .default(default)
+                .interact().unwrap();
+
+            (server, port, modes[selected])
+        }
+    };
+
+    let mut account = AccountConfig::new(&address, &server);
+    account.port = Some(port);
+    account.tls = tls;
+
+    // Self-signed certificates fail verification unless trusted explicitly
+    if let Some(prompt) = preset.and_then(|p| p.ca_prompt) {
+        let _ = term.write_line("");
+        account.ca_file = Some(PathBuf::from(ask(prompt, None, validate_ca_file)));
+    }
+
+    // Password
+    let _ = term.write_line("\nSelect how the password is obtained\n");
+    let selected = Select::new()
+        .item("Secret Service (GNOME Keyring, KWallet...)")
+        .item("Output of a command (e.g. `pass show smtp`)")
+        .item("Plaintext in the configuration file")
+        .default(0)
+        .interact().unwrap();
+
+    // Only stored in the keyring once the configuration is saved
+    let mut keyring_password = None;
+
+    match selected {
+        0 => {
+            let password = PasswordInput::new("SMTP password")
+                .confirm("Repeat the password", "The passwords do not match")
+                .interact().unwrap();
+
+            account.password_keyring = Some(Keyring::SecretService);
+            keyring_password = Some(password);
+        },
+        1 => {
+            let command = ask("Command printing the password", None, validate_not_empty);
+            account.password_command = Some(command);
+        },
+        _ => {
+            let password = PasswordInput::new("SMTP password")
+                .confirm("Repeat the password", "The passwords do not match")
+                .interact().unwrap();
+            account.password = Some(password);
+        }
+    }
+
+    // Storage of the ICE mails
+    let default_store = match config::get_default_store_path() {
+        Ok(v) if profile == config::DEFAULT_PROFILE => v.to_string_lossy().into_owned(),
+        Ok(v) => v.with_file_name(format!("{}.json", profile)).to_string_lossy().into_owned(),
+        Err(e) => {
+            let _ = term.write_line(e);
+            return;
+        }
+    };
+    let _ = term.write_line("");
+    let store = ask("File to store the ICE mails in", Some(default_store.as_str()), validate_store);
+
+    let conf = Config {
+        mail: Some(account),
+        json: StoreConfig {path: Some(PathBuf::from(&store))},
+        ..Config::default()
+    };
+
+    if let Err(e) = smtp::validate(&conf) {
+        let _ = term.write_line(format!("Invalid mail configuration: {}", e).as_str());
+        return;
+    }
+
+    // Live test, does not send anything
+    if Confirmation::new("\nTest the connection to the server now?")
+        .default(true)
+        .interact().unwrap() {
+        // The keyring is not written yet, test with the password as given
+        let mut test_conf = conf.clone();
+        if let Some(ref password) = keyring_password {
+            let test_account = test_conf.mail.as_mut().unwrap();
+            test_account.password_keyring = None;
+            test_account.password = Some(password.clone());
+        }
+
+        let mut mailer = Mailer::new(smtp::get_accounts(&test_conf).unwrap().remove(0));
+        let _ = term.write_line(
+            format!("Connecting to {}...", mailer.get_settings().get_description()).as_str()
+        );
+
+        match mailer.connect() {
+            Ok(_) => {
+                let _ = term.write_line("Connection and authentication succeeded");
+            },
+            Err(e) => {
+                let _ = term.write_line(format!("Error: {}", e).as_str());
+                if !Confirmation::new("Save the configuration anyway?")
+                    .default(false)
+                    .interact().unwrap() {
+                    return;
+                }
+            }
+        }
+
+        mailer.close();
+    }
+
+    if let Some(parent) = Path::new(&store).parent() {
+        if let Err(e) = fs::create_dir_all(parent) {
+            let _ = term.write_line(format!("Cannot create {}: {}", parent.display(), e).as_str());
+            return;
+        }
+    }
+
+    if let Some(ref password) = keyring_password {
+        if let Err(e) = Keyring::SecretService.store(&address, password) {
+            let _ = term.write_line(format!("Error: {}", e).as_str());
+            return;
+        }
+    }
+
+    let _ = match config::write_config(&conf) {
+        Ok(_) => term.write_line(format!("Configuration written to {}", conf_path.display()).as_str()),
+        Err(e) => term.write_line(e.as_str())
+    };
+}
+
+/// Ask for a value until it is valid
+///
+/// # Arguments
+///
+/// * `prompt` - Question to ask
+/// * `default` - Value used when the answer is empty
+/// * `validate` - Check the answer, returning the reason it is invalid
+fn ask(prompt: &str, default: Option<&str>, validate: fn(&str) -> Result<(), &'static str>) -> String {
+    let term = Term::stderr();
+
+    loop {
+        let mut input = Input::new(prompt);
+        if let Some(v) = default {
+            input.default(v);
+        }
+
+        let value = input.interact().unwrap().trim().to_string();
+        match validate(&value) {
+            Ok(_) => return value,
+            Err(e) => {
+                let _ = term.write_line(e);
+            }
+        }
+    }
+}
+
+/// Check that a value is not empty
+fn validate_not_empty(value: &str) -> Result<(), &'static str> {
+    if value.is_empty() {
+        return Err("A value is required");
+    }
+
+    Ok(())
+}
+
+/// Check that a value looks like a mail address
+fn validate_address(value: &str) -> Result<(), &'static str> {
+    let mut parts = value.rsplitn(2, '@');
+    let domain = parts.next().unwrap_or("");
+    let local = parts.next().unwrap_or("");
+
+    if local.is_empty() || domain.is_empty() || !domain.contains('.')
+        || value.contains(char::is_whitespace) {
+        return Err("Invalid address, expected something like user@example.com");
+    }
+
+    Ok(())
+}
+
+/// Check that a value looks like a host name
+fn validate_server(value: &str) -> Result<(), &'static str> {
+    if value.is_empty() || value.contains(char::is_whitespace) || value.contains('/') {
+        return Err("Invalid server, expected a host name such as smtp.example.com");
+    }
+
+    Ok(())
+}
+
+/// Check that a value is a valid port
+fn validate_port(value: &str) -> Result<(), &'static str> {
+    match value.parse::<u16>() {
+        Ok(v) if v != 0 => Ok(()),
+        _ => Err("Invalid port, expected a number between 1 and 65535")
+    }
+}
+
+/// Check that a value is a certificate file
+fn validate_ca_file(value: &str) -> Result<(), &'static str> {
+    let path = Path::new(value);
+
+    if !path.is_absolute() {
+        return Err("An absolute path is required");
+    }
+
+    if !path.is_file() {
+        return Err("The file does not exist");
+    }
+
+    Ok(())
+}
+
+/// Check that a value can be used as the JSON file
+fn validate_store(value: &str) -> Result<(), &'static str> {
+    let path = Path::new(value);
+
+    if !path.is_absolute() {
+        return Err("An absolute path is required");
+    }
+
+    if path.is_dir() {
+        return Err("The path is a directory");
+    }
+
+    Ok(())
+}
+
+/// Run in daemon mode
+///
+/// The daemon mode is an infinite loop that runs the `check()` function for
+/// each profile whenever an ICE mail is due, sleeping in between. The JSON
+/// files are read again when they change and at least every
+/// `max_poll_interval` seconds. Note that other periodic execution
+/// mechanisms, such as cron, are preferred.
+///
+/// SIGTERM and SIGINT stop the daemon after the current check, SIGHUP reloads
+/// the configuration and SIGUSR1 checks every profile immediately. Under
+/// systemd, readiness, status and watchdog pings are reported as well.
+///
+/// ICE mails that become due only because the wall clock jumped forward by
+/// more than `max_clock_jump` seconds are held, and the owner alerted, until
+/// `confirm-clock` is run or they are not due anymore.
+///
+/// # Arguments
+///
+/// * `term` - Terminal abstraction
+/// * `all_profiles` - Whether to check every profile or only the selected one
+/// * `clock` - Source of the current date
+pub fn daemon(term: &Term, all_profiles: bool, clock: &dyn Clock) {
+    let mut profiles = match read_daemon_profiles(all_profiles) {
+        Ok(v) => v,
+        Err(e) => {
+            logger::log(Level::Error, &e);
+            return;
+        }
+    };
+
+    let mut stores = match get_store_paths(&profiles) {
+        Ok(v) => v,
+        Err(e) => {
+            logger::log(Level::Error, &e);
+            return;
+        }
+    };
+    let mut locks = Vec::new();
+    if let Err(e) = lock_stores(&mut locks, &stores) {
+        logger::log(Level::Error, &e);
+        return;
+    }
+
+    if let Err(e) = signals::install() {
+        logger::log(Level::Error, &e);
+        return;
+    }
+
+    let mut listeners = Vec::new();
+    if let Err(e) = listen_stores(&mut listeners, &stores) {
+        logger::log(Level::Error, &e);
+        return;
+    }
+
+    // Served as long as the daemon runs, even if the configuration changes
+    let metrics_address = profiles.iter().find_map(|(_, conf)| conf.daemon.metrics_address.clone());
+    if let Some(address) = metrics_address {
+        if let Err(e) = metrics::serve(&address) {
+            logger::log(Level::Error, &e);
+            return;
+        }
+    }
+
+    let started = clock.now();
+    let mut last_checks: Vec<Option<DateTime<Local>>> = vec![None; profiles.len()];
+    let mut announced = None;
+    let mut previous = clock::read(clock);
+    // ICE mails held after a clock jump, by JSON file
+    let mut held: Vec<(PathBuf, HeldIce)> = Vec::new();
+    // Connections waiting for a requested check to finish
+    let mut waiting: Vec<UnixStream> = Vec::new();
+    systemd::notify_ready("Starting");
+
+    loop {
+        if signals::take_reload() {
+            logger::log(Level::Info, "Reloading the configuration");
+            systemd::notify_reloading();
+
+            // JSON files of the new configuration are locked before switching
+            let reloaded = read_daemon_profiles(all_profiles).and_then(|v| {
+                let new_stores = get_store_paths(&v)?;
+                lock_stores(&mut locks, &new_stores)?;
+                listen_stores(&mut listeners, &new_stores)?;
+                Ok((v, new_stores))
+            });
+
+            match reloaded {
+                Ok((v, new_stores)) => {
+                    profiles = v;
+                    stores = new_stores;
+                    last_checks = vec![None; profiles.len()];
+                    announced = None;
+                    metrics::clear_profiles();
+                },
+                Err(e) => {
+                    logger::log(Level::Error, &format!("Keeping the previous configuration: {}", e));
+                }
+            }
+
+            release_stores(&mut locks, &mut listeners, &stores);
+            systemd::notify_ready("Reloaded");
+        }
+
+        let forced = signals::take_check() || !waiting.is_empty();
+        if forced {
+            logger::log(Level::Info, "Check requested");
+        }
+
+        let reading = clock::read(clock);
+        let now = reading.date;
+        let jump = clock::get_jump(&previous, &reading);
+        previous = reading;
+
+        let mut next_check: Option<DateTime<Local>> = None;
+        let mut next_ice: Option<(DateTime<Local>, String)> = None;
+
+        for ((name, conf), last_check) in profiles.iter().zip(last_checks.iter_mut()) {
+            if signals::is_shutdown_requested() {
+                break;
+            }
+
+            scheduler::keep_alive();
+            let max_poll = Duration::seconds(conf.daemon.max_poll_interval as i64);
+
+            let store = parser::get_store_path(conf).unwrap_or_default();
+
+            // Unreadable files are read again on the next poll
+            let mut ices = match parser::get_ices(conf) {
+                Ok(v) => Some(v),
+                Err(e) => {
+                    logger::log(Level::Error, e);
+                    None
+                }
+            };
+
+            if let Some(ref i) = ices {
+                // Held until confirmed, changed or not due anymore
+                held.retain(|(path, h)| *path != store || scheduler::is_still_held(h, i, now));
+
+                if jump.num_seconds().abs() > conf.daemon.max_clock_jump as i64 {
+                    // Dates of past checks cannot be trusted anymore
+                    *last_check = None;
+                    hold_jumped_ices(conf, &store, i, now, jump, &mut held);
+                }
+            }
+
+            let due = ices.as_ref()
+                .is_some_and(|i| scheduler::get_next_check(i, *last_check, now, max_poll) <= now);
+            if forced || due {
+                if profiles.len() > 1 {
+                    logger::log(Level::Info, &format!("Checking profile '{}'", name));
+                }
+
+                let options = CheckOptions {
+                    held: held.iter()
+                        .filter(|(path, _)| *path == store)
+                        .map(|(_, h)| h.id.clone())
+                        .collect(),
+                    ..CheckOptions::default()
+                };
+
+                if run_check(term, conf, &options, clock) {
+                    metrics::set_last_check(name, now);
+                }
+                *last_check = Some(now);
+                ices = parser::get_ices(conf).ok();
+            }
+
+            if let Some(ref i) = ices {
+                metrics::set_ices(name, i, now);
+                record_heartbeat(&store, clock);
+            }
+
+            let next = match ices {
+                Some(ref i) => scheduler::get_next_check(i, *last_check, now, max_poll),
+                None => now + max_poll
+            };
+            next_check = Some(next_check.map_or(next, |n| n.min(next)));
+
+            if let Some(ice) = ices.as_ref().and_then(|i| scheduler::get_next_ice(i)) {
+                let date = ice.get_date().unwrap();
+                if next_ice.as_ref().is_none_or(|n| date < n.0) {
+                    next_ice = Some((date, ice.get_description()));
+                }
+            }
+        }
+
+        metrics::set_next_due(next_ice.as_ref().map(|n| n.0));
+
+        let response = if signals::is_shutdown_requested() {
+            Response::Error {message: "The daemon is shutting down".to_string()}
+        } else {
+            Response::Done
+        };
+
+        for stream in waiting.drain(..) {
+            let _ = control::write_response(&stream, &response);
+        }
+
+        // Only stopped between checks, so the JSON files are never half written
+        if signals::is_shutdown_requested() {
+            logger::log(Level::Info, "Shutting down");
+            systemd::notify_stopping();
+            return;
+        }
+
+        let next_check = next_check.unwrap();
+        if announced != Some(next_check) {
+            logger::log(Level::Info, &format!("Next check at {}", next_check.format("%F %T")));
+            announced = Some(next_check);
+        }
+
+        let status = match next_ice {
+            _ if !held.is_empty() => format!(
+                "{} ICE mail(s) held after a clock jump, run confirm-clock to send them",
+                held.len()
+            ),
+            Some((date, description)) => format!(
+                "Next ICE mail '{}' due at {}", description, date.format("%F %R")
+            ),
+            None => "No ICE mail scheduled".to_string()
+        };
+
+        systemd::notify_status(&status);
+
+        let (store, stream) = match scheduler::sleep_until(clock, next_check, &stores, &listeners) {
+            Wake::StoreChanged => {
+                logger::log(Level::Debug, "JSON file changed, rescheduling");
+                continue;
+            },
+            Wake::Request(store, stream) => (store, stream),
+            _ => continue
+        };
+
+        let request = match control::read_request(&stream) {
+            Ok(v) => v,
+            Err(e) => {
+                logger::log(Level::Warn, &format!("Control socket: {}", e));
+                let _ = control::write_response(&stream, &Response::Error {message: e});
+                continue;
+            }
+        };
+
+        let response = match request {
+            // Answered once the check is done
+            Request::TriggerCheck => {
+                waiting.push(stream);
+                continue;
+            },
+            Request::ConfirmClock => {
+                let count = held.len();
+                held.retain(|(path, _)| *path != store);
+                logger::log(Level::Info, &format!(
+                    "Clock confirmed, {} held ICE mail(s) released", count - held.len()
+                ));
+
+                waiting.push(stream);
+                continue;
+            },
+            Request::Status => Response::Status {
+                status: DaemonStatus {
+                    pid: process::id(),
+                    started,
+                    next_check: announced,
+                    profiles: profiles.iter()
+                        .zip(last_checks.iter())
+                        .map(|((name, conf), last_check)| {
+                            let store = parser::get_store_path(conf).unwrap_or_default();
+                            ProfileStatus {
+                                name: name.clone(),
+                                last_check: *last_check,
+                                held: held.iter()
+                                    .filter(|(path, _)| *path == store)
+                                    .map(|(_, h)| h.clone())
+                                    .collect(),
+                                store
+                            }
+                        })
+                        .collect()
+                }
+            },
+            request => {
+                let conf = profiles.iter()
+                    .map(|(_, conf)| conf)
+                    .find(|conf| parser::get_store_path(conf).is_ok_and(|s| s == store));
+                answer_request(conf, request, clock.now())
+            }
+        };
+
+        if let Err(e) = control::write_response(&stream, &response) {
+            logger::log(Level::Warn, &format!("Control socket: {}", e));
+        }
+    }
+}
+
+/// Hold the ICE mails made due by a clock jump and alert the owner
+///
+/// Backward jumps only delay the ICE mails, so they are just reported.
+///
+/// # Arguments
+///
+/// * `conf` - Configuration of the profile
+/// * `store` - Path of the JSON file of the profile
+/// * `ices` - ICE mails of the profile
+/// * `now` - Current date
+/// * `jump` - How far the clock jumped, forward when positive
+/// * `held` - ICE mails held by the daemon
+fn hold_jumped_ices(
+    conf: &Config,
+    store: &Path,
+    ices: &[Ice],
+    now: DateTime<Local>,
+    jump: Duration,
+    held: &mut Vec<(PathBuf, HeldIce)>
+) {
+    let direction = if jump > Duration::zero() {"forward"} else {"backward"};
+    logger::log(Level::Warn, &format!(
+        "The clock jumped {} by {} seconds", direction, jump.num_seconds().abs()
+    ));
+
+    let mut lines = Vec::new();
+    for ice in scheduler::get_jumped_ices(ices, now, jump) {
+        let already = held.iter().any(|(path, h)| path == store && h.id == ice.get_id());
+        if already {
+            continue;
+        }
+
+        logger::log_ice(Level::Warn, ice, &format!(
+            "ICE '{}' became due because of the clock jump, holding it", ice.get_description()
+        ));
+
+        lines.push(format!("- '{}', due at {}", ice.get_description(), ice.get_date_string()));
+        held.push((store.to_path_buf(), HeldIce {
+            id: ice.get_id().to_string(),
+            description: ice.get_description(),
+            date: ice.get_date().unwrap()
+        }));
+    }
+
+    if lines.is_empty() {
+        return;
+    }
+
+    let message = format!(
+        "The clock of the system running the daemon for {} jumped forward by {} \
+        minutes and is now {}. These ICE mails became due because of it and are \
+        not sent yet:\n\n{}\n\n\
+        If the clock is right, send them by running `simpleice confirm-clock`. \
+        Otherwise fix the clock, they are released once they are not due anymore.\n",
+        store.display(),
+        jump.num_minutes(),
+        now.format("%F %R"),
+        lines.join("\n")
+    );
+
+    send_alert(conf, "[simpleice] Clock jump", &message);
+}
+
+/// Read and validate the profiles checked by the daemon
+///
+/// # Arguments
+///
+/// * `all_profiles` - Whether to read every profile or only the selected one
+fn read_daemon_profiles(all_profiles: bool) -> Result<Vec<(String, Config)>, String> {
+    let profiles = if all_profiles {
+        config::read_profiles()?
+    } else {
+        vec![(config::get_profile(), config::read_config()?)]
+    };
+
+    for (name, conf) in &profiles {
+        smtp::validate(conf)
+            .map_err(|e| format!("Invalid mail configuration ({} profile): {}", name, e))?;
+    }
+
+    Ok(profiles)
+}
+
+/// Lock the JSON files processed by the daemon and write its PID next to them
+///
+/// Locks that are already held are kept, the ones of other files are released
+/// by `release_stores` once the files are not used anymore. Nothing changes
+/// when a file cannot be locked.
+///
+/// # Arguments
+///
+/// * `locks` - Locks held by the daemon
+/// * `stores` - Paths of the JSON files to lock
+fn lock_stores(locks: &mut Vec<StoreLock>, stores: &[PathBuf]) -> Result<(), String> {
+    let mut new_locks: Vec<StoreLock> = Vec::new();
+
+    for store in stores {
+        let held = locks.iter().chain(new_locks.iter()).any(|l| l.get_store() == store.as_path());
+        if held {
+            continue;
+        }
+
+        let mut new_lock = match lock::try_lock(store)? {
+            Some(v) => v,
+            None => return Err(match lock::get_daemon_pid(store) {
+                Some(pid) => format!(
+                    "A daemon (PID {}) is already processing {}", pid, store.display()
+                ),
+                None => format!(
+                    "Another process is processing {}, try again later", store.display()
+                )
+            })
+        };
+
+        new_lock.write_pid()?;
+        new_locks.push(new_lock);
+    }
+
+    locks.extend(new_locks);
+
+    Ok(())
+}
+
+/// Obtain the paths of the JSON files of the profiles
+///
+/// Profiles sharing a JSON file would check it once each, with different
+/// accounts, so they are refused.
+///
+/// # Arguments
+///
+/// * `profiles` - Name and configuration of the profiles
+fn get_store_paths(profiles: &[(String, Config)]) -> Result<Vec<PathBuf>, String> {
+    let mut stores: Vec<(&str, PathBuf)> = Vec::new();
+
+    for (name, conf) in profiles {
+        let store = match parser::get_store_path(conf) {
+            Ok(v) => v,
+            Err(_) => continue
+        };
+
+        if let Some((other, _)) = stores.iter().find(|(_, s)| *s == store) {
+            return Err(format!(
+                "Profiles '{}' and '{}' use the same JSON file {}", other, name, store.display()
+            ));
+        }
+
+        stores.push((name, store));
+    }
+
+    Ok(stores.into_iter().map(|(_, store)| store).collect())
+}
+
+/// Listen on the control sockets of the JSON files processed by the daemon
+///
+/// Sockets that are already open are kept, the ones of other files are closed
+/// by `release_stores`. Without its socket, commands would write a JSON file
+/// behind the back of the daemon, so nothing changes when one cannot be
+/// opened.
+///
+/// # Arguments
+///
+/// * `listeners` - Control sockets of the daemon
+/// * `stores` - Paths of the locked JSON files
+fn listen_stores(listeners: &mut Vec<Listener>, stores: &[PathBuf]) -> Result<(), String> {
+    let mut new_listeners: Vec<Listener> = Vec::new();
+
+    for store in stores {
+        let open = listeners.iter().chain(new_listeners.iter())
+            .any(|l| l.get_store() == store.as_path());
+        if open {
+            continue;
+        }
+
+        new_listeners.push(Listener::bind(store)?);
+    }
+
+    listeners.extend(new_listeners);
+
+    Ok(())
+}
+
+/// Close the control sockets and release the locks of unused JSON files
+///
+/// # Arguments
+///
+/// * `locks` - Locks held by the daemon
+/// * `listeners` - Control sockets of the daemon
+/// * `stores` - Paths of the JSON files still processed
+fn release_stores(
+    locks: &mut Vec<StoreLock>,
+    listeners: &mut Vec<Listener>,
+    stores: &[PathBuf]
+) {
+    // Sockets go first, they may only be replaced while the lock is held
+    listeners.retain(|l| stores.iter().any(|s| l.get_store() == s.as_path()));
+    locks.retain(|l| stores.iter().any(|s| l.get_store() == s.as_path()));
+}
+
+/// Answer a request of the control socket about the ICE mails of a profile
+///
+/// # Arguments
+///
+/// * `conf` - Configuration of the profile, if the JSON file is processed
+/// * `request` - Request received
+/// * `now` - Current date
+fn answer_request(conf: Option<&Config>, request: Request, now: DateTime<Local>) -> Response {
+    let conf = match conf {
+        Some(v) => v,
+        None => return Response::Error {
+            message: "The daemon does not process this JSON file anymore".to_string()
+        }
+    };
+
+    let result = match request {
+        Request::List => parser::get_ices(conf)
+            .map(|ices| Response::Ices {ices})
+            .map_err(String::from),
+        Request::Activate {id, date} => update_ice(conf, &id, Some(date), now)
+            .map(|_| Response::Done),
+        Request::Deactivate {id} => update_ice(conf, &id, None, now)
+            .map(|_| Response::Done),
+        Request::CheckIn {id, date} => postpone_ice(conf, &id, date)
+            .map(|_| Response::Done),
+        Request::Create {ice} => add_ice(conf, ice)
+            .map(|_| Response::Done),
+        Request::Edit {ice} => replace_ice(conf, &ice)
+            .map(|_| Response::Done),
+        Request::Remove {id} => delete_ice(conf, &id)
+            .map(|_| Response::Done),
+        _ => Err("Unexpected request".to_string())
+    };
+
+    result.unwrap_or_else(|message| Response::Error {message})
+}
+
+/// Activate or deactivate an ICE mail on behalf of a command
+///
+/// # Arguments
+///
+/// * `conf` - Configuration of the profile
+/// * `id` - Identifier of the ICE mail
+/// * `date` - Delivery date, `None` to deactivate
+/// * `now` - Current date
+fn update_ice(
+    conf: &Config,
+    id: &str,
+    date: Option<DateTime<Local>>,
+    now: DateTime<Local>
+) -> Result<(), String> {
+    let mut ices = parser::get_ices(conf)?;
+    let ice = ices.iter_mut()
+        .find(|ice| ice.get_id() == id)
+        .ok_or_else(|| format!("Unknown ICE mail {}", id))?;
+
+    match date {
+        Some(date) if date <= now => return Err("Date cannot be in the past".to_string()),
+        Some(_) => check_activation(conf, ice)?,
+        None if !ice.is_active() => return Err("That ICE mail is not active".to_string()),
+        None => ()
+    }
+
+    ice.set_date(date);
+    ice.set_active(date.is_some());
+
+    match date {
+        Some(_) => logger::log_ice(
+            Level::Info, ice, &format!("Activated for {}", ice.get_date_string())
+        ),
+        None => logger::log_ice(Level::Info, ice, "Deactivated")
+    }
+
+    parser::write_ices(conf, &ices).map_err(|e| e.to_string())
+}
+
+/// Postpone the delivery of an active ICE mail on behalf of a command
+///
+/// Unlike activating it again, checking in never arms an ICE mail that was
+/// sent or deactivated in the meantime, nor one whose delivery started.
+///
+/// # Arguments
+///
+/// * `conf` - Configuration of the profile
+/// * `id` - Identifier of the ICE mail
+/// * `date` - New delivery date, later than the current one
+fn postpone_ice(conf: &Config, id: &str, date: DateTime<Local>) -> Result<(), String> {
+    let mut ices = parser::get_ices(conf)?;
+    let ice = ices.iter_mut()
+        .find(|ice| ice.get_id() == id)
+        .ok_or_else(|| format!("Unknown ICE mail {}", id))?;
+
+    if !ice.is_active() {
+        return Err("That ICE mail is not active".to_string());
+    }
+
+    if !ice.get_pending().is_empty() {
+        return Err("That ICE mail is already being delivered".to_string());
+    }
+
+    if ice.get_date().is_some_and(|current| date <= current) {
+        return Err("The new date must be later than the current one".to_string());
+    }
+
+    ice.set_date(Some(date));
+    logger::log_ice(
+        Level::Info, ice, &format!("Checked in, postponed to {}", ice.get_date_string())
+    );
+
+    parser::write_ices(conf, &ices).map_err(|e| e.to_string())
+}
+
+/// Add a new ICE mail on behalf of a command
+///
+/// # Arguments
+///
+/// * `conf` - Configuration of the profile
+/// * `ice` - New ICE mail
+fn add_ice(conf: &Config, ice: Ice) -> Result<(), String> {
+    // The file is created along with the first ICE mail
+    let mut ices = match parser::get_store_path(conf)?.exists() {
+        true => parser::get_ices(conf)?,
+        false => Vec::new()
+    };
+
+    if ices.iter().any(|i| i.get_id() == ice.get_id()) {
+        return Err(format!("ICE mail {} already exists", ice.get_id()));
+    }
+
+    logger::log_ice(Level::Info, &ice, "Created");
+    ices.push(ice);
+
+    parser::write_ices(conf, &ices).map_err(|e| e.to_string())
+}
+
+/// Update the contents of an ICE mail on behalf of a command
+///
+/// # Arguments
+///
+/// * `conf` - Configuration of the profile
+/// * `edited` - Edited copy of the ICE mail
+fn replace_ice(conf: &Config, edited: &Ice) -> Result<(), String> {
+    let mut ices = parser::get_ices(conf)?;
+    let ice = ices.iter_mut()
+        .find(|ice| ice.get_id() == edited.get_id())
+        .ok_or_else(|| format!("Unknown ICE mail {}", edited.get_id()))?;
+
+    ice.set_contents(edited);
+
+    // Active ICE mails must still be deliverable
+    if ice.is_active() {
+        check_activation(conf, ice)?;
+    }
+
+    logger::log_ice(Level::Info, ice, "Edited");
+
+    parser::write_ices(conf, &ices).map_err(|e| e.to_string())
+}
+
+/// Remove an ICE mail on behalf of a command
+///
+/// # Arguments
+///
+/// * `conf` - Configuration of the profile
+/// * `id` - Identifier of the ICE mail
+fn delete_ice(conf: &Config, id: &str) -> Result<(), String> {
+    let mut ices = parser::get_ices(conf)?;
+    let index = ices.iter()
+        .position(|ice| ice.get_id() == id)
+        .ok_or_else(|| format!("Unknown ICE mail {}", id))?;
+
+    let removed = ices.remove(index);
+    logger::log_ice(Level::Info, &removed, "Removed");
+
+    parser::write_ices(conf, &ices).map_err(|e| e.to_string())
+}
+
+/// Send a request to the daemon processing the JSON file of a profile
+///
+/// Returns `None` when no daemon is listening.
+///
+/// # Arguments
+///
+/// * `conf` - Application configuration
+/// * `request` - Request to send
+fn ask_daemon(conf: &Config, request: &Request) -> Option<Result<Response, String>> {
+    let store = parser::get_store_path(conf).ok()?;
+    control::send(&store, request)
+}
+
+/// Read the ICE mails, through the daemon when one is running
+///
+/// # Arguments
+///
+/// * `conf` - Application configuration
+fn read_ices(conf: &Config) -> Result<Vec<Ice>, String> {
+    match ask_daemon(conf, &Request::List) {
+        Some(Ok(Response::Ices {ices})) => Ok(ices),
+        Some(Ok(_)) => Err("Unexpected answer from the daemon".to_string()),
+        Some(Err(e)) => Err(e),
+        None => parser::get_ices(conf).map_err(String::from)
+    }
+}
+
+/// Save a change to an ICE mail, through the daemon when one is running
+///
+/// Without a daemon, the change is applied to the JSON file as read right
+/// before writing it, as the daemon would do, holding its lock so that a
+/// check running meanwhile does not undo it. A check holding the lock is
+/// waited for a few seconds.
+///
+/// # Arguments
+///
+/// * `conf` - Application configuration
+/// * `request` - Request describing the change
+/// * `now` - Current date
+fn save_ices(conf: &Config, request: Request, now: DateTime<Local>) -> Result<(), String> {
+    let store = parser::get_store_path(conf)?;
+    let mut attempts = 0;
+
+    let response = loop {
+        if let Some(result) = control::send(&store, &request) {
+            break result?;
+        }
+
+        match lock::try_lock(&store)? {
+            Some(_lock) => break answer_request(Some(conf), request, now),
+            None if attempts < LOCK_ATTEMPTS => {
+                attempts += 1;
+                thread::sleep(time::Duration::from_millis(LOCK_RETRY_INTERVAL));
+            },
+            None => return Err(format!(
+                "A check is processing {}, try again in a moment", store.display()
+            ))
+        }
+    };
+
+    match response {
+        Response::Error {message} => Err(message),
+        _ => Ok(())
+    }
+}
+
+/// Show the state of the daemon processing the JSON file
+///
+/// # Arguments
+///
+/// * `term` - Terminal abstraction
+/// * `conf` - Application configuration
+pub fn daemon_status(term: &Term, conf: &Config) {
+    let status = match ask_daemon(conf, &Request::Status) {
+        Some(Ok(Response::Status {status})) => status,
+        Some(Ok(_)) => {
+            let _ = term.write_line("Error: Unexpected answer from the daemon");
+            return;
+        },
+        Some(Err(e)) => {
+            let _ = term.write_line(format!("Error: {}", e).as_str());
+            return;
+        },
         None => {
-            term.write_line("No server password found");
+            let _ = match parser::get_store_path(conf) {
+                Ok(v) => term.write_line(
+                    format!("No daemon is processing {}", v.display()).as_str()
+                ),
+                Err(e) => term.write_line(format!("Error: {}", e).as_str())
+            };
             return;
         }
     };
 
-    let mut mailer = SmtpTransportBuilder::new((server.as_str(), port))
-        .unwrap()
-        .credentials(sender.as_str(), password.as_str())
-        .security_level(SecurityLevel::AlwaysEncrypt)
-        .smtp_utf8(true)
-        .connection_reuse(true)
-        .build();
-
-    let now = Local::now();
-
-    // Send mails
-    for ice in &mut ices {
-        if !ice.is_active() {continue;}
-
-        let send_date = ice.get_date();
-        // Date cannot be empty
-        if send_date.is_none() {
-            //TODO log
-            term.write_line(
-                format!("ICE '{}' does not have a date!", ice.get_description()).as_str()
-            );
-            continue;
-        }
+    let _ = term.write_line(format!(
+        "Daemon running with PID {} since {}", status.pid, status.started.format("%F %T")
+    ).as_str());
 
-        // Check date
-        if send_date.unwrap() <= now {
-            // Send mail
-            let mut builder = ice.to_email();
-            builder.set_subject("[simpleice] ICE mail");
-            builder.add_from(sender.as_str());
+    if let Some(date) = status.next_check {
+        let _ = term.write_line(format!("Next check at {}", date.format("%F %T")).as_str());
+    }
 
-            term.write_line(
-                format!("Sending mail for '{}'", ice.get_description()).as_str()
-            );
+    for profile in status.profiles {
+        let last_check = match profile.last_check {
+            Some(v) => format!("last checked at {}", v.format("%F %T")),
+            None => "not checked yet".to_string()
+        };
 
-            mailer.send(builder.build().unwrap());
+        let _ = term.write_line(format!(
+            "Profile '{}' ({}): {}", profile.name, profile.store.display(), last_check
+        ).as_str());
 
-            // Reset ICE
-            ice.set_active(false);
-            ice.set_date(None);
+        for ice in profile.held {
+            let _ = term.write_line(format!(
+                "    '{}' (due at {}) is held after a clock jump, see `confirm-clock`",
+                ice.description, ice.date.format("%F %R")
+            ).as_str());
         }
     }
-
-    // Save any changes
-    match parser::write_ices(&conf, &ices) {
-        Ok(_) => term.write_line("ICE mails updated"),
-        Err(e) => term.write_line(format!("Error: {}" ,e.description()).as_str())
-    };
 }
 
-/// Run in daemon mode
+/// Send the ICE mails the daemon held after a clock jump
 ///
-/// The daemon mode is an infinite loop that runs the `check()` function every
-/// hour. Note that other periodic execution mechanisms, such as cron, are
-/// preferred.
+/// The held ICE mails are shown and sent once confirmed, as the clock is
+/// then known to be right.
 ///
 /// # Arguments
 ///
 /// * `term` - Terminal abstraction
 /// * `conf` - Application configuration
-pub fn daemon(term: &Term, conf: &Ini) {
-    loop {
-        check(term, conf);
+/// * `clock` - Source of the current date
+pub fn confirm_clock(term: &Term, conf: &Config, clock: &dyn Clock) {
+    let store = match parser::get_store_path(conf) {
+        Ok(v) => v,
+        Err(e) => {
+            let _ = term.write_line(format!("Error: {}", e).as_str());
+            return;
+        }
+    };
+
+    let held = match ask_daemon(conf, &Request::Status) {
+        Some(Ok(Response::Status {status})) => status.profiles.into_iter()
+            .filter(|p| p.store == store)
+            .flat_map(|p| p.held)
+            .collect::<Vec<HeldIce>>(),
+        Some(Ok(_)) => {
+            let _ = term.write_line("Error: Unexpected answer from the daemon");
+            return;
+        },
+        Some(Err(e)) => {
+            let _ = term.write_line(format!("Error: {}", e).as_str());
+            return;
+        },
+        None => {
+            let _ = term.write_line(
+                format!("No daemon is processing {}, nothing is held", store.display()).as_str()
+            );
+            return;
+        }
+    };
+
+    if held.is_empty() {
+        let _ = term.write_line("No ICE mail is held");
+        return;
+    }
 
-        thread::sleep(time::Duration::from_secs(3600));
+    let _ = term.write_line("These ICE mails became due because the clock jumped:\n");
+    for ice in &held {
+        let _ = term.write_line(
+            format!("    {} (due at {})", ice.description, ice.date.format("%F %R")).as_str()
+        );
     }
+    let _ = term.write_line("");
+
+    if !Confirmation::new(
+        format!("Is the current date, {}, right?", clock.now().format("%F %R")).as_str()
+    ).interact().unwrap() {
+        let _ = term.write_line(
+            "Fix the clock, held ICE mails are released once they are not due anymore"
+        );
+        return;
+    }
+
+    let _ = match ask_daemon(conf, &Request::ConfirmClock) {
+        Some(Ok(_)) => term.write_line("Held ICE mails released and checked"),
+        Some(Err(e)) => term.write_line(format!("Error: {}", e).as_str()),
+        None => term.write_line("The daemon stopped, nothing was sent")
+    };
 }
 
 /// Activate an ICE mail
@@ -175,8 +2913,9 @@ pub fn daemon(term: &Term, conf: &Ini) {
 ///
 /// * `term` - Terminal abstraction
 /// * `conf` - Application configuration
-pub fn activate_ice(term: &Term, conf: &Ini) {
-    let mut ices = match parser::get_ices(&conf) {
+/// * `clock` - Source of the current date
+pub fn activate_ice(term: &Term, conf: &Config, clock: &dyn Clock) {
+    let ices = match read_ices(conf) {
         Ok(v) => v,
         Err(e) => {
             term.write_line(format!("Error: {}", e).as_str());
@@ -199,45 +2938,172 @@ pub fn activate_ice(term: &Term, conf: &Ini) {
     let selected = selection.default(0).interact().unwrap();
     let mut edited = ices[selected].clone();
 
+    if let Err(e) = check_activation(conf, &edited) {
+        let _ = term.write_line(e.as_str());
+        return;
+    }
+
     // Ask for date
-    let mut date_string = String::new();
-    let mut date: Option<DateTime<Local>> = None;
-    let now = Local::now();
+    let now = clock.now();
+    let date = ask_date(term, now, "Date cannot be in the past");
+
+    // Update ICE
+    edited.set_date(Some(date));
+    edited.set_active(true);
+
+    term.write_line(format!("Activating ICE mail for {}...", edited.get_date_string()).as_str());
+
+    let request = Request::Activate {
+        id: edited.get_id().to_string(),
+        date: edited.get_date().unwrap()
+    };
+
+    let _ = match save_ices(conf, request, now) {
+        Ok(_) => term.write_line("ICE mail activated"),
+        Err(e) => term.write_line(format!("Error: {}", e).as_str())
+    };
+}
+
+/// Postpone the delivery of an active ICE mail
+///
+/// Checking in moves the delivery date of an ICE mail further away, without
+/// arming it again if it was sent or deactivated in the meantime.
+///
+/// # Arguments
+///
+/// * `term` - Terminal abstraction
+/// * `conf` - Application configuration
+/// * `clock` - Source of the current date
+pub fn check_in(term: &Term, conf: &Config, clock: &dyn Clock) {
+    let ices = match read_ices(conf) {
+        Ok(v) => v,
+        Err(e) => {
+            let _ = term.write_line(format!("Error: {}", e).as_str());
+            return;
+        }
+    };
+
+    if ices.is_empty() {
+        let _ = term.write_line("No ICE mails to show");
+        return;
+    }
+
+    // Select an ICE to postpone
+    let mut selection = Select::new();
+    for ice in &ices {
+        selection.item(ice.get_status_line().as_str());
+    }
+
+    let _ = term.write_line("Select an ICE mail to check in\n");
+    let selected = selection.default(0).interact().unwrap();
+    let ice = &ices[selected];
+
+    // Only active ICE mails have a date to postpone
+    let current = match ice.get_date() {
+        Some(v) if ice.is_active() => v,
+        _ => {
+            let _ = term.write_line("That ICE mail is not active");
+            return;
+        }
+    };
+
+    let _ = term.write_line(format!("Currently scheduled for {}", ice.get_date_string()).as_str());
+
+    // The new date must be later than both the current one and now
+    let now = clock.now();
+    let date = ask_date(
+        term, current.max(now), "The new date must be later than the current one"
+    );
+
+    let _ = term.write_line("Checking in...");
+
+    let request = Request::CheckIn {
+        id: ice.get_id().to_string(),
+        date
+    };
 
-    while date.is_none() {
-        date_string = Input::new("Please specify the date and time (yyyy-mm-dd HH:MM)")
+    let _ = match save_ices(conf, request, now) {
+        Ok(_) => term.write_line(
+            format!("ICE mail postponed to {}", date.format("%F %R")).as_str()
+        ),
+        Err(e) => term.write_line(format!("Error: {}", e).as_str())
+    };
+}
+
+/// Ask for a delivery date until a valid one is given
+///
+/// # Arguments
+///
+/// * `term` - Terminal abstraction
+/// * `after` - Date the given one must be later than
+/// * `message` - Message shown when it is not
+fn ask_date(term: &Term, after: DateTime<Local>, message: &str) -> DateTime<Local> {
+    loop {
+        let date_string: String = Input::new("Please specify the date and time (yyyy-mm-dd HH:MM)")
             .interact().unwrap();
 
-        date = match Local.datetime_from_str(date_string.as_str(), "%F %R") {
-            Ok(v) => {
+        let date = NaiveDateTime::parse_from_str(date_string.as_str(), "%F %R").ok()
+            .and_then(|d| Local.from_local_datetime(&d).single());
+
+        match date {
+            Some(v) => {
                 // Check if date is valid
-                if v > now {
-                    Some(v)
-                } else {
-                    term.write_line("Date cannot be in the past");
-                    None
+                if v > after {
+                    return v;
                 }
+
+                let _ = term.write_line(message);
             },
-            Err(e) => {
-                term.write_line("Invalid date format, try again");
-                println!("{}", e);
-                None
+            None => {
+                let _ = term.write_line("Invalid date format, try again");
             }
-        };
+        }
     }
+}
 
-    // Update ICE
-    edited.set_date(date);
-    edited.set_active(true);
+/// Check that an ICE mail can be delivered before activating it
+///
+/// # Arguments
+///
+/// * `conf` - Application configuration
+/// * `ice` - ICE mail to activate
+fn check_activation(conf: &Config, ice: &Ice) -> Result<(), String> {
+    if !ice.has_deliveries() {
+        return Err(
+            "ICE mail does not send mail, call webhooks or run actions".to_string()
+        );
+    }
 
-    term.write_line(format!("Activating ICE mail for {}...", edited.get_date_string()).as_str());
+    // Shared secrets need enough recipients to be rebuilt
+    if let Some(shared) = ice.get_shared_secret() {
+        if (shared.get_threshold() as usize) > ice.get_emails().len() {
+            return Err(format!(
+                "The secret needs {} shares but the ICE mail only has {} recipients",
+                shared.get_threshold(),
+                ice.get_emails().len()
+            ));
+        }
+    }
 
-    // Save edited ICE
-    ices[selected] = edited;
-    match parser::write_ices(&conf, &ices) {
-        Ok(_) => term.write_line("ICE mail activated"),
-        Err(e) => term.write_line(format!("Error: {}" ,e.description()).as_str())
-    };
+    // Make sure the mail can be encrypted and signed before arming it
+    match pgp::get_settings(conf) {
+        Ok(Some(settings)) => {
+            let missing = pgp::missing_keys(&settings, ice);
+            if !missing.is_empty() {
+                return Err(format!("Missing PGP keys for: {}", missing.join(", ")));
+            }
+        },
+        Ok(None) => {
+            if ice.is_encrypted() {
+                return Err(
+                    "ICE mail requires encryption but there is no PGP configuration".to_string()
+                );
+            }
+        },
+        Err(e) => return Err(format!("Error: {}", e))
+    }
+
+    Ok(())
 }
 
 /// Create a new ICE mail
@@ -249,7 +3115,8 @@ pub fn activate_ice(term: &Term, conf: &Ini) {
 ///
 /// * `term` - Terminal abstraction
 /// * `conf` - Application configuration
-pub fn create_ice(term: &Term, conf: &Ini) {
+/// * `clock` - Source of the current date
+pub fn create_ice(term: &Term, conf: &Config, clock: &dyn Clock) {
     term.write_line("Creating a new ICE mail");
     term.write_line("You need to provide a short description and message to send\n");
 
@@ -267,18 +3134,11 @@ pub fn create_ice(term: &Term, conf: &Ini) {
     }
 
     // Create new ICE
-    let new_ice = Ice::new(description, message.unwrap());
+    let request = Request::Create {ice: Ice::new(description, message.unwrap())};
 
-    let mut ices = match parser::get_ices(&conf) {
-        Ok(v) => v,
-        // File may not exist yet, will be created later
-        Err(_) => Vec::new()
-    };
-    ices.push(new_ice);
-
-    match parser::write_ices(&conf, &ices) {
+    let _ = match save_ices(conf, request, clock.now()) {
         Ok(_) => term.write_line("New ICE mail created"),
-        Err(e) => term.write_line(format!("Error: {}" ,e.description()).as_str())
+        Err(e) => term.write_line(format!("Error: {}", e).as_str())
     };
 }
 
@@ -288,8 +3148,9 @@ pub fn create_ice(term: &Term, conf: &Ini) {
 ///
 /// * `term` - Terminal abstraction
 /// * `conf` - Application configuration
-pub fn deactivate_ice(term: &Term, conf: &Ini) {
-    let mut ices = match parser::get_ices(&conf) {
+/// * `clock` - Source of the current date
+pub fn deactivate_ice(term: &Term, conf: &Config, clock: &dyn Clock) {
+    let ices = match read_ices(conf) {
         Ok(v) => v,
         Err(e) => {
             term.write_line(format!("Error: {}", e).as_str());
@@ -331,11 +3192,11 @@ pub fn deactivate_ice(term: &Term, conf: &Ini) {
 
     term.write_line("Deactivating ICE mail...");
 
-    // Save edited ICE
-    ices[selected] = edited;
-    match parser::write_ices(&conf, &ices) {
+    let request = Request::Deactivate {id: edited.get_id().to_string()};
+
+    let _ = match save_ices(conf, request, clock.now()) {
         Ok(_) => term.write_line("ICE mail deactivated"),
-        Err(e) => term.write_line(format!("Error: {}" ,e.description()).as_str())
+        Err(e) => term.write_line(format!("Error: {}", e).as_str())
     };
 }
 
@@ -345,8 +3206,9 @@ pub fn deactivate_ice(term: &Term, conf: &Ini) {
 ///
 /// * `term` - Terminal abstraction
 /// * `conf` - Application configuration
-pub fn edit_ice(term: &Term, conf: &Ini) {
-    let mut ices = match parser::get_ices(&conf) {
+/// * `clock` - Source of the current date
+pub fn edit_ice(term: &Term, conf: &Config, clock: &dyn Clock) {
+    let ices = match read_ices(conf) {
         Ok(v) => v,
         Err(e) => {
             term.write_line(format!("Error: {}", e).as_str());
@@ -405,14 +3267,274 @@ pub fn edit_ice(term: &Term, conf: &Ini) {
         edited.set_emails(&email_list);
     }
 
-    // Save edited ICE
-    ices[selected] = edited;
-    match parser::write_ices(&conf, &ices) {
+    // Shared secret
+    if Confirmation::new("Do you want to edit the secret split among the recipients?")
+        .interact().unwrap() {
+        if Confirmation::new("Do you want to split a secret among the recipients?")
+            .interact().unwrap() {
+            let secret = PasswordInput::new("Please specify the secret")
+                .confirm("Please repeat the secret", "Secrets do not match")
+                .interact().unwrap();
+
+            let mut threshold: Option<u8> = None;
+            while threshold.is_none() {
+                let threshold_string = Input::new(
+                    "Please specify the number of shares needed to rebuild the secret"
+                ).interact().unwrap();
+
+                threshold = match threshold_string.trim().parse::<u8>() {
+                    Ok(v) if v >= 2 => Some(v),
+                    _ => {
+                        let _ = term.write_line("At least two shares must be required, try again");
+                        None
+                    }
+                };
+            }
+
+            edited.set_shared_secret(Some(SharedSecret::new(secret, threshold.unwrap())));
+        } else {
+            edited.set_shared_secret(None);
+        }
+    }
+
+    // Encryption
+    if Confirmation::new(format!(
+        "Do you want to {} PGP encryption?",
+        if edited.is_encrypted() {"disable"} else {"enable"}
+    ).as_str()).interact().unwrap() {
+        let encrypted = edited.is_encrypted();
+        edited.set_encrypted(!encrypted);
+    }
+
+    // Webhooks
+    if Confirmation::new("Do you want to edit the webhooks?").interact().unwrap() {
+        edit_webhooks(term, &mut edited);
+    }
+
+    // Actions
+    if Confirmation::new("Do you want to edit the actions?").interact().unwrap() {
+        edit_actions(term, &mut edited);
+    }
+
+    if Confirmation::new(format!(
+        "Do you want to {} sending the mail when triggered?",
+        if edited.sends_mail() {"disable"} else {"enable"}
+    ).as_str()).interact().unwrap() {
+        let send_mail = edited.sends_mail();
+        edited.set_send_mail(!send_mail);
+    }
+
+    // Preferred account, only worth asking when there are several
+    let accounts = smtp::get_accounts(conf).unwrap_or_default();
+    if accounts.len() > 1 && Confirmation::new(format!(
+        "Do you want to change the preferred account? (current: {})",
+        edited.get_account().map(|v| v.as_str()).unwrap_or("none")
+    ).as_str()).interact().unwrap() {
+        let mut selection = Select::new();
+        selection.item("None, use the priority order");
+        for account in &accounts {
+            selection.item(
+                format!("{} ({})", account.get_name(), account.get_address()).as_str()
+            );
+        }
+
+        let chosen = selection.default(0).interact().unwrap();
+        edited.set_account(match chosen {
+            0 => None,
+            i => Some(accounts[i - 1].get_name().to_string())
+        });
+    }
+
+    // Only the contents are saved, the daemon may have sent the ICE mail
+    // in the meantime
+    let _ = match save_ices(conf, Request::Edit {ice: edited}, clock.now()) {
         Ok(_) => term.write_line("ICE mail updated"),
-        Err(e) => term.write_line(format!("Error: {}" ,e.description()).as_str())
+        Err(e) => term.write_line(format!("Error: {}", e).as_str())
     };
 }
 
+/// Add or remove the webhooks of an ICE mail
+///
+/// # Arguments
+///
+/// * `term` - Terminal abstraction
+/// * `ice` - ICE mail to edit
+fn edit_webhooks(term: &Term, ice: &mut Ice) {
+    loop {
+        let mut selection = Select::new();
+        for webhook in ice.get_webhooks() {
+            selection.item(format!("Remove: {}", webhook.get_description()).as_str());
+        }
+        selection.item("Add a new webhook");
+        selection.item("Done");
+
+        let _ = term.write_line("Select a webhook to remove or add a new one\n");
+        let selected = selection.default(0).interact().unwrap();
+        let count = ice.get_webhooks().len();
+
+        if selected == count + 1 {
+            return;
+        }
+
+        if selected < count {
+            ice.get_webhooks_mut().remove(selected);
+            continue;
+        }
+
+        // New webhook, a wrong URL would only show when the ICE mail is sent
+        let mut url = String::new();
+        while url.is_empty() {
+            url = Input::new("Please specify the URL").interact().unwrap();
+
+            if let Err(e) = http::parse_url(&url) {
+                let _ = term.write_line(format!("{}, try again", e).as_str());
+                url.clear();
+            }
+        }
+
+        let methods = ["POST", "PUT", "PATCH", "GET"];
+        let method = Select::new().items(&methods).default(0).interact().unwrap();
+
+        // One header per prompt, values may contain commas
+        let mut headers = Vec::new();
+        loop {
+            let line = Input::new("Please specify a header (`Name: value`, empty when done)")
+                .default("")
+                .show_default(false)
+                .interact().unwrap();
+
+            if line.trim().is_empty() {
+                break;
+            }
+
+            match webhook::parse_header(&line) {
+                Ok(v) => headers.push(v),
+                Err(e) => {
+                    let _ = term.write_line(format!("{}, try again", e).as_str());
+                }
+            }
+        }
+
+        let _ = term.write_line("Opening your default editor to write the body template...");
+        let template = match Editor::new().edit(DEFAULT_TEMPLATE).unwrap() {
+            Some(v) => v,
+            None => DEFAULT_TEMPLATE.to_string()
+        };
+
+        let secret = Input::new("Please specify a secret for HMAC signatures (optional)")
+            .default("")
+            .show_default(false)
+            .interact().unwrap();
+
+        let mut retries: Option<u32> = None;
+        while retries.is_none() {
+            let retries_string = Input::new("Please specify the number of retries")
+                .default("3")
+                .interact().unwrap();
+
+            retries = match retries_string.trim().parse::<u32>() {
+                Ok(v) if v <= webhook::MAX_RETRIES => Some(v),
+                _ => {
+                    let _ = term.write_line(format!(
+                        "The number of retries must be between 0 and {}, try again",
+                        webhook::MAX_RETRIES
+                    ).as_str());
+                    None
+                }
+            };
+        }
+
+        ice.get_webhooks_mut().push(Webhook::new(
+            url,
+            methods[method].to_string(),
+            headers,
+            template,
+            if secret.is_empty() {None} else {Some(secret)},
+            retries.unwrap()
+        ));
+    }
+}
+
+/// Add or remove the actions of an ICE mail
+///
+/// # Arguments
+///
+/// * `term` - Terminal abstraction
+/// * `ice` - ICE mail to edit
+fn edit_actions(term: &Term, ice: &mut Ice) {
+    loop {
+        let mut selection = Select::new();
+        for action in ice.get_actions() {
+            selection.item(format!("Remove: {}", action.get_description()).as_str());
+        }
+        selection.item("Add a new action");
+        selection.item("Done");
+
+        let _ = term.write_line("Select an action to remove or add a new one\n");
+        let selected = selection.default(0).interact().unwrap();
+        let count = ice.get_actions().len();
+
+        if selected == count + 1 {
+            return;
+        }
+
+        if selected < count {
+            ice.get_actions_mut().remove(selected);
+            continue;
+        }
+
+        // New action
+        let kind = Select::new()
+            .items(&[
+                "Run a command",
+                "Publish a file to a directory",
+                "Revoke a PGP key",
+            ])
+            .default(0)
+            .interact().unwrap();
+
+        let kind = match kind {
+            0 => ActionKind::Command {
+                command: Input::new("Please specify the command").interact().unwrap()
+            },
+            1 => ActionKind::Publish {
+                file: Input::new("Please specify the file to publish").interact().unwrap(),
+                directory: Input::new("Please specify the target directory").interact().unwrap()
+            },
+            _ => {
+                let keyserver = Input::new("Please specify a keyserver (optional)")
+                    .default("")
+                    .show_default(false)
+                    .interact().unwrap();
+
+                ActionKind::Revoke {
+                    certificate: Input::new("Please specify the revocation certificate")
+                        .interact().unwrap(),
+                    key: Input::new("Please specify the key ID").interact().unwrap(),
+                    keyserver: if keyserver.is_empty() {None} else {Some(keyserver)}
+                }
+            }
+        };
+
+        let mut timeout: Option<u64> = None;
+        while timeout.is_none() {
+            let timeout_string = Input::new("Please specify the timeout in seconds")
+                .default(DEFAULT_TIMEOUT.to_string().as_str())
+                .interact().unwrap();
+
+            timeout = match timeout_string.trim().parse::<u64>() {
+                Ok(v) if v > 0 => Some(v),
+                _ => {
+                    let _ = term.write_line("Invalid timeout, try again");
+                    None
+                }
+            };
+        }
+
+        ice.get_actions_mut().push(Action::new(kind, timeout.unwrap()));
+    }
+}
+
 /// List ICE mails present in the JSON file
 ///
 /// The output also shows whether an ICE is enabled and the date when it is
@@ -422,8 +3544,8 @@ pub fn edit_ice(term: &Term, conf: &Ini) {
 ///
 /// * `term` - Terminal abstraction
 /// * `conf` - Application configuration
-pub fn list_ices(term: &Term, conf: &Ini) {
-    let ices = match parser::get_ices(&conf) {
+pub fn list_ices(term: &Term, conf: &Config) {
+    let ices = match read_ices(conf) {
         Ok(v) => v,
         Err(e) => {
             term.write_line(format!("Error: {}", e).as_str());
@@ -447,8 +3569,9 @@ pub fn list_ices(term: &Term, conf: &Ini) {
 ///
 /// * `term` - Terminal abstraction
 /// * `conf` - Application configuration
-pub fn remove_ice(term: &Term, conf: &Ini) {
-    let mut ices = match parser::get_ices(&conf) {
+/// * `clock` - Source of the current date
+pub fn remove_ice(term: &Term, conf: &Config, clock: &dyn Clock) {
+    let ices = match read_ices(conf) {
         Ok(v) => v,
         Err(e) => {
             term.write_line(format!("Error: {}", e).as_str());
@@ -479,15 +3602,15 @@ pub fn remove_ice(term: &Term, conf: &Ini) {
     }
 
     // Remove ICE
-    let removed = ices.remove(selected);
-    match parser::write_ices(&conf, &ices) {
+    let request = Request::Remove {id: ices[selected].get_id().to_string()};
+    let _ = match save_ices(conf, request, clock.now()) {
         Ok(_) => {
             term.write_line(
-                format!("ICE mail '{}' removed", removed.get_description())
+                format!("ICE mail '{}' removed", ices[selected].get_description())
                 .as_str()
             )
         },
-        Err(e) => term.write_line(format!("Error: {}" ,e.description()).as_str())
+        Err(e) => term.write_line(format!("Error: {}", e).as_str())
     };
 }
 
@@ -497,8 +3620,8 @@ pub fn remove_ice(term: &Term, conf: &Ini) {
 ///
 /// * `term` - Terminal abstraction
 /// * `conf` - Application configuration
-pub fn show_ice(term: &Term, conf: &Ini) {
-    let ices = match parser::get_ices(&conf) {
+pub fn show_ice(term: &Term, conf: &Config) {
+    let ices = match read_ices(conf) {
         Ok(v) => v,
         Err(e) => {
             term.write_line(format!("Error: {}", e).as_str());
@@ -529,6 +3652,60 @@ pub fn show_ice(term: &Term, conf: &Ini) {
             ices[selected].get_emails().join(",")
         ).as_str()
     );
+    if let Some(shared) = ices[selected].get_shared_secret() {
+        let _ = term.write_line(
+            format!(
+                "Shared secret: {} of {} shares needed",
+                shared.get_threshold(),
+                ices[selected].get_emails().len()
+            ).as_str()
+        );
+    }
+    let _ = term.write_line(
+        format!(
+            "Encrypted: {}",
+            if ices[selected].is_encrypted() {"yes"} else {"no"}
+        ).as_str()
+    );
+    let _ = term.write_line(
+        format!(
+            "Send mail: {}",
+            if ices[selected].sends_mail() {"yes"} else {"no"}
+        ).as_str()
+    );
+    if let Some(account) = ices[selected].get_account() {
+        let _ = term.write_line(format!("Preferred account: {}", account).as_str());
+    }
+    for webhook in ices[selected].get_webhooks() {
+        let _ = term.write_line(format!("Webhook: {}", webhook.get_description()).as_str());
+
+        if let Some(response) = webhook.get_last_response() {
+            let _ = term.write_line(
+                format!("    Last call: {}", response.get_status_line()).as_str()
+            );
+            for line in response.get_body().lines() {
+                let _ = term.write_line(format!("    | {}", line).as_str());
+            }
+        }
+    }
+    for action in ices[selected].get_actions() {
+        let _ = term.write_line(format!("Action: {}", action.get_description()).as_str());
+
+        if let Some(result) = action.get_last_result() {
+            let _ = term.write_line(format!("    Last run: {}", result.get_status_line()).as_str());
+            for line in result.get_output().lines() {
+                let _ = term.write_line(format!("    | {}", line).as_str());
+            }
+        }
+    }
+    for record in ices[selected].get_history() {
+        let _ = term.write_line(format!("Delivery: {}", record.get_status_line()).as_str());
+    }
+    for pending in ices[selected].get_pending() {
+        let _ = term.write_line(
+            format!("Pending delivery: {}", pending.get_recipients().join(", ")).as_str()
+        );
+    }
     term.write_line("");
     term.write_line(ices[selected].get_message().as_str());
 }
@@ -21,61 +21,1582 @@
 // SOFTWARE.
 
 //! Configuration file operations
+//!
+//! The configuration is a TOML file, deserialized into a `Config`:
+//!
+//! ```toml
+//! [mail]
+//! address = "me@example.com"
+//! server = "smtp.example.com"
+//! port = 587
+//! password_keyring = "secret-service"
+//!
+//! [accounts.backup]
+//! address = "me@example.org"
+//! server = "smtp.example.org"
+//! priority = 1
+//!
+//! [json]
+//! path = "/home/me/.local/share/simpleice/ices.json"
+//!
+//! [daemon]
+//! max_poll_interval = 3600
+//! max_clock_jump = 300
+//! metrics_address = "127.0.0.1:9464"
+//!
+//! [watchdog]
+//! max_age = 7200
+//! owner = "me@example.com"
+//!
+//! [log]
+//! level = "info"
+//! sinks = ["stderr", "file"]
+//! file = "/home/me/.local/state/simpleice/simpleice.log"
+//! ```
+//!
+//! The configuration file is the first one found of:
+//!
+//! - the path given with `--config`
+//! - the path in the `SIMPLEICE_CONFIG` environment variable
+//! - `$XDG_CONFIG_HOME/simpleice/config.toml` (`~/.config/simpleice/config.toml`)
+//! - `$XDG_CONFIG_HOME/simpleice/config` and `~/.simpleice`, legacy INI files
+//!
+//! Files without the `.toml` extension are read as legacy INI files, which
+//! can be converted with `config migrate`. New configuration files are
+//! created in the XDG location.
+//!
+//! Any key can be overridden with a `SIMPLEICE_<TABLE>_<KEY>` environment
+//! variable, such as `SIMPLEICE_MAIL_SERVER`, `SIMPLEICE_JSON_PATH` or
+//! `SIMPLEICE_ACCOUNTS_BACKUP_PORT`. Overrides are not written back when the
+//! configuration file is modified.
+//!
+//! # Profiles
+//!
+//! Tables under `[profiles.<name>]` make up a named profile, with its own
+//! mail accounts and JSON file: `[profiles.oncall.mail]`,
+//! `[profiles.oncall.json]`... The top-level tables are the `default`
+//! profile. Profiles do not inherit anything from the default one, and must
+//! set the `path` of their JSON file.
+//!
+//! The profile is selected with `--profile` or the `SIMPLEICE_PROFILE`
+//! environment variable. Commands then see the tables of the profile as if
+//! they were at the top level.
 
-use std::env::home_dir;
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::{CStr, OsStr};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 use ini::Ini;
+use libc;
+use toml;
+use toml::Value;
 
-/// Attempt to read a configuration file from the home directory
-pub fn read_config() -> Result<Ini, &'static str> {
-    let home = home_dir();
-    if home.is_none() {
-        return Err("Cannot find home directory");
+use clock::DEFAULT_MAX_CLOCK_JUMP;
+use logger::{Level, Sink, DEFAULT_MAX_FILES, DEFAULT_MAX_SIZE};
+use password::Keyring;
+use scheduler::{DEFAULT_MAX_POLL_INTERVAL, MAX_POLL_INTERVAL};
+use smtp::{AuthMode, TlsMode, DEFAULT_ACCOUNT, DEFAULT_CONNECT_TIMEOUT, DEFAULT_TIMEOUT};
+use watchdog::{DEFAULT_GRACE, DEFAULT_MAX_AGE, DEFAULT_REPEAT};
+
+/// Settings of a well-known mail provider
+pub struct Preset {
+    pub name: &'static str,
+    pub domains: &'static [&'static str],
+    pub server: &'static str,
+    pub port: u16,
+    pub tls: TlsMode,
+    /// Certificate to trust for the server, when it is self-signed
+    pub ca_prompt: Option<&'static str>
+}
+
+/// Mail providers offered when creating the configuration
+pub const PRESETS: &[Preset] = &[
+    Preset {
+        name: "Gmail",
+        domains: &["gmail.com", "googlemail.com"],
+        server: "smtp.gmail.com",
+        port: 587,
+        tls: TlsMode::StartTls,
+        ca_prompt: None
+    },
+    Preset {
+        name: "Outlook / Office 365",
+        domains: &["outlook.com", "hotmail.com", "live.com"],
+        server: "smtp.office365.com",
+        port: 587,
+        tls: TlsMode::StartTls,
+        ca_prompt: None
+    },
+    Preset {
+        name: "Yahoo Mail",
+        domains: &["yahoo.com"],
+        server: "smtp.mail.yahoo.com",
+        port: 465,
+        tls: TlsMode::Implicit,
+        ca_prompt: None
+    },
+    Preset {
+        name: "iCloud Mail",
+        domains: &["icloud.com", "me.com", "mac.com"],
+        server: "smtp.mail.me.com",
+        port: 587,
+        tls: TlsMode::StartTls,
+        ca_prompt: None
+    },
+    Preset {
+        name: "Fastmail",
+        domains: &["fastmail.com", "fastmail.fm"],
+        server: "smtp.fastmail.com",
+        port: 465,
+        tls: TlsMode::Implicit,
+        ca_prompt: None
+    },
+    Preset {
+        name: "Proton Mail (Bridge)",
+        domains: &["proton.me", "protonmail.com"],
+        server: "127.0.0.1",
+        port: 1025,
+        tls: TlsMode::StartTls,
+        // The Bridge uses its own self-signed certificate
+        ca_prompt: Some("Certificate exported from the Bridge (Settings > Export TLS certificates)")
+    }
+];
+
+/// Keys of a mail account
+const ACCOUNT_KEYS: &[&str] = &[
+    "address", "password", "password_command", "password_keyring", "server", "port",
+    "tls", "auth", "hello_name", "connect_timeout", "timeout", "ca_file", "priority",
+    "oauth_token_url", "oauth_auth_url", "oauth_client_id", "oauth_client_secret",
+    "oauth_scope"
+];
+
+/// Keys of the `[json]` table
+const JSON_KEYS: &[&str] = &["path"];
+
+/// Keys of the `[pgp]` table
+const PGP_KEYS: &[&str] = &["keyring", "sign", "sign_key"];
+
+/// Keys of the `[daemon]` table
+const DAEMON_KEYS: &[&str] = &["max_poll_interval", "max_clock_jump", "metrics_address"];
+
+/// Keys of the `[watchdog]` table
+const WATCHDOG_KEYS: &[&str] = &["max_age", "grace", "repeat", "owner"];
+
+/// Keys of the `[log]` table
+const LOG_KEYS: &[&str] = &["level", "sinks", "file", "max_size", "max_files"];
+
+/// Keys whose value is an integer
+const INTEGER_KEYS: &[&str] = &[
+    "port", "priority", "connect_timeout", "timeout", "max_poll_interval", "max_size",
+    "max_files", "max_age", "grace", "repeat", "max_clock_jump"
+];
+
+/// Keys whose value is a boolean
+const BOOLEAN_KEYS: &[&str] = &["sign"];
+
+/// Keys whose value is a list, given as comma separated text
+const LIST_KEYS: &[&str] = &["sinks"];
+
+/// Environment variable with the path of the configuration file
+const CONFIG_VAR: &str = "SIMPLEICE_CONFIG";
+
+/// Environment variable with the profile to use
+const PROFILE_VAR: &str = "SIMPLEICE_PROFILE";
+
+/// Prefix of the environment variables overriding configuration keys
+const OVERRIDE_PREFIX: &str = "SIMPLEICE_";
+
+/// Prefix of the sections of named profiles in legacy INI files
+const PROFILE_PREFIX: &str = "profile.";
+
+/// Name of the profile made of the top-level tables
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Template written by `create-config` outside of a terminal
+const TEMPLATE: &str = "\
+# Configuration of simpleice, see the README for every available key
+
+[mail]
+address = \"\"
+server = \"\"
+port = 587
+# Where the password is obtained from, the first one set is used
+# password_command = \"pass show smtp\"
+# password_keyring = \"secret-service\"
+password = \"\"
+
+[json]
+# Defaults to $XDG_DATA_HOME/simpleice/ices.json
+# path = \"/home/me/.local/share/simpleice/ices.json\"
+";
+
+/// Path given in the command line, if any
+static CONFIG_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Profile given in the command line, if any
+static PROFILE: OnceLock<String> = OnceLock::new();
+
+/// Configuration of a profile
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Main mail account
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mail: Option<AccountConfig>,
+    /// Additional mail accounts, by name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub accounts: BTreeMap<String, AccountConfig>,
+    /// Storage of the ICE mails
+    #[serde(default, skip_serializing_if = "StoreConfig::is_empty")]
+    pub json: StoreConfig,
+    /// Encryption and signature of the ICE mails
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pgp: Option<PgpConfig>,
+    /// Scheduling of the `daemon` command
+    #[serde(default, skip_serializing_if = "DaemonConfig::is_default")]
+    pub daemon: DaemonConfig,
+    /// Alerts of the `watchdog` command
+    #[serde(default, skip_serializing_if = "WatchdogConfig::is_default")]
+    pub watchdog: WatchdogConfig,
+    /// Logging of the `check` and `daemon` commands
+    #[serde(default, skip_serializing_if = "LogConfig::is_default")]
+    pub log: LogConfig,
+    /// Named profiles, only allowed at the top level of the file
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, Config>
+}
+
+/// Mail account used to send the ICE mails
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccountConfig {
+    pub address: String,
+    pub server: String,
+    /// Defaults to 465 for implicit TLS and 587 otherwise
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// Accounts are tried in ascending order
+    #[serde(default)]
+    pub priority: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_command: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_keyring: Option<Keyring>,
+    #[serde(default)]
+    pub tls: TlsMode,
+    /// Picked from the mechanisms of the server when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<AuthMode>,
+    #[serde(default = "default_hello_name")]
+    pub hello_name: String,
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: u64,
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_file: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub oauth_token_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub oauth_auth_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub oauth_client_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub oauth_client_secret: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub oauth_scope: Option<String>
+}
+
+/// Storage of the ICE mails
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StoreConfig {
+    /// Defaults to `$XDG_DATA_HOME/simpleice/ices.json`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>
+}
+
+/// Encryption and signature of the ICE mails
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PgpConfig {
+    /// GnuPG home directory with the keys
+    pub keyring: PathBuf,
+    #[serde(default)]
+    pub sign: bool,
+    /// Defaults to the sender address
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sign_key: Option<String>
+}
+
+/// Scheduling of the `daemon` command
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DaemonConfig {
+    /// Seconds after which the JSON file is read again even if nothing is due
+    #[serde(default = "default_max_poll_interval")]
+    pub max_poll_interval: u64,
+    /// Seconds the wall clock may jump before the ICE mails it made due are
+    /// held until confirmed
+    #[serde(default = "default_max_clock_jump")]
+    pub max_clock_jump: u64,
+    /// Address and port the metrics are served on, not served by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metrics_address: Option<String>
+}
+
+/// Alerts of the `watchdog` command
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WatchdogConfig {
+    /// Seconds after which the heartbeat of the scheduler is stale, see
+    /// `Config::get_max_age`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_age: Option<u64>,
+    /// Seconds an ICE mail may stay unsent after its date
+    #[serde(default = "default_grace")]
+    pub grace: u64,
+    /// Seconds before the same problems are alerted again
+    #[serde(default = "default_repeat")]
+    pub repeat: u64,
+    /// Address alerted, defaults to the sender address
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>
+}
+
+/// Logging of the `check` and `daemon` commands
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogConfig {
+    #[serde(default)]
+    pub level: Level,
+    #[serde(default = "default_sinks")]
+    pub sinks: Vec<Sink>,
+    /// Log file of the `file` sink
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+    /// Size in bytes after which the log file is rotated
+    #[serde(default = "default_max_size")]
+    pub max_size: u64,
+    /// Number of rotated log files kept
+    #[serde(default = "default_max_files")]
+    pub max_files: u32
+}
+
+/// Configuration key set through an environment variable
+pub struct Override {
+    pub variable: String,
+    /// Dotted path of the key, such as `mail.server`
+    pub key: String,
+    pub value: String
+}
+
+fn default_hello_name() -> String {
+    "localhost".to_string()
+}
+
+fn default_connect_timeout() -> u64 {
+    DEFAULT_CONNECT_TIMEOUT
+}
+
+fn default_timeout() -> u64 {
+    DEFAULT_TIMEOUT
+}
+
+fn default_max_poll_interval() -> u64 {
+    DEFAULT_MAX_POLL_INTERVAL
+}
+
+fn default_max_clock_jump() -> u64 {
+    DEFAULT_MAX_CLOCK_JUMP
+}
+
+fn default_grace() -> u64 {
+    DEFAULT_GRACE
+}
+
+fn default_repeat() -> u64 {
+    DEFAULT_REPEAT
+}
+
+fn default_sinks() -> Vec<Sink> {
+    vec![Sink::Stderr]
+}
+
+fn default_max_size() -> u64 {
+    DEFAULT_MAX_SIZE
+}
+
+fn default_max_files() -> u32 {
+    DEFAULT_MAX_FILES
+}
+
+impl Config {
+    /// Get every mail account by name, `[mail]` first
+    pub fn get_accounts(&self) -> Vec<(String, &AccountConfig)> {
+        let mut accounts = Vec::new();
+
+        if let Some(ref account) = self.mail {
+            accounts.push((DEFAULT_ACCOUNT.to_string(), account));
+        }
+
+        for (name, account) in &self.accounts {
+            accounts.push((name.clone(), account));
+        }
+
+        accounts
+    }
+
+    /// Get a mail account to modify it
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the account
+    pub fn get_account_mut(&mut self, name: &str) -> Option<&mut AccountConfig> {
+        if name == DEFAULT_ACCOUNT {
+            self.mail.as_mut()
+        } else {
+            self.accounts.get_mut(name)
+        }
+    }
+
+    /// Get the seconds after which the heartbeat of the scheduler is stale
+    ///
+    /// Unless set, twice the longest the daemon sleeps, and never less than
+    /// what a check run from cron or a timer needs.
+    pub fn get_max_age(&self) -> u64 {
+        self.watchdog.max_age
+            .unwrap_or_else(|| DEFAULT_MAX_AGE.max(2 * self.daemon.max_poll_interval))
+    }
+
+    /// Check the values of the configuration
+    ///
+    /// # Arguments
+    ///
+    /// * `prefix` - Prefix of the keys in error messages
+    pub fn validate(&self, prefix: &str) -> Result<(), String> {
+        for (name, account) in self.get_accounts() {
+            account.validate(&format!("{}{}", prefix, get_account_table(&name)))?;
+        }
+
+        if self.accounts.contains_key(DEFAULT_ACCOUNT) {
+            return Err(format!(
+                "`{}accounts.{}`: the name is reserved for [mail]", prefix, DEFAULT_ACCOUNT
+            ));
+        }
+
+        if let Some(ref path) = self.json.path {
+            if !path.is_absolute() {
+                return Err(format!("`{}json.path`: an absolute path is required", prefix));
+            }
+        }
+
+        if let Some(ref pgp) = self.pgp {
+            if !pgp.keyring.is_dir() {
+                return Err(format!(
+                    "`{}pgp.keyring`: directory '{}' does not exist", prefix, pgp.keyring.display()
+                ));
+            }
+        }
+
+        if self.daemon.max_poll_interval == 0 || self.daemon.max_poll_interval > MAX_POLL_INTERVAL {
+            return Err(format!(
+                "`{}daemon.max_poll_interval`: must be between 1 and {} seconds",
+                prefix, MAX_POLL_INTERVAL
+            ));
+        }
+
+        if self.daemon.max_clock_jump == 0 {
+            return Err(format!("`{}daemon.max_clock_jump`: must be at least 1 second", prefix));
+        }
+
+        if let Some(ref address) = self.daemon.metrics_address {
+            let valid = address.rsplit_once(':')
+                .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+            if !valid {
+                return Err(format!(
+                    "`{}daemon.metrics_address`: expected an address and a port, such as 127.0.0.1:9464",
+                    prefix
+                ));
+            }
+        }
+
+        // The daemon may not write the heartbeat more often than it polls
+        if let Some(max_age) = self.watchdog.max_age {
+            if max_age <= self.daemon.max_poll_interval {
+                return Err(format!(
+                    "`{}watchdog.max_age`: must be longer than `daemon.max_poll_interval` ({} seconds)",
+                    prefix, self.daemon.max_poll_interval
+                ));
+            }
+        }
+
+        if let Some(ref owner) = self.watchdog.owner {
+            if !owner.contains('@') {
+                return Err(format!("`{}watchdog.owner`: invalid address '{}'", prefix, owner));
+            }
+        }
+
+        self.log.validate(prefix)?;
+
+        for (name, profile) in &self.profiles {
+            let prefix = format!("{}profiles.{}.", prefix, name);
+
+            if !profile.profiles.is_empty() {
+                return Err(format!("`{}profiles`: profiles cannot be nested", prefix));
+            }
+
+            // Never share the JSON file of the default profile by accident
+            if profile.json.path.is_none() {
+                return Err(format!("`{}json.path`: profiles must set their JSON file", prefix));
+            }
+
+            profile.validate(&prefix)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl AccountConfig {
+    /// Create an account with the default settings
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - Address mails are sent from, also used to log in
+    /// * `server` - Address of the SMTP server
+    pub fn new(address: &str, server: &str) -> AccountConfig {
+        AccountConfig {
+            address: address.to_string(),
+            server: server.to_string(),
+            port: None,
+            priority: 0,
+            password: None,
+            password_command: None,
+            password_keyring: None,
+            tls: TlsMode::default(),
+            auth: None,
+            hello_name: default_hello_name(),
+            connect_timeout: default_connect_timeout(),
+            timeout: default_timeout(),
+            ca_file: None,
+            oauth_token_url: None,
+            oauth_auth_url: None,
+            oauth_client_id: None,
+            oauth_client_secret: None,
+            oauth_scope: None
+        }
+    }
+
+    /// Get the port of the server
+    pub fn get_port(&self) -> u16 {
+        match self.port {
+            Some(v) => v,
+            None if self.tls == TlsMode::Implicit => 465,
+            None => 587
+        }
+    }
+
+    /// Check the values of the account
+    ///
+    /// # Arguments
+    ///
+    /// * `table` - Table of the account in error messages
+    fn validate(&self, table: &str) -> Result<(), String> {
+        if self.address.is_empty() {
+            return Err(format!("`{}.address`: the sender address is required", table));
+        }
+
+        if self.server.is_empty() {
+            return Err(format!("`{}.server`: the server address is required", table));
+        }
+
+        if self.port == Some(0) {
+            return Err(format!("`{}.port`: invalid port 0", table));
+        }
+
+        if self.hello_name.is_empty() || self.hello_name.contains(char::is_whitespace) {
+            return Err(format!("`{}.hello_name`: invalid name '{}'", table, self.hello_name));
+        }
+
+        if self.connect_timeout == 0 {
+            return Err(format!("`{}.connect_timeout`: must be at least 1 second", table));
+        }
+
+        if self.timeout == 0 {
+            return Err(format!("`{}.timeout`: must be at least 1 second", table));
+        }
+
+        if self.auth == Some(AuthMode::XOAuth2) {
+            if self.oauth_token_url.as_ref().is_none_or(|v| v.is_empty()) {
+                return Err(format!("`{}.oauth_token_url`: required for XOAUTH2", table));
+            }
+
+            if self.oauth_client_id.as_ref().is_none_or(|v| v.is_empty()) {
+                return Err(format!("`{}.oauth_client_id`: required for XOAUTH2", table));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl StoreConfig {
+    /// Whether no setting is given
+    fn is_empty(&self) -> bool {
+        self.path.is_none()
+    }
+}
+
+impl Default for DaemonConfig {
+    fn default() -> DaemonConfig {
+        DaemonConfig {
+            max_poll_interval: default_max_poll_interval(),
+            max_clock_jump: default_max_clock_jump(),
+            metrics_address: None
+        }
+    }
+}
+
+impl DaemonConfig {
+    /// Whether every setting has its default value
+    fn is_default(&self) -> bool {
+        self.max_poll_interval == DEFAULT_MAX_POLL_INTERVAL
+            && self.max_clock_jump == DEFAULT_MAX_CLOCK_JUMP && self.metrics_address.is_none()
+    }
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> WatchdogConfig {
+        WatchdogConfig {
+            max_age: None,
+            grace: default_grace(),
+            repeat: default_repeat(),
+            owner: None
+        }
+    }
+}
+
+impl WatchdogConfig {
+    /// Whether every setting has its default value
+    fn is_default(&self) -> bool {
+        self.max_age.is_none() && self.grace == DEFAULT_GRACE && self.repeat == DEFAULT_REPEAT
+            && self.owner.is_none()
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            level: Level::default(),
+            sinks: default_sinks(),
+            file: None,
+            max_size: default_max_size(),
+            max_files: default_max_files()
+        }
+    }
+}
+
+impl LogConfig {
+    /// Whether every setting has its default value
+    fn is_default(&self) -> bool {
+        self.level == Level::default() && self.sinks == default_sinks() && self.file.is_none()
+            && self.max_size == DEFAULT_MAX_SIZE && self.max_files == DEFAULT_MAX_FILES
+    }
+
+    /// Check the values of the logging configuration
+    ///
+    /// # Arguments
+    ///
+    /// * `prefix` - Prefix of the keys in error messages
+    fn validate(&self, prefix: &str) -> Result<(), String> {
+        match self.file {
+            Some(ref path) if !path.is_absolute() => {
+                return Err(format!("`{}log.file`: an absolute path is required", prefix));
+            },
+            None if self.sinks.contains(&Sink::File) => {
+                return Err(format!("`{}log.file`: required by the file sink", prefix));
+            },
+            _ => ()
+        }
+
+        if self.max_size == 0 {
+            return Err(format!("`{}log.max_size`: must be at least 1 byte", prefix));
+        }
+
+        if self.max_files == 0 {
+            return Err(format!("`{}log.max_files`: must be at least 1", prefix));
+        }
+
+        Ok(())
+    }
+}
+
+/// Obtain the table of an account in the configuration file
+///
+/// # Arguments
+///
+/// * `name` - Name of the account
+pub fn get_account_table(name: &str) -> String {
+    if name == DEFAULT_ACCOUNT {
+        "mail".to_string()
+    } else {
+        format!("accounts.{}", name)
+    }
+}
+
+/// Use a configuration file instead of looking it up
+///
+/// # Arguments
+///
+/// * `path` - Path of the configuration file
+pub fn set_config_path(path: &str) {
+    let _ = CONFIG_PATH.set(PathBuf::from(path));
+}
+
+/// Use a profile instead of the one in the environment
+///
+/// # Arguments
+///
+/// * `name` - Name of the profile
+pub fn set_profile(name: &str) {
+    let _ = PROFILE.set(name.to_string());
+}
+
+/// Obtain the name of the selected profile
+pub fn get_profile() -> String {
+    if let Some(name) = PROFILE.get() {
+        return name.clone();
+    }
+
+    match env::var(PROFILE_VAR) {
+        Ok(ref v) if !v.is_empty() => v.clone(),
+        _ => DEFAULT_PROFILE.to_string()
+    }
+}
+
+/// Whether a profile was selected, with `--profile` or in the environment
+pub fn has_profile() -> bool {
+    PROFILE.get().is_some() || env::var(PROFILE_VAR).is_ok_and(|v| !v.is_empty())
+}
+
+/// Obtain the path of the configuration file
+///
+/// When no configuration file exists, this is the path where it should be
+/// created.
+pub fn get_config_path() -> Result<PathBuf, &'static str> {
+    if let Some(path) = get_explicit_path() {
+        return Ok(path);
+    }
+
+    let mut directory = get_xdg_dir("XDG_CONFIG_HOME", ".config")?;
+    directory.push("simpleice");
+
+    let conf_path = directory.join("config.toml");
+    if conf_path.exists() {
+        return Ok(conf_path);
+    }
+
+    let mut legacy_paths = vec![directory.join("config")];
+    legacy_paths.push(get_home_dir()?.join(".simpleice"));
+
+    for path in legacy_paths {
+        if path.exists() {
+            return Ok(path);
+        }
+    }
+
+    Ok(conf_path)
+}
+
+/// Obtain the path of the configuration file given by the user, if any
+fn get_explicit_path() -> Option<PathBuf> {
+    if let Some(path) = CONFIG_PATH.get() {
+        return Some(path.clone());
+    }
+
+    env::var_os(CONFIG_VAR).filter(|v| !v.is_empty()).map(PathBuf::from)
+}
+
+/// Whether a configuration file uses the TOML format
+///
+/// # Arguments
+///
+/// * `path` - Path of the configuration file
+pub fn is_toml(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == "toml")
+}
+
+/// Obtain the default path of the JSON file that stores the ICE mails
+///
+/// This is `$XDG_DATA_HOME/simpleice/ices.json`.
+pub fn get_default_store_path() -> Result<PathBuf, &'static str> {
+    let mut store_path = get_xdg_dir("XDG_DATA_HOME", ".local/share")?;
+    store_path.push("simpleice");
+    store_path.push("ices.json");
+
+    Ok(store_path)
+}
+
+/// Obtain the home directory of the user
+fn get_home_dir() -> Result<PathBuf, &'static str> {
+    if let Some(home) = env::var_os("HOME").filter(|v| !v.is_empty()) {
+        return Ok(PathBuf::from(home));
+    }
+
+    // Fall back to the user database
+    unsafe {
+        let passwd = libc::getpwuid(libc::getuid());
+        if passwd.is_null() || (*passwd).pw_dir.is_null() {
+            return Err("Cannot find home directory");
+        }
+
+        let home = CStr::from_ptr((*passwd).pw_dir).to_bytes();
+        Ok(PathBuf::from(OsStr::from_bytes(home)))
+    }
+}
+
+/// Obtain a base directory of the XDG specification
+///
+/// # Arguments
+///
+/// * `variable` - Environment variable with the directory
+/// * `fallback` - Directory relative to the home directory when not set
+fn get_xdg_dir(variable: &str, fallback: &str) -> Result<PathBuf, &'static str> {
+    // Relative paths are invalid and must be ignored
+    match env::var_os(variable).map(PathBuf::from) {
+        Some(ref v) if v.is_absolute() => Ok(v.clone()),
+        _ => Ok(get_home_dir()?.join(fallback))
+    }
+}
+
+/// Attempt to read the configuration file
+///
+/// Only the selected profile is kept. Environment overrides are applied, and
+/// are enough to run without a configuration file.
+pub fn read_config() -> Result<Config, String> {
+    let file = read_config_file()?;
+    let profile = get_profile();
+
+    let conf = get_profile_config(&file, &profile)?;
+    let overrides = get_overrides(&conf);
+    if file.is_none() && overrides.is_empty() {
+        return Err("Cannot find configuration file".to_string());
     }
 
-    let mut conf_path = PathBuf::new();
-    conf_path.push(home.unwrap());
-    conf_path.push(".simpleice");
+    apply_overrides(&conf, &overrides, &get_key_prefix(&profile))
+}
+
+/// Read the configuration of every profile
+///
+/// Environment overrides only apply to the default profile, as they would
+/// otherwise make every profile use the same JSON file or account.
+pub fn read_profiles() -> Result<Vec<(String, Config)>, String> {
+    let file = read_config_file()?;
+    let names = match file {
+        Some(ref v) => get_profiles(v),
+        None => vec![DEFAULT_PROFILE.to_string()]
+    };
+
+    let mut profiles = Vec::new();
+    for name in names {
+        let conf = get_profile_config(&file, &name)?;
+        let overrides = match name == DEFAULT_PROFILE {
+            true => get_overrides(&conf),
+            false => Vec::new()
+        };
+        if file.is_none() && overrides.is_empty() {
+            return Err("Cannot find configuration file".to_string());
+        }
+
+        let conf = apply_overrides(&conf, &overrides, &get_key_prefix(&name))?;
+        profiles.push((name, conf));
+    }
+
+    Ok(profiles)
+}
+
+/// Obtain the names of the profiles of a configuration
+///
+/// The default profile comes first, the rest are sorted.
+///
+/// # Arguments
+///
+/// * `conf` - Whole configuration file
+pub fn get_profiles(conf: &Config) -> Vec<String> {
+    let mut names: Vec<String> = conf.profiles.keys().cloned().collect();
+
+    let has_default = conf.mail.is_some() || !conf.accounts.is_empty() || conf.pgp.is_some()
+        || !conf.json.is_empty() || !conf.daemon.is_default() || !conf.watchdog.is_default()
+        || !conf.log.is_default();
+    if has_default || names.is_empty() {
+        names.insert(0, DEFAULT_PROFILE.to_string());
+    }
+
+    names
+}
+
+/// Obtain the prefix of the keys of a profile in error messages
+fn get_key_prefix(profile: &str) -> String {
+    if profile == DEFAULT_PROFILE {
+        String::new()
+    } else {
+        format!("profiles.{}.", profile)
+    }
+}
+
+/// Load and validate the configuration file, if there is one
+fn read_config_file() -> Result<Option<Config>, String> {
+    let conf_path = get_config_path()?;
 
     if !conf_path.exists() {
-        return Err("Cannot find configuration file");
+        return Ok(None);
+    }
+
+    let conf = if is_toml(&conf_path) {
+        let content = fs::read_to_string(&conf_path)
+            .map_err(|e| format!("Failed to load configuration file: {}", e))?;
+
+        toml::from_str::<Config>(&content)
+            .map_err(|e| format!("Invalid configuration file: {}", e))?
+    } else {
+        let ini = Ini::load_from_file(conf_path.to_str().unwrap())
+            .map_err(|_| "Failed to load configuration file".to_string())?;
+
+        from_ini(&ini).map_err(|e| format!("Invalid configuration file: {}", e))?
+    };
+
+    conf.validate("").map_err(|e| format!("Invalid configuration file: {}", e))?;
+
+    Ok(Some(conf))
+}
+
+/// Extract the configuration of a profile
+///
+/// # Arguments
+///
+/// * `file` - Whole configuration file, if there is one
+/// * `profile` - Name of the profile
+fn get_profile_config(file: &Option<Config>, profile: &str) -> Result<Config, String> {
+    if profile == DEFAULT_PROFILE {
+        let mut conf = file.clone().unwrap_or_default();
+        conf.profiles.clear();
+        return Ok(conf);
+    }
+
+    file.as_ref()
+        .and_then(|f| f.profiles.get(profile))
+        .cloned()
+        .ok_or_else(|| format!("Unknown profile '{}'", profile))
+}
+
+/// Find the configuration keys overridden by environment variables
+///
+/// Variables are named `SIMPLEICE_<TABLE>_<KEY>`, where dots and dashes of
+/// the table name are replaced by underscores.
+///
+/// # Arguments
+///
+/// * `conf` - Configuration whose tables may be overridden
+pub fn get_overrides(conf: &Config) -> Vec<Override> {
+    let mut tables = vec![
+        "mail".to_string(), "json".to_string(), "pgp".to_string(), "daemon".to_string(),
+        "watchdog".to_string(), "log".to_string()
+    ];
+    for name in conf.accounts.keys() {
+        tables.push(get_account_table(name));
+    }
+
+    let mut overrides = Vec::new();
+
+    for (variable, value) in env::vars() {
+        if variable == CONFIG_VAR || variable == PROFILE_VAR
+            || !variable.starts_with(OVERRIDE_PREFIX) {
+            continue;
+        }
+        let name = &variable[OVERRIDE_PREFIX.len()..];
+
+        // The longest matching table wins, `accounts.mail_relay` over `mail`
+        let mut found: Option<(&String, String)> = None;
+        for table in &tables {
+            let prefix = format!("{}_", table.to_uppercase().replace(['.', '-'], "_"));
+            let key = match name.strip_prefix(prefix.as_str()) {
+                Some(v) => v.to_lowercase(),
+                None => continue
+            };
+
+            let known = get_known_keys(table).is_some_and(|keys| keys.contains(&key.as_str()));
+            if known && found.as_ref().is_none_or(|f| f.0.len() < table.len()) {
+                found = Some((table, key));
+            }
+        }
+
+        if let Some((table, key)) = found {
+            overrides.push(Override {
+                variable: variable.clone(),
+                key: format!("{}.{}", table, key),
+                value
+            });
+        }
+    }
+
+    overrides.sort_by(|a, b| a.variable.cmp(&b.variable));
+    overrides
+}
+
+/// Apply environment overrides to a configuration
+///
+/// # Arguments
+///
+/// * `conf` - Configuration of a profile
+/// * `overrides` - Keys to override
+/// * `prefix` - Prefix of the keys in error messages
+fn apply_overrides(conf: &Config, overrides: &[Override], prefix: &str) -> Result<Config, String> {
+    if overrides.is_empty() {
+        return Ok(conf.clone());
     }
 
-    // Load config
-    match Ini::load_from_file(conf_path.to_str().unwrap()) {
-        Ok(v) => Ok(v),
-        Err(_) => Err("Failed to load configuration file")
+    let mut value = Value::try_from(conf).map_err(|e| e.to_string())?;
+    for o in overrides {
+        let parsed = parse_value(&o.key, &o.value)
+            .map_err(|e| format!("Invalid value in {}: {}", o.variable, e))?;
+        set_value(&mut value, &o.key, Some(parsed));
     }
+
+    let conf: Config = value.try_into()
+        .map_err(|e| format!("Invalid environment override: {}", e))?;
+    conf.validate(prefix).map_err(|e| format!("Invalid environment override: {}", e))?;
+
+    Ok(conf)
 }
 
+/// Parse the value of a key given as text
+///
+/// # Arguments
+///
+/// * `key` - Dotted path of the key
+/// * `value` - Value of the key
+fn parse_value(key: &str, value: &str) -> Result<Value, String> {
+    let name = key.rsplit('.').next().unwrap_or(key);
 
-/// Write a basic configuration file
-pub fn write_empty_config() -> Result<(), &'static str> {
-    let home = home_dir();
-    if home.is_none() {
-        return Err("Cannot find home directory");
+    if INTEGER_KEYS.contains(&name) {
+        value.parse::<i64>()
+            .map(Value::Integer)
+            .map_err(|_| format!("`{}`: expected a number, found '{}'", key, value))
+    } else if LIST_KEYS.contains(&name) {
+        Ok(Value::Array(
+            value.split(',')
+                .map(|v| v.trim())
+                .filter(|v| !v.is_empty())
+                .map(|v| Value::String(v.to_string()))
+                .collect()
+        ))
+    } else if BOOLEAN_KEYS.contains(&name) {
+        match value {
+            "true" | "yes" => Ok(Value::Boolean(true)),
+            "false" | "no" | "" => Ok(Value::Boolean(false)),
+            _ => Err(format!("`{}`: expected true or false, found '{}'", key, value))
+        }
+    } else {
+        Ok(Value::String(value.to_string()))
     }
+}
 
-    let mut conf_path = PathBuf::new();
-    conf_path.push(home.unwrap());
-    conf_path.push(".simpleice");
+/// Obtain the value of a key
+///
+/// # Arguments
+///
+/// * `value` - Table to look into
+/// * `key` - Dotted path of the key
+fn get_value<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
+    let mut current = value;
+    for part in split_key(key) {
+        current = current.get(part.as_str())?;
+    }
+
+    Some(current)
+}
+
+/// Set or remove the value of a key, creating the tables it belongs to
+///
+/// # Arguments
+///
+/// * `value` - Table to modify
+/// * `key` - Dotted path of the key
+/// * `new` - New value, `None` to remove the key
+fn set_value(value: &mut Value, key: &str, new: Option<Value>) {
+    let mut parts = split_key(key);
+    let last = parts.pop().unwrap();
+
+    let mut current = value;
+    for part in parts {
+        let table = match *current {
+            Value::Table(ref mut t) => t,
+            _ => return
+        };
+
+        if !table.contains_key(&part) && new.is_none() {
+            return;
+        }
+        current = table.entry(part).or_insert_with(|| Value::Table(Default::default()));
+    }
+
+    if let Value::Table(ref mut table) = *current {
+        match new {
+            Some(v) => {
+                table.insert(last, v);
+            },
+            None => {
+                table.remove(&last);
+            }
+        }
+    }
+}
+
+/// Split the dotted path of a key
+///
+/// Account names may contain dots, so everything between `accounts` and the
+/// last part is taken as the name of the account.
+fn split_key(key: &str) -> Vec<String> {
+    if let Some(rest) = key.strip_prefix("profiles.") {
+        if let Some(i) = rest.find('.') {
+            let mut parts = vec!["profiles".to_string(), rest[..i].to_string()];
+            parts.extend(split_key(&rest[i + 1..]));
+            return parts;
+        }
+    }
+
+    match key.strip_prefix("accounts.") {
+        Some(rest) => match rest.rfind('.') {
+            Some(i) => vec![
+                "accounts".to_string(), rest[..i].to_string(), rest[i + 1..].to_string()
+            ],
+            None => vec!["accounts".to_string(), rest.to_string()]
+        },
+        None => key.split('.').map(|p| p.to_string()).collect()
+    }
+}
+
+/// Obtain the keys of a table
+///
+/// # Arguments
+///
+/// * `table` - Name of the table
+fn get_known_keys(table: &str) -> Option<&'static [&'static str]> {
+    if table == "mail" || table.starts_with("accounts.") {
+        Some(ACCOUNT_KEYS)
+    } else if table == "json" {
+        Some(JSON_KEYS)
+    } else if table == "pgp" {
+        Some(PGP_KEYS)
+    } else if table == "daemon" {
+        Some(DAEMON_KEYS)
+    } else if table == "watchdog" {
+        Some(WATCHDOG_KEYS)
+    } else if table == "log" {
+        Some(LOG_KEYS)
+    } else {
+        None
+    }
+}
+
+/// Write a configuration file template
+///
+/// Returns the path of the new file.
+pub fn write_empty_config() -> Result<PathBuf, String> {
+    let conf_path = get_config_path()?;
 
     if conf_path.exists() {
-        return Err("Configuration file already exists");
+        return Err("Configuration file already exists".to_string());
     }
 
-    // Create config
-    let mut conf = Ini::new();
-    conf.with_section(Some("mail".to_owned()))
-        .set("address", "")
-        .set("password", "")
-        .set("server", "")
-        .set("port", "");
-    conf.with_section(Some("json".to_owned()))
-        .set("path", "");
+    if !is_toml(&conf_path) {
+        return Err("Configuration files must have the .toml extension".to_string());
+    }
 
-    match conf.write_to_file(conf_path.to_str().unwrap()) {
+    write_private(TEMPLATE, &conf_path)?;
+    Ok(conf_path)
+}
+
+/// Check that the configuration file can be modified
+///
+/// Returns the path of the configuration file.
+pub fn check_writable() -> Result<PathBuf, String> {
+    let conf_path = get_config_path()?;
+
+    if !is_toml(&conf_path) {
+        if conf_path.exists() {
+            return Err(
+                "The configuration file uses the legacy INI format, \
+                convert it with `config migrate` first".to_string()
+            );
+        }
+
+        return Err("Configuration files must have the .toml extension".to_string());
+    }
+
+    Ok(conf_path)
+}
+
+/// Overwrite the configuration of the selected profile
+///
+/// Values coming from environment overrides are replaced with the ones of the
+/// file, and the other profiles are kept as they are.
+///
+/// # Arguments
+///
+/// * `conf` - Configuration of the profile to write
+pub fn write_config(conf: &Config) -> Result<(), String> {
+    let conf_path = check_writable()?;
+    let profile = get_profile();
+
+    let file = read_config_file()?;
+    let current = get_profile_config(&file, &profile).ok();
+
+    // Keep the values of the file for keys set by the environment
+    let mut value = Value::try_from(conf).map_err(|e| e.to_string())?;
+    let current = match current {
+        Some(ref v) => Some(Value::try_from(v).map_err(|e| e.to_string())?),
+        None => None
+    };
+
+    for o in get_overrides(conf) {
+        let overridden = match parse_value(&o.key, &o.value) {
+            Ok(v) => v,
+            Err(_) => continue
+        };
+
+        // Modified by the command, keep the new value
+        if get_value(&value, &o.key) != Some(&overridden) {
+            continue;
+        }
+
+        let original = current.as_ref().and_then(|c| get_value(c, &o.key)).cloned();
+        set_value(&mut value, &o.key, original);
+    }
+
+    let mut conf: Config = value.try_into().map_err(|e| e.to_string())?;
+    conf.validate(&get_key_prefix(&profile))?;
+
+    // Replace the profile, keeping the rest of the file
+    let mut whole = file.unwrap_or_default();
+    if profile == DEFAULT_PROFILE {
+        conf.profiles = whole.profiles;
+        whole = conf;
+    } else {
+        conf.profiles.clear();
+        whole.profiles.insert(profile, conf);
+    }
+
+    let content = to_toml(&whole)?;
+    write_private(&content, &conf_path)
+}
+
+/// Convert a legacy INI configuration file into a TOML one
+///
+/// The new file is written next to the old one, or in the XDG location when
+/// the old one was found through the lookup. The old file is kept.
+///
+/// Returns the paths of the old and new files.
+pub fn migrate() -> Result<(PathBuf, PathBuf), String> {
+    let old_path = get_config_path()?;
+
+    if !old_path.exists() {
+        return Err("Cannot find configuration file".to_string());
+    }
+
+    if is_toml(&old_path) {
+        return Err(format!("{} already uses the TOML format", old_path.display()));
+    }
+
+    let ini = Ini::load_from_file(old_path.to_str().unwrap())
+        .map_err(|_| "Failed to load configuration file".to_string())?;
+    let conf = from_ini(&ini)?;
+    conf.validate("")?;
+
+    let new_path = match get_explicit_path() {
+        Some(_) => old_path.with_extension("toml"),
+        None => get_xdg_dir("XDG_CONFIG_HOME", ".config")?.join("simpleice").join("config.toml")
+    };
+
+    if new_path.exists() {
+        return Err(format!("{} already exists", new_path.display()));
+    }
+
+    let content = to_toml(&conf)?;
+    write_private(&content, &new_path)?;
+
+    Ok((old_path, new_path))
+}
+
+/// Serialize a configuration, with every table separated by a blank line
+fn to_toml(conf: &Config) -> Result<String, String> {
+    let content = toml::to_string(conf).map_err(|e| e.to_string())?;
+    let mut result = String::new();
+
+    for line in content.lines() {
+        if line.starts_with('[') && !result.is_empty() && !result.ends_with("\n\n") {
+            result.push('\n');
+        }
+        result.push_str(line);
+        result.push('\n');
+    }
+
+    Ok(result)
+}
+
+/// Convert a legacy INI configuration
+///
+/// Empty values are left out, as they were ignored.
+///
+/// # Arguments
+///
+/// * `ini` - Contents of the INI file
+fn from_ini(ini: &Ini) -> Result<Config, String> {
+    let mut value = Value::Table(Default::default());
+
+    for (section, properties) in ini.iter() {
+        let section = match *section {
+            Some(ref v) => v.as_str(),
+            None => {
+                if let Some(key) = properties.keys().next() {
+                    return Err(format!("`{}` is outside of any section", key));
+                }
+                continue;
+            }
+        };
+
+        // `[profile.<name>.<section>]` becomes `[profiles.<name>.<section>]`
+        let (prefix, table) = match section.strip_prefix(PROFILE_PREFIX) {
+            Some(rest) => match rest.find('.') {
+                Some(i) => (format!("profiles.{}.", &rest[..i]), &rest[i + 1..]),
+                None => return Err(format!("Unknown section [{}]", section))
+            },
+            None => (String::new(), section)
+        };
+
+        let table = match table.strip_prefix("mail.") {
+            Some(name) => get_account_table(name),
+            None => table.to_string()
+        };
+
+        let known = get_known_keys(&table)
+            .ok_or_else(|| format!("Unknown section [{}]", section))?;
+
+        for (key, v) in properties.iter() {
+            if !known.contains(&key.as_str()) {
+                return Err(format!("Unknown key `{}` in [{}]", key, section));
+            }
+
+            if v.is_empty() {
+                continue;
+            }
+
+            let path = format!("{}{}.{}", prefix, table, key);
+            let parsed = parse_value(&path, v)?;
+            set_value(&mut value, &path, Some(parsed));
+        }
+    }
+
+    value.try_into::<Config>().map_err(|e| e.to_string())
+}
+
+/// Write a configuration file only readable by its owner
+///
+/// The configuration may contain passwords, so permissions of existing files
+/// are restricted as well.
+///
+/// # Arguments
+///
+/// * `content` - Contents of the file
+/// * `path` - Path of the configuration file
+fn write_private(content: &str, path: &Path) -> Result<(), String> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        if fs::create_dir_all(parent).is_err() {
+            return Err("Failed to create configuration directory".to_string());
+        }
+    }
+
+    let mut file = match OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path) {
+        Ok(v) => v,
+        Err(_) => return Err("Failed to write configuration file".to_string())
+    };
+
+    if fs::set_permissions(path, fs::Permissions::from_mode(0o600)).is_err() {
+        return Err("Failed to set permissions of configuration file".to_string());
+    }
+
+    match file.write_all(content.as_bytes()) {
         Ok(_) => Ok(()),
-        Err(e) => Err("Failed to write configuration file"),
+        Err(_) => Err("Failed to write configuration file".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ini_config(content: &str) -> Result<Config, String> {
+        from_ini(&Ini::load_from_str(content).unwrap())
+    }
+
+    fn test_override(variable: &str, key: &str, value: &str) -> Override {
+        Override {
+            variable: variable.to_string(),
+            key: key.to_string(),
+            value: value.to_string()
+        }
+    }
+
+    #[test]
+    fn convert_legacy_ini() {
+        let conf = ini_config(
+            "[mail]\n\
+            address = me@example.com\n\
+            server = smtp.example.com\n\
+            port = 2525\n\
+            password =\n\
+            [mail.backup]\n\
+            address = me@backup.example.com\n\
+            server = smtp.backup.example.com\n\
+            [json]\n\
+            path = /home/me/ices.json\n\
+            [profile.work.mail]\n\
+            address = me@work.example.com\n\
+            server = smtp.work.example.com\n\
+            port = 465\n\
+            [profile.work.json]\n\
+            path = /home/me/work.json\n"
+        ).unwrap();
+        conf.validate("").unwrap();
+
+        let mail = conf.mail.as_ref().unwrap();
+        assert_eq!(mail.server, "smtp.example.com");
+        assert_eq!(mail.port, Some(2525));
+        // Empty values are left out
+        assert!(mail.password.is_none());
+
+        assert_eq!(conf.accounts["backup"].address, "me@backup.example.com");
+        assert_eq!(conf.accounts["backup"].get_port(), 587);
+        assert_eq!(conf.json.path, Some(PathBuf::from("/home/me/ices.json")));
+
+        let work = &conf.profiles["work"];
+        assert_eq!(work.mail.as_ref().unwrap().port, Some(465));
+        assert_eq!(work.json.path, Some(PathBuf::from("/home/me/work.json")));
+    }
+
+    #[test]
+    fn refuse_invalid_legacy_ini() {
+        assert_eq!(
+            ini_config("[mail]\nport = abc\n").err().unwrap(),
+            "`mail.port`: expected a number, found 'abc'"
+        );
+        assert_eq!(
+            ini_config("[mail]\nsender = me@example.com\n").err().unwrap(),
+            "Unknown key `sender` in [mail]"
+        );
+        assert_eq!(
+            ini_config("[profile.work]\npath = /home/me/work.json\n").err().unwrap(),
+            "Unknown section [profile.work]"
+        );
+        assert_eq!(
+            ini_config("address = me@example.com\n").err().unwrap(),
+            "`address` is outside of any section"
+        );
+    }
+
+    #[test]
+    fn invalid_keys_of_profiles_are_named() {
+        let conf: Config = toml::from_str(
+            "[profiles.work.mail]\n\
+            address = \"me@work.example.com\"\n\
+            server = \"smtp.work.example.com\"\n\
+            port = 0\n\
+            [profiles.work.json]\n\
+            path = \"/home/me/work.json\"\n"
+        ).unwrap();
+        assert_eq!(
+            conf.validate("").err().unwrap(),
+            "`profiles.work.mail.port`: invalid port 0"
+        );
+
+        let conf: Config = toml::from_str(
+            "[profiles.work.mail]\n\
+            address = \"me@work.example.com\"\n\
+            server = \"smtp.work.example.com\"\n"
+        ).unwrap();
+        assert_eq!(
+            conf.validate("").err().unwrap(),
+            "`profiles.work.json.path`: profiles must set their JSON file"
+        );
+    }
+
+    #[test]
+    fn parse_values_by_key() {
+        assert_eq!(parse_value("mail.port", "2525").unwrap(), Value::Integer(2525));
+        assert_eq!(
+            parse_value("mail.port", "25x").err().unwrap(),
+            "`mail.port`: expected a number, found '25x'"
+        );
+
+        assert_eq!(parse_value("pgp.sign", "yes").unwrap(), Value::Boolean(true));
+        assert_eq!(parse_value("pgp.sign", "false").unwrap(), Value::Boolean(false));
+        assert_eq!(parse_value("pgp.sign", "").unwrap(), Value::Boolean(false));
+        assert_eq!(
+            parse_value("pgp.sign", "maybe").err().unwrap(),
+            "`pgp.sign`: expected true or false, found 'maybe'"
+        );
+
+        assert_eq!(
+            parse_value("log.sinks", "stderr, file,").unwrap(),
+            Value::Array(vec![Value::String("stderr".to_string()), Value::String("file".to_string())])
+        );
+        assert_eq!(
+            parse_value("mail.server", "2525").unwrap(),
+            Value::String("2525".to_string())
+        );
+    }
+
+    #[test]
+    fn apply_environment_overrides() {
+        let conf = Config {
+            mail: Some(AccountConfig::new("me@example.com", "smtp.example.com")),
+            ..Config::default()
+        };
+
+        let overridden = apply_overrides(&conf, &[
+            test_override("SIMPLEICE_MAIL_PORT", "mail.port", "2525"),
+            test_override("SIMPLEICE_MAIL_SERVER", "mail.server", "relay.example.com"),
+            test_override("SIMPLEICE_PGP_KEYRING", "pgp.keyring", env::temp_dir().to_str().unwrap()),
+            test_override("SIMPLEICE_PGP_SIGN", "pgp.sign", "true")
+        ], "").unwrap();
+
+        let mail = overridden.mail.as_ref().unwrap();
+        assert_eq!(mail.port, Some(2525));
+        assert_eq!(mail.server, "relay.example.com");
+        assert!(overridden.pgp.as_ref().unwrap().sign);
+
+        assert_eq!(
+            apply_overrides(&conf, &[test_override("SIMPLEICE_MAIL_PORT", "mail.port", "smtp")], "")
+                .err().unwrap(),
+            "Invalid value in SIMPLEICE_MAIL_PORT: `mail.port`: expected a number, found 'smtp'"
+        );
+        assert_eq!(
+            apply_overrides(&conf, &[test_override("SIMPLEICE_MAIL_PORT", "mail.port", "0")], "profiles.work.")
+                .err().unwrap(),
+            "Invalid environment override: `profiles.work.mail.port`: invalid port 0"
+        );
     }
 }
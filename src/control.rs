@@ -0,0 +1,269 @@
+// MIT License
+//
+// Copyright (c) 2017 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+//! Control socket of the daemon
+//!
+//! While it runs, the daemon listens on `<file>.sock` next to each JSON file
+//! it processes, so that commands modify the ICE mails through it instead of
+//! writing the file behind its back.
+//!
+//! Each connection carries a single request and its response, both JSON
+//! objects on a line of their own:
+//!
+//! ```text
+//! {"command":"deactivate","id":"3f2a9c1e"}
+//! {"result":"done"}
+//! ```
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::prelude::*;
+use serde_json;
+
+use parser::Ice;
+
+/// Seconds to wait for the other end of a connection
+const TIMEOUT: u64 = 5;
+
+/// Seconds to wait for the daemon to answer, checks may take a while
+const RESPONSE_TIMEOUT: u64 = 300;
+
+/// Request sent to the daemon
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    /// Obtain every ICE mail
+    List,
+    /// Schedule the delivery of an ICE mail
+    Activate {
+        id: String,
+        date: DateTime<Local>
+    },
+    /// Cancel the delivery of an ICE mail
+    Deactivate {
+        id: String
+    },
+    /// Postpone the delivery of an active ICE mail
+    CheckIn {
+        id: String,
+        date: DateTime<Local>
+    },
+    /// Add a new ICE mail
+    Create {
+        ice: Ice
+    },
+    /// Update the contents of an ICE mail, keeping its state
+    Edit {
+        ice: Ice
+    },
+    /// Delete an ICE mail
+    Remove {
+        id: String
+    },
+    /// Check every profile right away
+    TriggerCheck,
+    /// Send the ICE mails held after a clock jump
+    ConfirmClock,
+    /// Obtain the state of the daemon
+    Status
+}
+
+/// Response of the daemon
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "kebab-case")]
+pub enum Response {
+    Ices {
+        ices: Vec<Ice>
+    },
+    Done,
+    Status {
+        status: DaemonStatus
+    },
+    Error {
+        message: String
+    }
+}
+
+/// State of a running daemon
+#[derive(Serialize, Deserialize)]
+pub struct DaemonStatus {
+    pub pid: u32,
+    pub started: DateTime<Local>,
+    /// Next time the daemon wakes up, unless something changes before
+    pub next_check: Option<DateTime<Local>>,
+    pub profiles: Vec<ProfileStatus>
+}
+
+/// State of a profile in a running daemon
+#[derive(Serialize, Deserialize)]
+pub struct ProfileStatus {
+    pub name: String,
+    pub store: PathBuf,
+    pub last_check: Option<DateTime<Local>>,
+    /// ICE mails held after a clock jump
+    #[serde(default)]
+    pub held: Vec<HeldIce>
+}
+
+/// ICE mail that became due only because the clock jumped
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HeldIce {
+    pub id: String,
+    pub description: String,
+    pub date: DateTime<Local>
+}
+
+/// Socket the daemon listens on for a JSON file
+pub struct Listener {
+    store: PathBuf,
+    path: PathBuf,
+    listener: UnixListener
+}
+
+impl Listener {
+    /// Listen on the socket of a JSON file
+    ///
+    /// The lock of the JSON file must be held, so that any socket left
+    /// behind can be replaced.
+    ///
+    /// # Arguments
+    ///
+    /// * `store` - Path of the JSON file
+    pub fn bind(store: &Path) -> Result<Listener, String> {
+        let path = get_socket_path(store);
+        let _ = fs::remove_file(&path);
+
+        let listener = UnixListener::bind(&path)
+            .map_err(|e| format!("Cannot listen on {}: {}", path.display(), e))?;
+
+        // Only the owner may control the daemon
+        let bound = Listener {
+            store: store.to_path_buf(),
+            path,
+            listener
+        };
+        fs::set_permissions(&bound.path, fs::Permissions::from_mode(0o600))
+            .map_err(|e| format!("Cannot restrict {}: {}", bound.path.display(), e))?;
+        bound.listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+
+        Ok(bound)
+    }
+
+    /// Get the path of the JSON file
+    pub fn get_store(&self) -> &Path {
+        &self.store
+    }
+
+    /// Accept a pending connection, if any
+    pub fn accept(&self) -> Option<UnixStream> {
+        let (stream, _) = self.listener.accept().ok()?;
+        stream.set_nonblocking(false).ok()?;
+        stream.set_read_timeout(Some(Duration::from_secs(TIMEOUT))).ok()?;
+        stream.set_write_timeout(Some(Duration::from_secs(TIMEOUT))).ok()?;
+
+        Some(stream)
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Read the request of a connection
+///
+/// # Arguments
+///
+/// * `stream` - Accepted connection
+pub fn read_request(stream: &UnixStream) -> Result<Request, String> {
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).map_err(|e| e.to_string())?;
+
+    serde_json::from_str(&line).map_err(|e| format!("Invalid request: {}", e))
+}
+
+/// Answer the request of a connection
+///
+/// # Arguments
+///
+/// * `stream` - Accepted connection
+/// * `response` - Response to send
+pub fn write_response(mut stream: &UnixStream, response: &Response) -> Result<(), String> {
+    let mut line = serde_json::to_string(response).map_err(|e| e.to_string())?;
+    line.push('\n');
+
+    stream.write_all(line.as_bytes()).map_err(|e| e.to_string())
+}
+
+/// Send a request to the daemon processing a JSON file
+///
+/// Returns `None` when no daemon is listening. Error responses are turned
+/// into errors.
+///
+/// # Arguments
+///
+/// * `store` - Path of the JSON file
+/// * `request` - Request to send
+pub fn send(store: &Path, request: &Request) -> Option<Result<Response, String>> {
+    // Sockets left behind refuse connections
+    let stream = UnixStream::connect(get_socket_path(store)).ok()?;
+
+    Some(exchange(stream, request))
+}
+
+/// Write a request and wait for its response
+fn exchange(mut stream: UnixStream, request: &Request) -> Result<Response, String> {
+    stream.set_read_timeout(Some(Duration::from_secs(RESPONSE_TIMEOUT)))
+        .map_err(|e| e.to_string())?;
+    stream.set_write_timeout(Some(Duration::from_secs(TIMEOUT)))
+        .map_err(|e| e.to_string())?;
+
+    let mut line = serde_json::to_string(request).map_err(|e| e.to_string())?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())
+        .map_err(|e| format!("Cannot reach the daemon: {}", e))?;
+
+    let mut answer = String::new();
+    BufReader::new(&stream).read_line(&mut answer)
+        .map_err(|e| format!("No answer from the daemon: {}", e))?;
+
+    match serde_json::from_str(&answer) {
+        Ok(Response::Error {message}) => Err(message),
+        Ok(v) => Ok(v),
+        Err(e) => Err(format!("Invalid answer from the daemon: {}", e))
+    }
+}
+
+/// Obtain the path of the control socket of a JSON file
+fn get_socket_path(store: &Path) -> PathBuf {
+    let mut name = store.as_os_str().to_os_string();
+    name.push(".sock");
+
+    PathBuf::from(name)
+}
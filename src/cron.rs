@@ -0,0 +1,291 @@
+// MIT License
+//
+// Copyright (c) 2017 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+//! crontab integration
+//!
+//! The `cron` command manages an entry of the crontab of the user running
+//! `check`. Each entry is preceded by a tag line naming its profile, so it
+//! can be found again without touching the rest of the crontab:
+//!
+//! ```text
+//! # simpleice check, profile default
+//! */5 * * * * /usr/bin/simpleice --config /home/me/.config/simpleice/config.toml check
+//! ```
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// How often `check` runs unless told otherwise
+pub const DEFAULT_EVERY: &str = "5m";
+
+/// Obtain the tag line of the entry of a profile
+///
+/// # Arguments
+///
+/// * `profile` - Name of the profile
+pub fn get_tag(profile: &str) -> String {
+    format!("# simpleice check, profile {}", profile)
+}
+
+/// Convert an interval into a crontab schedule
+///
+/// # Arguments
+///
+/// * `every` - Interval in minutes or hours, such as `5m` or `1h`
+pub fn get_schedule(every: &str) -> Result<String, String> {
+    let error = || format!("Invalid interval '{}', expected minutes or hours such as 5m or 1h", every);
+
+    let (value, unit) = match every.char_indices().last() {
+        Some((i, _)) => (&every[..i], &every[i..]),
+        None => return Err(error())
+    };
+    let value = value.parse::<u32>().map_err(|_| error())?;
+
+    match (unit, value) {
+        ("m", 1) => Ok("* * * * *".to_string()),
+        ("m", 2..=59) => Ok(format!("*/{} * * * *", value)),
+        ("h", 1) => Ok("0 * * * *".to_string()),
+        ("h", 2..=23) => Ok(format!("0 */{} * * *", value)),
+        ("h", 24) => Ok("0 0 * * *".to_string()),
+        ("m", _) => Err(format!("Invalid interval '{}', minutes must be between 1 and 59", every)),
+        ("h", _) => Err(format!("Invalid interval '{}', hours must be between 1 and 24", every)),
+        _ => Err(error())
+    }
+}
+
+/// Build the entry running `check`
+///
+/// # Arguments
+///
+/// * `schedule` - Crontab schedule
+/// * `command` - Binary and options to run `check` with
+pub fn get_entry(schedule: &str, command: &[String]) -> String {
+    let args: Vec<String> = command.iter().map(|a| quote(a)).collect();
+    format!("{} {} check", schedule, args.join(" "))
+}
+
+/// Find the entry following a tag
+///
+/// # Arguments
+///
+/// * `crontab` - Contents of the crontab
+/// * `tag` - Tag line of the entry
+pub fn find_entry(crontab: &str, tag: &str) -> Option<String> {
+    let mut lines = crontab.lines();
+    lines.find(|l| l.trim() == tag)?;
+
+    lines.next().map(|l| l.to_string())
+}
+
+/// Whether a line of the crontab is an entry running `check` with a binary
+///
+/// # Arguments
+///
+/// * `line` - Line of the crontab
+/// * `binary` - Path of the simpleice binary
+pub fn is_entry(line: &str, binary: &str) -> bool {
+    line.trim_end().ends_with(" check") && line.contains(&format!(" {} ", quote(binary)))
+}
+
+/// Remove a tag and the entry following it
+///
+/// The line following the tag is only removed when it runs `check` with the
+/// binary, so that lines added by hand after a tag left behind are kept.
+///
+/// # Arguments
+///
+/// * `crontab` - Contents of the crontab
+/// * `tag` - Tag line of the entry
+/// * `binary` - Path of the simpleice binary
+pub fn remove_entry(crontab: &str, tag: &str, binary: &str) -> String {
+    let mut kept = Vec::new();
+    let mut lines = crontab.lines().peekable();
+
+    while let Some(line) = lines.next() {
+        if line.trim() == tag {
+            if lines.peek().is_some_and(|l| is_entry(l, binary)) {
+                lines.next();
+            }
+            continue;
+        }
+        kept.push(line);
+    }
+
+    let mut result = kept.join("\n");
+    if !result.is_empty() {
+        result.push('\n');
+    }
+
+    result
+}
+
+/// Add a tagged entry, replacing the previous one
+///
+/// # Arguments
+///
+/// * `crontab` - Contents of the crontab
+/// * `tag` - Tag line of the entry
+/// * `entry` - Entry to add
+/// * `binary` - Path of the simpleice binary
+pub fn add_entry(crontab: &str, tag: &str, entry: &str, binary: &str) -> String {
+    let mut result = remove_entry(crontab, tag, binary);
+    result.push_str(&format!("{}\n{}\n", tag, entry));
+
+    result
+}
+
+/// Read the crontab of the user
+///
+/// Users without a crontab have an empty one.
+pub fn read_crontab() -> Result<String, String> {
+    let output = Command::new("crontab")
+        .arg("-l")
+        .output()
+        .map_err(|e| format!("Failed to run crontab: {}", e))?;
+
+    if output.status.success() {
+        return Ok(String::from_utf8_lossy(&output.stdout).into_owned());
+    }
+
+    let error = String::from_utf8_lossy(&output.stderr);
+    if error.contains("no crontab") {
+        return Ok(String::new());
+    }
+
+    Err(format!("crontab failed: {}", error.trim()))
+}
+
+/// Replace the crontab of the user
+///
+/// # Arguments
+///
+/// * `crontab` - New contents of the crontab
+pub fn write_crontab(crontab: &str) -> Result<(), String> {
+    let mut child = Command::new("crontab")
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run crontab: {}", e))?;
+
+    {
+        let stdin = child.stdin.as_mut().unwrap();
+        stdin.write_all(crontab.as_bytes())
+            .map_err(|e| format!("Failed to write to crontab: {}", e))?;
+    }
+
+    let output = child.wait_with_output()
+        .map_err(|e| format!("Failed to run crontab: {}", e))?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "crontab failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Quote an argument of a command line in a crontab entry
+///
+/// # Arguments
+///
+/// * `arg` - Argument to quote
+fn quote(arg: &str) -> String {
+    // Percent signs are turned into newlines by cron otherwise
+    let escaped = arg.replace('%', "\\%");
+
+    if !arg.is_empty() && arg.chars().all(|c| c.is_alphanumeric() || "/._-+=:,@%".contains(c)) {
+        return escaped;
+    }
+
+    format!("'{}'", escaped.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BINARY: &str = "/usr/bin/simpleice";
+    const TAG: &str = "# simpleice check, profile default";
+    const ENTRY: &str = "*/5 * * * * /usr/bin/simpleice --config /home/me/config.toml check";
+
+    #[test]
+    fn schedules() {
+        assert_eq!(get_schedule("1m").unwrap(), "* * * * *");
+        assert_eq!(get_schedule("5m").unwrap(), "*/5 * * * *");
+        assert_eq!(get_schedule("1h").unwrap(), "0 * * * *");
+        assert_eq!(get_schedule("6h").unwrap(), "0 */6 * * *");
+        assert_eq!(get_schedule("24h").unwrap(), "0 0 * * *");
+
+        for every in &["", "m", "0m", "60m", "25h", "5", "5d", "-5m"] {
+            assert!(get_schedule(every).is_err(), "{}", every);
+        }
+    }
+
+    #[test]
+    fn entry_quotes_arguments() {
+        let command = vec![
+            BINARY.to_string(),
+            "--config".to_string(),
+            "/home/me/my config%.toml".to_string()
+        ];
+
+        assert_eq!(
+            get_entry("*/5 * * * *", &command),
+            "*/5 * * * * /usr/bin/simpleice --config '/home/me/my config\\%.toml' check"
+        );
+    }
+
+    #[test]
+    fn remove_tagged_entry() {
+        let crontab = format!("0 0 * * * backup\n{}\n{}\n@reboot other\n", TAG, ENTRY);
+
+        assert_eq!(remove_entry(&crontab, TAG, BINARY), "0 0 * * * backup\n@reboot other\n");
+        assert_eq!(remove_entry(&format!("{}\n{}\n", TAG, ENTRY), TAG, BINARY), "");
+    }
+
+    #[test]
+    fn keep_lines_of_other_programs() {
+        // The entry was removed by hand, leaving the tag before another line
+        let crontab = format!("{}\n0 0 * * * backup\n", TAG);
+        assert_eq!(remove_entry(&crontab, TAG, BINARY), "0 0 * * * backup\n");
+
+        // Entries of another binary are not ours either
+        let other = ENTRY.replace(BINARY, "/opt/simpleice/simpleice");
+        let crontab = format!("{}\n{}\n", TAG, other);
+        assert_eq!(remove_entry(&crontab, TAG, BINARY), format!("{}\n", other));
+    }
+
+    #[test]
+    fn add_replaces_entry() {
+        let crontab = format!("0 0 * * * backup\n{}\n{}\n", TAG, ENTRY);
+        let entry = ENTRY.replace("*/5", "*/10");
+
+        let result = add_entry(&crontab, TAG, &entry, BINARY);
+        assert_eq!(result, format!("0 0 * * * backup\n{}\n{}\n", TAG, entry));
+        assert_eq!(find_entry(&result, TAG), Some(entry));
+    }
+}
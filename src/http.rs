@@ -0,0 +1,336 @@
+// MIT License
+//
+// Copyright (c) 2017 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Minimal HTTP/1.1 client
+//!
+//! Only what is needed to talk to webhooks and token endpoints is supported:
+//! a single request per connection, over plain TCP or TLS.
+
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use openssl::ssl::{SslConnector, SslMethod};
+
+/// Largest response read from a server, in bytes
+const MAX_RESPONSE_SIZE: u64 = 1024 * 1024;
+
+/// Headers set by the client itself
+const RESERVED_HEADERS: &[&str] = &["Host", "Content-Length", "Transfer-Encoding", "Connection"];
+
+/// Response obtained from a server
+pub struct Response {
+    pub status: u16,
+    pub body: String
+}
+
+/// Target of a request, obtained from an URL
+pub struct Target {
+    tls: bool,
+    host: String,
+    port: u16,
+    path: String
+}
+
+/// Send a request and wait for the response
+///
+/// # Arguments
+///
+/// * `method` - HTTP method to use
+/// * `url` - Target URL, either `http://` or `https://`
+/// * `headers` - Additional headers to send
+/// * `body` - Request body
+/// * `timeout` - Timeout for connecting, reading and writing
+pub fn request(
+    method: &str,
+    url: &str,
+    headers: &[(String, String)],
+    body: &str,
+    timeout: Duration
+) -> Result<Response, String> {
+    let target = parse_url(url)?;
+
+    for (name, value) in headers {
+        check_header(name, value).map_err(|e| format!("{}: {}", e, name))?;
+    }
+
+    let address = (target.host.as_str(), target.port)
+        .to_socket_addrs()
+        .map_err(|e| format!("Cannot resolve {}: {}", target.host, e))?
+        .next()
+        .ok_or_else(|| format!("Cannot resolve {}", target.host))?;
+
+    let stream = TcpStream::connect_timeout(&address, timeout)
+        .map_err(|e| format!("Cannot connect to {}: {}", target.host, e))?;
+    stream.set_read_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+    stream.set_write_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+
+    let mut message = format!(
+        "{} {} HTTP/1.1\r\n\
+        Host: {}\r\n\
+        User-Agent: simpleice/{}\r\n\
+        Connection: close\r\n\
+        Content-Length: {}\r\n",
+        method,
+        target.path,
+        if target.port == (if target.tls {443} else {80}) {
+            target.host.clone()
+        } else {
+            format!("{}:{}", target.host, target.port)
+        },
+        env!("CARGO_PKG_VERSION"),
+        body.len()
+    );
+
+    for (name, value) in headers {
+        message.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    message.push_str("\r\n");
+    message.push_str(body);
+
+    let raw = if target.tls {
+        let connector = SslConnector::builder(SslMethod::tls())
+            .map_err(|e| e.to_string())?
+            .build();
+        let mut tls = connector.connect(&target.host, stream)
+            .map_err(|e| format!("TLS error: {}", e))?;
+
+        exchange(&mut tls, &message)?
+    } else {
+        let mut stream = stream;
+        exchange(&mut stream, &message)?
+    };
+
+    parse_response(&raw)
+}
+
+/// Check that a header can be sent as is
+///
+/// Names must be HTTP tokens and values cannot contain control characters,
+/// which would allow injecting headers or splitting the request. Headers the
+/// client sets itself cannot be given twice.
+///
+/// # Arguments
+///
+/// * `name` - Header name
+/// * `value` - Header value
+pub fn check_header(name: &str, value: &str) -> Result<(), &'static str> {
+    if name.is_empty() || !name.chars().all(|c| {
+        c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)
+    }) {
+        return Err("Invalid header name");
+    }
+
+    if RESERVED_HEADERS.iter().any(|h| h.eq_ignore_ascii_case(name)) {
+        return Err("Header is set by simpleice");
+    }
+
+    if value.chars().any(|c| c.is_control() && c != '\t') {
+        return Err("Header values cannot contain control characters");
+    }
+
+    Ok(())
+}
+
+/// Write a request and read the response, up to `MAX_RESPONSE_SIZE` bytes
+fn exchange<S: Read + Write>(stream: &mut S, message: &str) -> Result<Vec<u8>, String> {
+    stream.write_all(message.as_bytes()).map_err(|e| format!("Write error: {}", e))?;
+    stream.flush().map_err(|e| format!("Write error: {}", e))?;
+
+    let mut raw = Vec::new();
+    match stream.take(MAX_RESPONSE_SIZE).read_to_end(&mut raw) {
+        Ok(_) => Ok(raw),
+        // Some servers close TLS connections without notifying
+        Err(_) if !raw.is_empty() => Ok(raw),
+        Err(e) => Err(format!("Read error: {}", e))
+    }
+}
+
+/// Split an URL into its parts
+///
+/// URLs with whitespace or control characters are refused, they would end up
+/// in the request line as is.
+///
+/// # Arguments
+///
+/// * `url` - URL to parse, either `http://` or `https://`
+pub fn parse_url(url: &str) -> Result<Target, String> {
+    if url.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err(format!("URLs cannot contain spaces or control characters: {:?}", url));
+    }
+
+    let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+        (true, rest)
+    } else if let Some(rest) = url.strip_prefix("http://") {
+        (false, rest)
+    } else {
+        return Err(format!("Unsupported URL: {}", url));
+    };
+
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/")
+    };
+
+    let (host, port) = match authority.rfind(':') {
+        Some(i) => {
+            let port = authority[i + 1..].parse::<u16>()
+                .map_err(|_| format!("Invalid port in URL: {}", url))?;
+            (&authority[..i], port)
+        },
+        None => (authority, if tls {443} else {80})
+    };
+
+    if host.is_empty() {
+        return Err(format!("Invalid URL: {}", url));
+    }
+
+    Ok(Target {
+        tls,
+        host: host.to_string(),
+        port,
+        path: path.to_string()
+    })
+}
+
+/// Parse the status and body of a raw response
+fn parse_response(raw: &[u8]) -> Result<Response, String> {
+    let separator = raw.windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| "Invalid HTTP response".to_string())?;
+
+    let head = String::from_utf8_lossy(&raw[..separator]);
+    let mut lines = head.lines();
+
+    let status = lines.next()
+        .and_then(|l| l.split_whitespace().nth(1))
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or_else(|| "Invalid HTTP status line".to_string())?;
+
+    let chunked = lines.any(|l| {
+        let l = l.to_lowercase();
+        l.starts_with("transfer-encoding:") && l.contains("chunked")
+    });
+
+    let body = &raw[separator + 4..];
+    let body = if chunked {decode_chunked(body)} else {body.to_vec()};
+
+    Ok(Response {
+        status,
+        body: String::from_utf8_lossy(&body).into_owned()
+    })
+}
+
+/// Decode a body sent with chunked transfer encoding
+fn decode_chunked(mut data: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+
+    while let Some(end) = data.windows(2).position(|w| w == b"\r\n") {
+        let size_line = String::from_utf8_lossy(&data[..end]);
+        let size = match usize::from_str_radix(size_line.split(';').next().unwrap().trim(), 16) {
+            Ok(v) => v,
+            Err(_) => break
+        };
+
+        if size == 0 || data.len() < end + 2 + size {
+            break;
+        }
+
+        body.extend_from_slice(&data[end + 2..end + 2 + size]);
+        data = &data[(end + 4 + size).min(data.len())..];
+    }
+
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{self, Cursor};
+
+    /// Stream answering with a fixed response
+    struct Stream(Cursor<Vec<u8>>);
+
+    impl Read for Stream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for Stream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn parse_urls() {
+        let target = parse_url("https://example.com/hook?a=1").unwrap();
+        assert!(target.tls);
+        assert_eq!((target.host.as_str(), target.port), ("example.com", 443));
+        assert_eq!(target.path, "/hook?a=1");
+
+        let target = parse_url("http://127.0.0.1:8080").unwrap();
+        assert!(!target.tls);
+        assert_eq!((target.host.as_str(), target.port), ("127.0.0.1", 8080));
+        assert_eq!(target.path, "/");
+
+        assert!(parse_url("ftp://example.com/").is_err());
+        assert!(parse_url("https://:443/").is_err());
+        assert!(parse_url("https://example.com:port/").is_err());
+    }
+
+    #[test]
+    fn refuse_request_splitting_urls() {
+        assert!(parse_url("https://example.com/a b").is_err());
+        assert!(parse_url("https://example.com/a HTTP/1.1\r\nX-Injected: 1").is_err());
+        assert!(parse_url("https://example.com/\n").is_err());
+        assert!(parse_url("https://example.com/a\tb").is_err());
+    }
+
+    #[test]
+    fn check_headers() {
+        assert!(check_header("X-Token", "a b\tc").is_ok());
+        assert!(check_header("X Token", "value").is_err());
+        assert!(check_header("X-Token\r\nX-Injected", "value").is_err());
+        assert!(check_header("X-Token", "a\r\nX-Injected: b").is_err());
+
+        for name in &["Host", "content-length", "Transfer-Encoding", "CONNECTION"] {
+            assert!(check_header(name, "value").is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn response_size_is_bounded() {
+        let mut response = b"HTTP/1.1 200 OK\r\n\r\n".to_vec();
+        response.resize(MAX_RESPONSE_SIZE as usize * 2, b'a');
+
+        let raw = exchange(&mut Stream(Cursor::new(response)), "GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(raw.len(), MAX_RESPONSE_SIZE as usize);
+        assert_eq!(parse_response(&raw).unwrap().status, 200);
+    }
+}
@@ -0,0 +1,160 @@
+// MIT License
+//
+// Copyright (c) 2017 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+//! Exclusive access to the JSON file
+//!
+//! Sending is only done by the process holding the lock of the JSON file,
+//! `<file>.lock`, so that an ICE mail is never sent twice. The daemon keeps
+//! the lock as long as it runs and writes its PID to `<file>.pid`, which lets
+//! `check` hand its work over to it.
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use libc;
+
+/// Lock of a JSON file, released when dropped
+pub struct StoreLock {
+    store: PathBuf,
+    /// Kept open, closing it releases the lock
+    _file: File,
+    /// PID file to remove on release, if one was written
+    pid_file: Option<PathBuf>
+}
+
+impl StoreLock {
+    /// Get the path of the locked JSON file
+    pub fn get_store(&self) -> &Path {
+        &self.store
+    }
+
+    /// Write the PID of the current process next to the JSON file
+    pub fn write_pid(&mut self) -> Result<(), String> {
+        let pid_file = get_pid_path(&self.store);
+
+        fs::write(&pid_file, format!("{}\n", std::process::id()))
+            .map_err(|e| format!("Cannot write PID file {}: {}", pid_file.display(), e))?;
+        self.pid_file = Some(pid_file);
+
+        Ok(())
+    }
+}
+
+impl Drop for StoreLock {
+    fn drop(&mut self) {
+        if let Some(ref pid_file) = self.pid_file {
+            let _ = fs::remove_file(pid_file);
+        }
+    }
+}
+
+/// Try to lock a JSON file without waiting
+///
+/// Returns `None` when another process holds the lock.
+///
+/// # Arguments
+///
+/// * `store` - Path of the JSON file
+pub fn try_lock(store: &Path) -> Result<Option<StoreLock>, String> {
+    let lock_path = get_lock_path(store);
+
+    if let Some(parent) = lock_path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Cannot create {}: {}", parent.display(), e))?;
+    }
+
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)
+        .map_err(|e| format!("Cannot open lock file {}: {}", lock_path.display(), e))?;
+
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let error = io::Error::last_os_error();
+        if error.raw_os_error() == Some(libc::EWOULDBLOCK) {
+            return Ok(None);
+        }
+
+        return Err(format!("Cannot lock {}: {}", lock_path.display(), error));
+    }
+
+    Ok(Some(StoreLock {
+        store: store.to_path_buf(),
+        _file: file,
+        pid_file: None
+    }))
+}
+
+/// Obtain the PID of the daemon processing a JSON file, if it is running
+///
+/// # Arguments
+///
+/// * `store` - Path of the JSON file
+pub fn get_daemon_pid(store: &Path) -> Option<u32> {
+    let pid = fs::read_to_string(get_pid_path(store)).ok()?
+        .trim()
+        .parse::<u32>()
+        .ok()?;
+
+    // Left behind by a daemon that did not exit cleanly
+    if unsafe { libc::kill(pid as libc::pid_t, 0) } != 0 {
+        return None;
+    }
+
+    Some(pid)
+}
+
+/// Ask the daemon to check the ICE mails right away
+///
+/// # Arguments
+///
+/// * `pid` - PID of the daemon
+pub fn request_check(pid: u32) -> Result<(), String> {
+    if unsafe { libc::kill(pid as libc::pid_t, libc::SIGUSR1) } != 0 {
+        return Err(io::Error::last_os_error().to_string());
+    }
+
+    Ok(())
+}
+
+/// Obtain the path of the lock file of a JSON file
+fn get_lock_path(store: &Path) -> PathBuf {
+    with_suffix(store, ".lock")
+}
+
+/// Obtain the path of the PID file of a JSON file
+fn get_pid_path(store: &Path) -> PathBuf {
+    with_suffix(store, ".pid")
+}
+
+/// Append a suffix to the file name of a path
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+
+    PathBuf::from(name)
+}
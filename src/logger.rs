@@ -0,0 +1,364 @@
+// MIT License
+//
+// Copyright (c) 2017 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+//! Logging of the `check` and `daemon` commands
+//!
+//! Messages have a level and, when they are about an ICE mail, its
+//! identifier. They are written to the sinks of the `[log]` table:
+//!
+//! - `stderr`: one line per message, with a timestamp
+//! - `file`: same as `stderr`, rotated once it reaches `max_size` bytes
+//! - `syslog`: the system logger, with the `user` facility
+//! - `journald`: the systemd journal, with the ICE identifier in `ICE_ID`
+//!
+//! Until the configuration is read, messages go to stderr.
+
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::prelude::*;
+use libc;
+
+use config::LogConfig;
+use parser::Ice;
+
+/// Default size in bytes after which the log file is rotated
+pub const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Default number of rotated log files kept
+pub const DEFAULT_MAX_FILES: u32 = 5;
+
+/// Socket of the native protocol of journald
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Identifier of the messages in syslog and journald
+const IDENTIFIER: &[u8] = b"simpleice\0";
+
+/// Logger in use, `None` until configured
+static LOGGER: Mutex<Option<Logger>> = Mutex::new(None);
+
+/// Importance of a message
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug
+}
+
+/// Destination of the messages
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sink {
+    Stderr,
+    File,
+    Syslog,
+    Journald
+}
+
+/// Configured logger
+struct Logger {
+    level: Level,
+    sinks: Vec<Sink>,
+    file: Option<RotatingFile>
+}
+
+/// Log file rotated when it grows too big
+///
+/// Rotated files are named `<file>.1` (the most recent) to `<file>.<n>`.
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: u32,
+    handle: Option<File>,
+    size: u64
+}
+
+impl Level {
+    /// Get the name of the level in log lines
+    fn get_name(&self) -> &'static str {
+        match *self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG"
+        }
+    }
+
+    /// Get the matching syslog priority
+    fn get_priority(&self) -> libc::c_int {
+        match *self {
+            Level::Error => libc::LOG_ERR,
+            Level::Warn => libc::LOG_WARNING,
+            Level::Info => libc::LOG_INFO,
+            Level::Debug => libc::LOG_DEBUG
+        }
+    }
+
+    /// Raise or lower the level
+    ///
+    /// # Arguments
+    ///
+    /// * `steps` - Positive for more messages, negative for less
+    fn adjust(self, steps: i64) -> Level {
+        let levels = [Level::Error, Level::Warn, Level::Info, Level::Debug];
+        let current = levels.iter().position(|l| *l == self).unwrap() as i64;
+
+        levels[(current + steps).clamp(0, levels.len() as i64 - 1) as usize]
+    }
+}
+
+impl RotatingFile {
+    /// Append a line to the file, rotating it first when needed
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - Line to append, with its line break
+    fn write(&mut self, line: &str) -> Result<(), String> {
+        if self.handle.is_some() && self.size + line.len() as u64 > self.max_size {
+            self.handle = None;
+            self.rotate()?;
+        }
+
+        if self.handle.is_none() {
+            let handle = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .map_err(|e| format!("Cannot open log file {}: {}", self.path.display(), e))?;
+
+            self.size = handle.metadata().map(|m| m.len()).unwrap_or(0);
+            self.handle = Some(handle);
+
+            // Already too big when opened
+            if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+                self.handle = None;
+                self.rotate()?;
+                return self.write(line);
+            }
+        }
+
+        let handle = self.handle.as_mut().unwrap();
+        handle.write_all(line.as_bytes())
+            .map_err(|e| format!("Cannot write log file {}: {}", self.path.display(), e))?;
+        self.size += line.len() as u64;
+
+        Ok(())
+    }
+
+    /// Shift the rotated files and move the current one to `<file>.1`
+    fn rotate(&mut self) -> Result<(), String> {
+        let _ = fs::remove_file(get_rotated_path(&self.path, self.max_files));
+
+        for i in (1..self.max_files).rev() {
+            let _ = fs::rename(get_rotated_path(&self.path, i), get_rotated_path(&self.path, i + 1));
+        }
+
+        fs::rename(&self.path, get_rotated_path(&self.path, 1))
+            .map_err(|e| format!("Cannot rotate log file {}: {}", self.path.display(), e))?;
+        self.size = 0;
+
+        Ok(())
+    }
+}
+
+/// Configure the logger
+///
+/// # Arguments
+///
+/// * `conf` - Logging configuration
+/// * `verbosity` - Levels to add to the configured one, negative to remove
+pub fn init(conf: &LogConfig, verbosity: i64) -> Result<(), String> {
+    let file = if conf.sinks.contains(&Sink::File) {
+        let path = conf.file.clone().ok_or("`log.file` is required by the file sink")?;
+
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("Cannot create {}: {}", parent.display(), e))?;
+        }
+
+        Some(RotatingFile {
+            path,
+            max_size: conf.max_size,
+            max_files: conf.max_files,
+            handle: None,
+            size: 0
+        })
+    } else {
+        None
+    };
+
+    if conf.sinks.contains(&Sink::Syslog) {
+        unsafe {
+            libc::openlog(IDENTIFIER.as_ptr() as *const libc::c_char, libc::LOG_PID, libc::LOG_USER);
+        }
+    }
+
+    *LOGGER.lock().unwrap() = Some(Logger {
+        level: conf.level.adjust(verbosity),
+        sinks: conf.sinks.clone(),
+        file
+    });
+
+    Ok(())
+}
+
+/// Log a message
+///
+/// # Arguments
+///
+/// * `level` - Importance of the message
+/// * `message` - Message to log
+pub fn log(level: Level, message: &str) {
+    write(level, None, message);
+}
+
+/// Log a message about an ICE mail
+///
+/// # Arguments
+///
+/// * `level` - Importance of the message
+/// * `ice` - ICE mail the message is about
+/// * `message` - Message to log
+pub fn log_ice(level: Level, ice: &Ice, message: &str) {
+    write(level, Some(ice.get_id()), message);
+}
+
+/// Write a message to every sink
+///
+/// # Arguments
+///
+/// * `level` - Importance of the message
+/// * `ice` - Identifier of the ICE mail the message is about, if any
+/// * `message` - Message to log
+fn write(level: Level, ice: Option<&str>, message: &str) {
+    let mut guard = LOGGER.lock().unwrap();
+
+    let logger = match *guard {
+        Some(ref mut v) => v,
+        None => {
+            if level <= Level::Info {
+                eprint!("{}", format_line(level, ice, message));
+            }
+            return;
+        }
+    };
+
+    if level > logger.level {
+        return;
+    }
+
+    for sink in &logger.sinks {
+        match *sink {
+            Sink::Stderr => eprint!("{}", format_line(level, ice, message)),
+            Sink::File => {
+                let line = format_line(level, ice, message);
+                if let Some(Err(e)) = logger.file.as_mut().map(|f| f.write(&line)) {
+                    eprintln!("{}", e);
+                    eprint!("{}", line);
+                }
+            },
+            Sink::Syslog => write_syslog(level, ice, message),
+            Sink::Journald => write_journald(level, ice, message)
+        }
+    }
+}
+
+/// Format a message as a line of text
+fn format_line(level: Level, ice: Option<&str>, message: &str) -> String {
+    format!(
+        "{} {:<5} {}{}\n",
+        Local::now().format("%F %T"),
+        level.get_name(),
+        ice.map(|i| format!("[ice {}] ", i)).unwrap_or_default(),
+        message
+    )
+}
+
+/// Send a message to syslog
+fn write_syslog(level: Level, ice: Option<&str>, message: &str) {
+    let text = match ice {
+        Some(id) => format!("[ice {}] {}", id, message),
+        None => message.to_string()
+    };
+
+    let text = match CString::new(text.replace('\0', "")) {
+        Ok(v) => v,
+        Err(_) => return
+    };
+
+    unsafe {
+        libc::syslog(level.get_priority(), b"%s\0".as_ptr() as *const libc::c_char, text.as_ptr());
+    }
+}
+
+/// Send a message to journald with its native protocol
+fn write_journald(level: Level, ice: Option<&str>, message: &str) {
+    let identifier = String::from_utf8_lossy(&IDENTIFIER[..IDENTIFIER.len() - 1]);
+    let priority = level.get_priority().to_string();
+
+    let mut fields = vec![
+        ("MESSAGE", message),
+        ("PRIORITY", priority.as_str()),
+        ("SYSLOG_IDENTIFIER", &identifier)
+    ];
+    if let Some(id) = ice {
+        fields.push(("ICE_ID", id));
+    }
+
+    let mut datagram = Vec::new();
+    for (name, value) in fields {
+        datagram.extend_from_slice(name.as_bytes());
+
+        // Values with line breaks are sent with their length
+        if value.contains('\n') {
+            datagram.push(b'\n');
+            datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            datagram.push(b'=');
+        }
+
+        datagram.extend_from_slice(value.as_bytes());
+        datagram.push(b'\n');
+    }
+
+    let sent = UnixDatagram::unbound()
+        .and_then(|s| s.send_to(&datagram, JOURNALD_SOCKET));
+    if sent.is_err() {
+        eprint!("{}", format_line(level, ice, message));
+    }
+}
+
+/// Obtain the path of a rotated log file
+fn get_rotated_path(path: &Path, index: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", index));
+
+    PathBuf::from(name)
+}
@@ -28,19 +28,41 @@
 //!
 //! There are a series of commands available in simpleice:
 //!
+//! - `auth login`: obtains an OAuth2 refresh token for an account using
+//!   XOAUTH2 authentication
 //! - `check`: looks through the list of mails and sends any pending mail
-//! - `daemon`: periodically (every hour) runs the `check` command and sends
-//! emails
+//! - `check-in`: postpones the delivery date of an active mail
+//! - `combine`: rebuilds a secret from the shares sent by a split ICE mail
+//! - `config migrate`: converts a legacy INI configuration file to TOML
+//! - `confirm-clock`: sends the mails the daemon held because they became due
+//!   only after the system clock jumped forward
+//! - `daemon`: runs the `check` command whenever an ICE mail is due and sends
+//!   emails, for every profile unless `--profile` is given
 //! - `activate`: enables delivery for a specific mail and sets date and time
 //! in which the mail should be delivered
-//! - `create-config`: creates an empty configuration file in the home folder
-//! of the user
+//! - `cron install`: adds an entry running `check` to the crontab of the user,
+//!   every 5 minutes unless `--every` is given, `cron uninstall` removes it and
+//!   `cron status` shows it
+//! - `create-config`: asks for the mail account and the storage of the mails
+//!   and creates the configuration file (an empty file is created when not run
+//!   from a terminal)
 //! - `deactivate`: disables delivery for a specific mail
+//! - `doctor`: checks the configuration, the ICE mails and the connection to
+//!   the server without sending anything
 //! - `edit`: edits a mail and sets recipients of the message
+//! - `install-service`: writes systemd units running the daemon, or `check`
+//!   through a timer
 //! - `list`: lists all existing mails and their current status
+//! - `migrate-password`: moves the plaintext SMTP password to the system
+//!   keyring
 //! - `new`: creates a new mail
 //! - `remove`: removes a mail from the list
 //! - `show`: shows the contents of a specific mail
+//! - `status`: shows the state of the daemon processing the mails, if any
+//! - `test-send`: sends a mail to the sender address only, to check what the
+//!   recipients would receive
+//! - `watchdog`: emails the owner when no check went through recently or when
+//!   active mails were not sent in time
 
 //! Application launcher
 
@@ -50,21 +72,43 @@ extern crate clap;
 extern crate console;
 extern crate dialoguer;
 extern crate lettre;
+extern crate libc;
 extern crate ini;
+extern crate openssl;
 extern crate serde;
 extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
+extern crate toml;
 
+use chrono::prelude::*;
 use clap::{Arg, App, SubCommand};
 use console::Term;
-use ini::Ini;
 
+use clock::{Clock, FixedClock, SystemClock};
 use parser::Ice;
 
+mod actions;
+mod clock;
 mod commands;
 mod config;
+mod control;
+mod cron;
+mod http;
+mod lock;
+mod logger;
+mod metrics;
+mod oauth;
 mod parser;
+mod password;
+mod pgp;
+mod scheduler;
+mod shamir;
+mod signals;
+mod smtp;
+mod systemd;
+mod watchdog;
+mod webhook;
 
 
 fn main() {
@@ -74,70 +118,242 @@ fn main() {
         .about("Schedule emails in case of emergency")
         .arg(Arg::with_name("command")
             .possible_values(&[
+                "auth",
                 "check",
+                "check-in",
+                "combine",
+                "config",
+                "confirm-clock",
                 "daemon",
                 "activate",
                 "create-config",
+                "cron",
                 "deactivate",
+                "doctor",
                 "edit",
+                "install-service",
                 "list",
+                "migrate-password",
                 "new",
                 "remove",
                 "show",
+                "status",
+                "test-send",
+                "watchdog",
             ])
             .hide_possible_values(true)
             .required(true)
             .takes_value(true)
             .long_help("\
                 Available commands:\n\n\
+                auth login          Obtain an OAuth2 token for an XOAUTH2 account\n\
                 check               Check if there are scheduled emails to send\n\
+                check-in            Postpone the delivery date of an active ICE mail\n\
+                combine             Rebuild a secret from its shares\n\
+                config migrate      Convert a legacy INI configuration file to TOML\n\
+                confirm-clock       Send the ICE mails the daemon held after a clock jump\n\
                 daemon              Run in daemon mode\n\
                 activate            Set delivery date and activate an ICE mail\n\
-                create-config       Create empty configuration file\n\
+                create-config       Create the configuration file interactively\n\
+                cron install        Add a crontab entry running `check` (also `uninstall` and `status`)\n\
                 deactivate          Deactivate an active ICE mail\n\
+                doctor              Check the configuration and the connection to the server\n\
                 edit                Edit an existing ICE mail\n\
+                install-service     Write systemd units for the daemon and a check timer\n\
                 list                List existing ICE mails\n\
+                migrate-password    Move the SMTP password to the system keyring\n\
                 new                 Create new ICE mail\n\
                 remove              Remove an ICE mail\n\
-                show                Show details of an ICE mail"))
+                show                Show details of an ICE mail\n\
+                status              Show the state of the daemon processing the ICE mails\n\
+                test-send           Send an ICE mail to the sender address only\n\
+                watchdog            Alert the owner when the scheduler stopped working"))
+        .arg(Arg::with_name("action")
+            .takes_value(true)
+            .help("Action for commands that take one, such as `auth login` or `cron install`"))
+        .arg(Arg::with_name("config")
+            .long("config")
+            .takes_value(true)
+            .value_name("PATH")
+            .help("Configuration file to use instead of looking it up"))
+        .arg(Arg::with_name("every")
+            .long("every")
+            .takes_value(true)
+            .value_name("INTERVAL")
+            .help("How often the entry of `cron install` runs `check`, such as 5m or 1h (5m by default)"))
+        .arg(Arg::with_name("profile")
+            .long("profile")
+            .takes_value(true)
+            .value_name("NAME")
+            .help("Profile of the configuration file to use (`daemon` checks all of them by default)"))
+        .arg(Arg::with_name("dry-run")
+            .long("dry-run")
+            .help("Report what `check` would send without sending anything"))
+        .arg(Arg::with_name("at")
+            .long("at")
+            .takes_value(true)
+            .value_name("DATETIME")
+            .help("Run `check` as of the given date (yyyy-mm-dd HH:MM), implies --dry-run"))
+        .arg(Arg::with_name("system")
+            .long("system")
+            .help("Write system units instead of user ones with `install-service`"))
+        .arg(Arg::with_name("verbose")
+            .short("v")
+            .multiple(true)
+            .help("Log more details, can be repeated"))
+        .arg(Arg::with_name("quiet")
+            .short("q")
+            .multiple(true)
+            .conflicts_with("verbose")
+            .help("Log less details, can be repeated"))
+        .arg(Arg::with_name("to")
+            .long("to")
+            .takes_value(true)
+            .value_name("ADDRESS")
+            .help("Address `test-send` delivers to instead of the sender address"))
         .get_matches();
 
     let term = Term::stdout();
     let command = matches.value_of("command").unwrap();
 
-    // Special case, create empty config
+    if let Some(path) = matches.value_of("config") {
+        config::set_config_path(path);
+    }
+
+    if let Some(name) = matches.value_of("profile") {
+        config::set_profile(name);
+    }
+
+    // Special case, combine does not need a configuration
+    if command == "combine" {
+        commands::combine_shares(&term);
+        return;
+    }
+
+    // Special case, create config, interactively when possible
     if command == "create-config" {
+        if term.is_term() {
+            commands::create_config(&term);
+            return;
+        }
+
         match config::write_empty_config() {
-            Ok(_) => term.write_line("Empty config file created in ~/.simpleice"),
-            Err(e) => term.write_line(e)
+            Ok(path) => term.write_line(
+                format!("Empty config file created in {}", path.display()).as_str()
+            ),
+            Err(e) => term.write_line(e.as_str())
         };
 
         return;
     }
 
+    // Special case, the legacy configuration file is converted as is
+    if command == "config" {
+        match matches.value_of("action") {
+            Some("migrate") => commands::migrate_config(&term),
+            _ => {
+                let _ = term.write_line("Unknown action for `config`, expected `migrate`");
+            }
+        }
+
+        return;
+    }
+
     // Check config
     let conf = match config::read_config() {
         Ok(v) => {v},
         Err(e) => {
-            term.write_line(e);
-            term.write_line(
-                "You can create an empty configuration file using the `create-config` command"
-            );
+            let _ = term.write_line(e.as_str());
+            if !config::get_config_path().is_ok_and(|p| p.exists()) {
+                let _ = term.write_line(
+                    "You can create a configuration file using the `create-config` command"
+                );
+            }
             return;
         }
     };
 
+    let verbosity = matches.occurrences_of("verbose") as i64 - matches.occurrences_of("quiet") as i64;
+    if let Err(e) = logger::init(&conf.log, verbosity) {
+        let _ = term.write_line(format!("Invalid log configuration: {}", e).as_str());
+        return;
+    }
+
+    // Special case, doctor reports invalid configurations itself
+    if command == "doctor" {
+        commands::doctor(&term, &conf, &SystemClock);
+        return;
+    }
+
+    if let Err(e) = smtp::validate(&conf) {
+        let _ = term.write_line(format!("Invalid mail configuration: {}", e).as_str());
+        return;
+    }
+
+    // Simulated date for `check`
+    let at = match matches.value_of("at") {
+        Some(v) => match NaiveDateTime::parse_from_str(v, "%F %R") {
+            Ok(date) => match Local.from_local_datetime(&date).single() {
+                Some(date) => Some(date),
+                None => {
+                    let _ = term.write_line("Ambiguous date for --at");
+                    return;
+                }
+            },
+            Err(_) => {
+                let _ = term.write_line("Invalid date format for --at, expected yyyy-mm-dd HH:MM");
+                return;
+            }
+        },
+        None => None
+    };
+
+    let check_options = commands::CheckOptions {
+        dry_run: matches.is_present("dry-run") || at.is_some(),
+        ..commands::CheckOptions::default()
+    };
+
+    // Only `check` runs as of the simulated date
+    let check_clock: Box<dyn Clock> = match at {
+        Some(v) => Box::new(FixedClock(v)),
+        None => Box::new(SystemClock)
+    };
+
     // Check command to run
     match command {
-        "activate" => commands::activate_ice(&term, &conf),
-        "check" => commands::check(&term, &conf),
-        "daemon" => commands::daemon(&term, &conf),
-        "deactivate" => commands::deactivate_ice(&term, &conf),
-        "edit" => commands::edit_ice(&term, &conf),
+        "activate" => commands::activate_ice(&term, &conf, &SystemClock),
+        "auth" => match matches.value_of("action") {
+            Some("login") => commands::auth_login(&term, &conf),
+            _ => {
+                let _ = term.write_line("Unknown action for `auth`, expected `login`");
+            }
+        },
+        "check" => commands::check(&term, &conf, &check_options, check_clock.as_ref()),
+        "check-in" => commands::check_in(&term, &conf, &SystemClock),
+        "confirm-clock" => commands::confirm_clock(&term, &conf, &SystemClock),
+        "cron" => match matches.value_of("action") {
+            Some("install") => commands::cron_install(&term, matches.value_of("every")),
+            Some("uninstall") => commands::cron_uninstall(&term),
+            Some("status") => commands::cron_status(&term),
+            _ => {
+                let _ = term.write_line(
+                    "Unknown action for `cron`, expected `install`, `uninstall` or `status`"
+                );
+            }
+        },
+        // Without a profile, every profile is checked
+        "daemon" => commands::daemon(&term, !config::has_profile(), &SystemClock),
+        "deactivate" => commands::deactivate_ice(&term, &conf, &SystemClock),
+        "edit" => commands::edit_ice(&term, &conf, &SystemClock),
+        "install-service" => commands::install_service(&term, matches.is_present("system")),
         "list" => commands::list_ices(&term, &conf),
-        "new" => commands::create_ice(&term, &conf),
-        "remove" => commands::remove_ice(&term, &conf),
+        "migrate-password" => commands::migrate_password(&term, &conf),
+        "new" => commands::create_ice(&term, &conf, &SystemClock),
+        "remove" => commands::remove_ice(&term, &conf, &SystemClock),
         "show" => commands::show_ice(&term, &conf),
+        "status" => commands::daemon_status(&term, &conf),
+        "test-send" => commands::test_send(&term, &conf, matches.value_of("to")),
+        "watchdog" => commands::watchdog(&conf, &SystemClock),
         _ => ()
     }
 }
@@ -0,0 +1,322 @@
+// MIT License
+//
+// Copyright (c) 2017 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+//! Metrics of the `daemon` command
+//!
+//! When `metrics_address` is set in the `[daemon]` table, the daemon serves
+//! over HTTP:
+//!
+//! - `/metrics`: metrics in the Prometheus text format
+//! - `/healthz`: `200 OK` while the main loop is running, `503` when it has
+//!   been stuck for longer than `HEALTH_TIMEOUT`
+
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::prelude::*;
+
+use logger;
+use logger::Level;
+use parser::Ice;
+
+/// Seconds without the main loop running after which the daemon is unhealthy
+pub const HEALTH_TIMEOUT: u64 = 600;
+
+/// Seconds to wait for a client
+const TIMEOUT: u64 = 5;
+
+/// Largest request accepted, in bytes
+const MAX_REQUEST: usize = 8192;
+
+/// Transport used to deliver an ICE mail
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Transport {
+    Smtp,
+    Webhook,
+    Action
+}
+
+/// Number of ICE mails of a profile in each state
+#[derive(Default)]
+struct IceCounts {
+    inactive: u64,
+    active: u64,
+    /// Active and due, sending failed so far
+    overdue: u64
+}
+
+/// Outcome of the deliveries of a transport
+#[derive(Default)]
+struct SendCounts {
+    successes: u64,
+    failures: u64
+}
+
+struct Metrics {
+    alive: Option<Instant>,
+    ices: BTreeMap<String, IceCounts>,
+    last_checks: BTreeMap<String, DateTime<Local>>,
+    next_due: Option<DateTime<Local>>,
+    /// By transport and account
+    sends: BTreeMap<(Transport, String), SendCounts>
+}
+
+static METRICS: Mutex<Metrics> = Mutex::new(Metrics {
+    alive: None,
+    ices: BTreeMap::new(),
+    last_checks: BTreeMap::new(),
+    next_due: None,
+    sends: BTreeMap::new()
+});
+
+impl Transport {
+    /// Get the name used as label
+    fn get_name(self) -> &'static str {
+        match self {
+            Transport::Smtp => "smtp",
+            Transport::Webhook => "webhook",
+            Transport::Action => "action"
+        }
+    }
+}
+
+/// Serve the metrics in a background thread
+///
+/// # Arguments
+///
+/// * `address` - Address and port to listen on, such as `127.0.0.1:9464`
+pub fn serve(address: &str) -> Result<(), String> {
+    let listener = TcpListener::bind(address)
+        .map_err(|e| format!("Cannot serve metrics on {}: {}", address, e))?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Err(e) = answer(stream) {
+                logger::log(Level::Debug, &format!("Metrics request failed: {}", e));
+            }
+        }
+    });
+
+    logger::log(Level::Info, &format!("Serving metrics on {}", address));
+    Ok(())
+}
+
+/// Record that the main loop is running
+pub fn touch() {
+    METRICS.lock().unwrap().alive = Some(Instant::now());
+}
+
+/// Record the ICE mails of a profile
+///
+/// # Arguments
+///
+/// * `profile` - Name of the profile
+/// * `ices` - ICE mails of the profile
+/// * `now` - Current date
+pub fn set_ices(profile: &str, ices: &[Ice], now: DateTime<Local>) {
+    let mut counts = IceCounts::default();
+
+    for ice in ices {
+        match ice.get_date() {
+            _ if !ice.is_active() => counts.inactive += 1,
+            Some(date) if date <= now => counts.overdue += 1,
+            _ => counts.active += 1
+        }
+    }
+
+    METRICS.lock().unwrap().ices.insert(profile.to_string(), counts);
+}
+
+/// Record a check that went through
+///
+/// # Arguments
+///
+/// * `profile` - Name of the profile
+/// * `date` - Date of the check
+pub fn set_last_check(profile: &str, date: DateTime<Local>) {
+    METRICS.lock().unwrap().last_checks.insert(profile.to_string(), date);
+}
+
+/// Record the date of the next ICE mail, if any
+///
+/// # Arguments
+///
+/// * `date` - Date of the earliest active ICE mail
+pub fn set_next_due(date: Option<DateTime<Local>>) {
+    METRICS.lock().unwrap().next_due = date;
+}
+
+/// Forget the profiles, after the configuration is reloaded
+pub fn clear_profiles() {
+    let mut metrics = METRICS.lock().unwrap();
+    metrics.ices.clear();
+    metrics.last_checks.clear();
+}
+
+/// Record the outcome of a delivery
+///
+/// # Arguments
+///
+/// * `transport` - Transport used
+/// * `account` - Name of the mail account, for SMTP
+/// * `success` - Whether the delivery succeeded
+pub fn record_send(transport: Transport, account: Option<&str>, success: bool) {
+    let mut metrics = METRICS.lock().unwrap();
+    let counts = metrics.sends
+        .entry((transport, account.unwrap_or_default().to_string()))
+        .or_default();
+
+    if success {
+        counts.successes += 1;
+    } else {
+        counts.failures += 1;
+    }
+}
+
+/// Answer a single HTTP request
+fn answer(mut stream: TcpStream) -> Result<(), String> {
+    stream.set_read_timeout(Some(Duration::from_secs(TIMEOUT))).map_err(|e| e.to_string())?;
+    stream.set_write_timeout(Some(Duration::from_secs(TIMEOUT))).map_err(|e| e.to_string())?;
+
+    // Only the request line matters, headers are read and ignored
+    let mut raw = Vec::new();
+    let mut buffer = [0; 1024];
+    while !raw.windows(4).any(|w| w == b"\r\n\r\n") && raw.len() < MAX_REQUEST {
+        let read = stream.read(&mut buffer).map_err(|e| e.to_string())?;
+        if read == 0 {
+            break;
+        }
+        raw.extend_from_slice(&buffer[..read]);
+    }
+
+    let head = String::from_utf8_lossy(&raw);
+    let mut parts = head.lines().next().unwrap_or_default().split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+    // Query strings are ignored
+    let path = path.split('?').next().unwrap_or_default();
+
+    let (status, body) = match (method, path) {
+        ("GET", "/metrics") => ("200 OK", render(Local::now())),
+        ("GET", "/healthz") => if is_healthy() {
+            ("200 OK", "ok\n".to_string())
+        } else {
+            ("503 Service Unavailable", "stalled\n".to_string())
+        },
+        ("GET", _) => ("404 Not Found", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_string())
+    };
+
+    let content_type = if path == "/metrics" && status.starts_with("200") {
+        "text/plain; version=0.0.4; charset=utf-8"
+    } else {
+        "text/plain; charset=utf-8"
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\n\
+        Content-Type: {}\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\
+        \r\n\
+        {}",
+        status, content_type, body.len(), body
+    );
+
+    stream.write_all(response.as_bytes()).map_err(|e| e.to_string())
+}
+
+/// Whether the main loop ran recently
+fn is_healthy() -> bool {
+    METRICS.lock().unwrap().alive
+        .is_some_and(|a| a.elapsed() < Duration::from_secs(HEALTH_TIMEOUT))
+}
+
+/// Write the metrics in the Prometheus text format
+///
+/// # Arguments
+///
+/// * `now` - Current date
+fn render(now: DateTime<Local>) -> String {
+    let metrics = METRICS.lock().unwrap();
+    let mut out = String::new();
+
+    out.push_str("# HELP simpleice_ices Number of ICE mails by state.\n");
+    out.push_str("# TYPE simpleice_ices gauge\n");
+    for (profile, counts) in &metrics.ices {
+        for (state, count) in &[
+            ("inactive", counts.inactive), ("active", counts.active), ("overdue", counts.overdue)
+        ] {
+            out.push_str(&format!(
+                "simpleice_ices{{profile=\"{}\",state=\"{}\"}} {}\n",
+                escape(profile), state, count
+            ));
+        }
+    }
+
+    out.push_str("# HELP simpleice_next_due_seconds Seconds until the next active ICE mail is due, negative when overdue.\n");
+    out.push_str("# TYPE simpleice_next_due_seconds gauge\n");
+    if let Some(date) = metrics.next_due {
+        out.push_str(&format!("simpleice_next_due_seconds {}\n", (date - now).num_seconds()));
+    }
+
+    out.push_str("# HELP simpleice_last_check_timestamp_seconds Time of the last check that went through.\n");
+    out.push_str("# TYPE simpleice_last_check_timestamp_seconds gauge\n");
+    for (profile, date) in &metrics.last_checks {
+        out.push_str(&format!(
+            "simpleice_last_check_timestamp_seconds{{profile=\"{}\"}} {}\n",
+            escape(profile), date.timestamp()
+        ));
+    }
+
+    for (name, help, successes) in &[
+        ("simpleice_send_successes_total", "Deliveries that succeeded.", true),
+        ("simpleice_send_failures_total", "Deliveries that failed.", false)
+    ] {
+        out.push_str(&format!("# HELP {} {}\n# TYPE {} counter\n", name, help, name));
+
+        for ((transport, account), counts) in &metrics.sends {
+            let labels = if account.is_empty() {
+                format!("transport=\"{}\"", transport.get_name())
+            } else {
+                format!("transport=\"{}\",account=\"{}\"", transport.get_name(), escape(account))
+            };
+
+            out.push_str(&format!(
+                "{}{{{}}} {}\n",
+                name, labels, if *successes {counts.successes} else {counts.failures}
+            ));
+        }
+    }
+
+    out
+}
+
+/// Escape a label value
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
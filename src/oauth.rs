@@ -0,0 +1,535 @@
+// MIT License
+//
+// Copyright (c) 2017 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! OAuth2 support for XOAUTH2 authentication
+//!
+//! Accounts with `auth = xoauth2` use the following keys:
+//!
+//! - `oauth_token_url`: token endpoint of the provider
+//! - `oauth_auth_url`: authorization endpoint, only needed by `auth login`
+//! - `oauth_client_id` and `oauth_client_secret`: client credentials
+//! - `oauth_scope`: scope requested when logging in
+//!
+//! The refresh token takes the place of the password of the account, so it
+//! is obtained from `password_command`, `password_keyring` or `password`.
+//! A new access token is requested from the token endpoint every time a
+//! connection to the server is made. When the provider rotates the refresh
+//! token, the new one replaces the old one in the keyring (other password
+//! sources cannot be updated, run `auth login` again for them).
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use openssl::base64;
+use openssl::rand::rand_bytes;
+use openssl::sha::sha256;
+use serde_json;
+use serde_json::Value;
+
+use config::AccountConfig;
+use http;
+
+/// Timeout for token requests
+const TIMEOUT: u64 = 30;
+
+/// Time the user has to complete the login in the browser, in seconds
+const LOGIN_TIMEOUT: u64 = 300;
+
+/// OAuth2 settings of an account
+#[derive(Clone)]
+pub struct OAuthSettings {
+    token_url: String,
+    auth_url: Option<String>,
+    client_id: String,
+    client_secret: Option<String>,
+    scope: Option<String>
+}
+
+/// Tokens obtained with a refresh token
+pub struct Tokens {
+    pub access_token: String,
+    /// New refresh token, for providers that rotate them
+    pub refresh_token: Option<String>
+}
+
+/// Interactive login in progress
+///
+/// The user must open the authorization URL in a browser, which is then
+/// redirected to a local listener with the authorization code.
+pub struct Login {
+    listener: TcpListener,
+    redirect_uri: String,
+    state: String,
+    verifier: String,
+    url: String
+}
+
+impl Login {
+    /// Get the URL the user has to open in a browser
+    pub fn get_url(&self) -> &str {
+        &self.url
+    }
+}
+
+/// Obtain the OAuth2 settings of a mail account
+///
+/// # Arguments
+///
+/// * `account` - Mail account of the configuration file
+pub fn get_settings(account: &AccountConfig) -> Result<OAuthSettings, String> {
+    let get = |value: &Option<String>| value.clone().filter(|v| !v.is_empty());
+
+    let token_url = get(&account.oauth_token_url)
+        .ok_or_else(|| "No `oauth_token_url` found for XOAUTH2".to_string())?;
+    check_url("oauth_token_url", &token_url)?;
+
+    let auth_url = get(&account.oauth_auth_url);
+    if let Some(ref v) = auth_url {
+        check_url("oauth_auth_url", v)?;
+    }
+
+    let client_id = get(&account.oauth_client_id)
+        .ok_or_else(|| "No `oauth_client_id` found for XOAUTH2".to_string())?;
+
+    Ok(OAuthSettings {
+        token_url,
+        auth_url,
+        client_id,
+        client_secret: get(&account.oauth_client_secret),
+        scope: get(&account.oauth_scope)
+    })
+}
+
+/// Obtain a new access token using a refresh token
+///
+/// # Arguments
+///
+/// * `settings` - OAuth2 settings of the account
+/// * `refresh_token` - Refresh token of the account
+pub fn refresh(settings: &OAuthSettings, refresh_token: &str) -> Result<Tokens, String> {
+    let mut params = vec![
+        ("grant_type", "refresh_token"),
+        ("refresh_token", refresh_token),
+        ("client_id", settings.client_id.as_str())
+    ];
+    if let Some(ref secret) = settings.client_secret {
+        params.push(("client_secret", secret.as_str()));
+    }
+
+    let response = token_request(settings, &params)?;
+
+    let access_token = match response.get("access_token").and_then(|v| v.as_str()) {
+        Some(v) => v.to_string(),
+        None => return Err("Token endpoint did not return an access token".to_string())
+    };
+
+    let refresh_token = response.get("refresh_token")
+        .and_then(|v| v.as_str())
+        .filter(|v| !v.is_empty() && *v != refresh_token)
+        .map(|v| v.to_string());
+
+    Ok(Tokens {
+        access_token,
+        refresh_token
+    })
+}
+
+/// Start an interactive login
+///
+/// # Arguments
+///
+/// * `settings` - OAuth2 settings of the account
+pub fn start_login(settings: &OAuthSettings) -> Result<Login, String> {
+    let auth_url = match settings.auth_url {
+        Some(ref v) => v,
+        None => return Err("No `oauth_auth_url` found for XOAUTH2".to_string())
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .map_err(|e| format!("Cannot listen for the redirection: {}", e))?;
+    let port = listener.local_addr().map_err(|e| e.to_string())?.port();
+    let redirect_uri = format!("http://127.0.0.1:{}/", port);
+
+    // PKCE (RFC 7636) verifier and challenge, plus a state to match the
+    // redirection with this login
+    let verifier = random_token()?;
+    let challenge = base64_url(&sha256(verifier.as_bytes()));
+    let state = random_token()?;
+
+    let mut params = vec![
+        ("response_type", "code"),
+        ("client_id", settings.client_id.as_str()),
+        ("redirect_uri", redirect_uri.as_str()),
+        ("state", state.as_str()),
+        ("code_challenge", challenge.as_str()),
+        ("code_challenge_method", "S256"),
+        // Needed by some providers to issue refresh tokens
+        ("access_type", "offline"),
+        ("prompt", "consent")
+    ];
+    if let Some(ref scope) = settings.scope {
+        params.push(("scope", scope.as_str()));
+    }
+
+    let url = format!(
+        "{}{}{}",
+        auth_url,
+        if auth_url.contains('?') {"&"} else {"?"},
+        encode_form(&params)
+    );
+
+    Ok(Login {
+        listener,
+        redirect_uri,
+        state,
+        verifier,
+        url
+    })
+}
+
+/// Wait for the user to complete a login and obtain the refresh token
+///
+/// # Arguments
+///
+/// * `settings` - OAuth2 settings of the account
+/// * `login` - Login in progress
+pub fn finish_login(settings: &OAuthSettings, login: &Login) -> Result<String, String> {
+    let code = wait_for_code(login)?;
+
+    let mut params = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code.as_str()),
+        ("redirect_uri", login.redirect_uri.as_str()),
+        ("client_id", settings.client_id.as_str()),
+        ("code_verifier", login.verifier.as_str())
+    ];
+    if let Some(ref secret) = settings.client_secret {
+        params.push(("client_secret", secret.as_str()));
+    }
+
+    let response = token_request(settings, &params)?;
+
+    match response.get("refresh_token").and_then(|v| v.as_str()) {
+        Some(v) => Ok(v.to_string()),
+        None => Err("Token endpoint did not return a refresh token".to_string())
+    }
+}
+
+/// Build the initial response of the XOAUTH2 mechanism
+///
+/// # Arguments
+///
+/// * `username` - Account name
+/// * `access_token` - Access token for the account
+pub fn xoauth2_response(username: &str, access_token: &str) -> String {
+    let response = format!("user={}\x01auth=Bearer {}\x01\x01", username, access_token);
+    base64::encode_block(response.as_bytes())
+}
+
+/// Wait for the browser to be redirected with the authorization code
+fn wait_for_code(login: &Login) -> Result<String, String> {
+    login.listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+    let start = Instant::now();
+
+    loop {
+        let stream = match login.listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(_) if start.elapsed() < Duration::from_secs(LOGIN_TIMEOUT) => {
+                thread::sleep(Duration::from_millis(200));
+                continue;
+            },
+            Err(_) => return Err("Timed out waiting for the login to complete".to_string())
+        };
+
+        stream.set_nonblocking(false).map_err(|e| e.to_string())?;
+        stream.set_read_timeout(Some(Duration::from_secs(TIMEOUT))).map_err(|e| e.to_string())?;
+
+        let mut request_line = String::new();
+        let mut reader = BufReader::new(&stream);
+        if reader.read_line(&mut request_line).is_err() {
+            continue;
+        }
+
+        // Only the query of the request line matters
+        let query = request_line.split_whitespace()
+            .nth(1)
+            .and_then(|target| target.split_once('?'))
+            .map(|(_, query)| parse_query(query))
+            .unwrap_or_default();
+
+        let get = |key: &str| query.iter().find(|p| p.0 == key).map(|p| p.1.clone());
+
+        let (result, page) = if let Some(error) = get("error") {
+            (Some(Err(format!("Login failed: {}", error))), "Login failed, you can close this window.")
+        } else if let Some(code) = get("code") {
+            if get("state").as_ref() == Some(&login.state) {
+                (Some(Ok(code)), "Login completed, you can close this window.")
+            } else {
+                (Some(Err("Login failed: state mismatch".to_string())), "Login failed, you can close this window.")
+            }
+        } else {
+            // Favicons and the like
+            (None, "")
+        };
+
+        let mut stream = &stream;
+        let _ = write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            if result.is_some() {"200 OK"} else {"404 Not Found"},
+            page.len(),
+            page
+        );
+
+        if let Some(result) = result {
+            return result;
+        }
+    }
+}
+
+/// Send a request to the token endpoint and parse the JSON response
+fn token_request(settings: &OAuthSettings, params: &[(&str, &str)]) -> Result<Value, String> {
+    let headers = vec![
+        ("Content-Type".to_string(), "application/x-www-form-urlencoded".to_string()),
+        ("Accept".to_string(), "application/json".to_string())
+    ];
+
+    let response = http::request(
+        "POST", &settings.token_url, &headers, &encode_form(params), Duration::from_secs(TIMEOUT)
+    ).map_err(|e| format!("Token request failed: {}", e))?;
+
+    let json: Value = serde_json::from_str(&response.body)
+        .map_err(|_| format!("Invalid response from token endpoint (HTTP {})", response.status))?;
+
+    if !(200..300).contains(&response.status) {
+        let error = json.get("error_description")
+            .or_else(|| json.get("error"))
+            .and_then(|v| v.as_str())
+            .unwrap_or("unknown error");
+
+        return Err(format!("Token endpoint returned HTTP {}: {}", response.status, error));
+    }
+
+    Ok(json)
+}
+
+/// Make sure a URL can be used with the HTTP client
+fn check_url(key: &str, url: &str) -> Result<(), String> {
+    if url.starts_with("https://") || url.starts_with("http://") {
+        Ok(())
+    } else {
+        Err(format!("Invalid value for `{}`: '{}' (expected an http(s) URL)", key, url))
+    }
+}
+
+/// Generate a random URL-safe token
+fn random_token() -> Result<String, String> {
+    let mut bytes = [0u8; 32];
+    rand_bytes(&mut bytes).map_err(|e| e.to_string())?;
+
+    Ok(base64_url(&bytes))
+}
+
+/// Encode data with the URL-safe base64 alphabet, without padding
+fn base64_url(data: &[u8]) -> String {
+    base64::encode_block(data)
+        .replace('+', "-")
+        .replace('/', "_")
+        .trim_end_matches('=')
+        .to_string()
+}
+
+/// Encode parameters as `application/x-www-form-urlencoded`
+fn encode_form(params: &[(&str, &str)]) -> String {
+    let pairs: Vec<String> = params.iter()
+        .map(|&(k, v)| format!("{}={}", encode(k), encode(v)))
+        .collect();
+
+    pairs.join("&")
+}
+
+/// Percent-encode a value
+fn encode(value: &str) -> String {
+    let mut encoded = String::new();
+
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            },
+            _ => encoded.push_str(&format!("%{:02X}", byte))
+        }
+    }
+
+    encoded
+}
+
+/// Parse the parameters of a query string
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    query.split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let (k, v) = p.split_once('=').unwrap_or((p, ""));
+            (decode(k), decode(v))
+        })
+        .collect()
+}
+
+/// Decode a percent-encoded value
+fn decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                match value.get(i + 1..i + 3).and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                    Some(v) => {
+                        decoded.push(v);
+                        i += 2;
+                    },
+                    None => decoded.push(b'%')
+                }
+            },
+            b => decoded.push(b)
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+    use std::thread::JoinHandle;
+
+    /// Answer a single token request on a local port, returning the form
+    /// sent by the client
+    fn serve(status: u16, body: &'static str) -> (OAuthSettings, JoinHandle<Vec<(String, String)>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let mut account = AccountConfig::new("me@example.com", "localhost");
+        account.oauth_token_url = Some(
+            format!("http://{}/token", listener.local_addr().unwrap())
+        );
+        account.oauth_client_id = Some("client".to_string());
+        account.oauth_client_secret = Some("s3cret".to_string());
+        let settings = get_settings(&account).unwrap();
+
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut length = 0;
+
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some(v) = line.strip_prefix("Content-Length: ") {
+                    length = v.trim().parse().unwrap();
+                }
+                if line == "\r\n" {
+                    break;
+                }
+            }
+
+            let mut form = vec![0u8; length];
+            reader.read_exact(&mut form).unwrap();
+
+            reader.get_mut().write_all(format!(
+                "HTTP/1.1 {} Status\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            ).as_bytes()).unwrap();
+
+            parse_query(&String::from_utf8(form).unwrap())
+        });
+
+        (settings, handle)
+    }
+
+    #[test]
+    fn refresh_rotated_token() {
+        let (settings, handle) = serve(
+            200, r#"{"access_token":"at-123","refresh_token":"rt-new","expires_in":3600}"#
+        );
+
+        let tokens = refresh(&settings, "rt-old").unwrap();
+        assert_eq!(tokens.access_token, "at-123");
+        assert_eq!(tokens.refresh_token, Some("rt-new".to_string()));
+
+        let form = handle.join().unwrap();
+        let get = |key: &str| form.iter().find(|p| p.0 == key).map(|p| p.1.as_str());
+        assert_eq!(get("grant_type"), Some("refresh_token"));
+        assert_eq!(get("refresh_token"), Some("rt-old"));
+        assert_eq!(get("client_id"), Some("client"));
+        assert_eq!(get("client_secret"), Some("s3cret"));
+    }
+
+    #[test]
+    fn refresh_same_token() {
+        let (settings, handle) = serve(200, r#"{"access_token":"at-123","refresh_token":"rt-old"}"#);
+        assert_eq!(refresh(&settings, "rt-old").unwrap().refresh_token, None);
+        handle.join().unwrap();
+
+        let (settings, handle) = serve(200, r#"{"access_token":"at-123"}"#);
+        assert_eq!(refresh(&settings, "rt-old").unwrap().refresh_token, None);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn refresh_errors() {
+        let (settings, handle) = serve(
+            400, r#"{"error":"invalid_grant","error_description":"Token has been revoked"}"#
+        );
+        assert_eq!(
+            refresh(&settings, "rt-old").err().unwrap(),
+            "Token endpoint returned HTTP 400: Token has been revoked"
+        );
+        handle.join().unwrap();
+
+        let (settings, handle) = serve(200, r#"{"token_type":"Bearer"}"#);
+        assert_eq!(
+            refresh(&settings, "rt-old").err().unwrap(),
+            "Token endpoint did not return an access token"
+        );
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn form_encoding() {
+        let params = [("redirect_uri", "http://127.0.0.1:8080/"), ("scope", "a b+c")];
+        let encoded = encode_form(&params);
+        assert_eq!(encoded, "redirect_uri=http%3A%2F%2F127.0.0.1%3A8080%2F&scope=a%20b%2Bc");
+        assert_eq!(parse_query(&encoded), vec![
+            ("redirect_uri".to_string(), "http://127.0.0.1:8080/".to_string()),
+            ("scope".to_string(), "a b+c".to_string())
+        ]);
+    }
+}
@@ -22,23 +22,149 @@
 
 //! ICE mail parser
 
-use std::fs::File;
-use std::path::Path;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
 
 use chrono::prelude::*;
-use ini::Ini;
 use lettre::email::EmailBuilder;
+use openssl::rand::rand_bytes;
 use serde_json;
 use console::style;
 
+use actions::Action;
+use config::{self, Config};
+use shamir;
+use webhook::Webhook;
+
+/// Maximum number of delivery records kept for each ICE mail
+const MAX_HISTORY: usize = 50;
+
+/// Secret split among the recipients of an ICE mail
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SharedSecret {
+    secret: String,
+    threshold: u8
+}
+
+impl SharedSecret {
+    /// Create a new shared secret
+    ///
+    /// # Arguments
+    ///
+    /// * `secret` - Secret to split when the mail is sent
+    /// * `threshold` - Number of shares needed to rebuild the secret
+    pub fn new(secret: String, threshold: u8) -> SharedSecret {
+        SharedSecret {
+            secret,
+            threshold
+        }
+    }
+
+    /// Get the number of shares needed to rebuild the secret
+    pub fn get_threshold(&self) -> u8 {
+        self.threshold
+    }
+}
+
+/// Mail of a triggered ICE mail, kept until it is delivered
+///
+/// The share is stored along with its recipient, since a share from a new
+/// split cannot be combined with the ones already delivered.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PendingDelivery {
+    recipients: Vec<String>,
+    share: Option<String>
+}
+
+impl PendingDelivery {
+    /// Get the recipients of the mail
+    pub fn get_recipients(&self) -> &Vec<String> {
+        &self.recipients
+    }
+}
+
+/// Record of a mail delivered (or not) for an ICE mail
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DeliveryRecord {
+    date: DateTime<Local>,
+    recipients: Vec<String>,
+    /// Account used to send the mail, `None` if every account failed
+    account: Option<String>,
+    response: String
+}
+
+impl DeliveryRecord {
+    /// Create a new delivery record
+    ///
+    /// # Arguments
+    ///
+    /// * `recipients` - Recipients of the mail
+    /// * `account` - Account the mail was sent through, if any
+    /// * `response` - Last response of the server (or error)
+    pub fn new(recipients: Vec<String>, account: Option<String>, response: String) -> DeliveryRecord {
+        DeliveryRecord {
+            date: Local::now(),
+            recipients,
+            account,
+            response
+        }
+    }
+
+    /// Whether the mail was delivered
+    pub fn is_success(&self) -> bool {
+        self.account.is_some()
+    }
+
+    /// Get a short line describing the delivery
+    pub fn get_status_line(&self) -> String {
+        format!(
+            "{} to {} {} ({})",
+            self.date.format("%F %R"),
+            self.recipients.join(", "),
+            match self.account {
+                Some(ref v) => format!("sent through '{}'", v),
+                None => "failed".to_string()
+            },
+            self.response
+        )
+    }
+}
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Ice {
+    /// Assigned when first read for ICE mails stored before identifiers
+    #[serde(default)]
+    id: String,
     description: String,
     message: String,
     emails: Vec<String>,
     active: bool,
-    send_date: Option<DateTime<Local>>
+    send_date: Option<DateTime<Local>>,
+    #[serde(default)]
+    encrypt: bool,
+    #[serde(default)]
+    shared_secret: Option<SharedSecret>,
+    #[serde(default)]
+    actions: Vec<Action>,
+    #[serde(default)]
+    webhooks: Vec<Webhook>,
+    #[serde(default = "default_send_mail")]
+    send_mail: bool,
+    #[serde(default)]
+    account: Option<String>,
+    #[serde(default)]
+    history: Vec<DeliveryRecord>,
+    /// Deliveries still to be retried once the ICE mail was triggered
+    #[serde(default)]
+    pending: Vec<PendingDelivery>
+}
+
+/// ICE mails stored before actions existed are always sent
+fn default_send_mail() -> bool {
+    true
 }
 
 impl Ice {
@@ -50,14 +176,28 @@ impl Ice {
     /// * `message` - Mail contents
     pub fn new(description: String, message: String) -> Ice {
         Ice {
+            id: generate_id(),
             description: description,
             message: message,
             emails: Vec::new(),
             active: false,
-            send_date: None
+            send_date: None,
+            encrypt: false,
+            shared_secret: None,
+            actions: Vec::new(),
+            webhooks: Vec::new(),
+            send_mail: true,
+            account: None,
+            history: Vec::new(),
+            pending: Vec::new()
         }
     }
 
+    /// Get the identifier of the ICE mail
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+
     /// Get the short description of the ICE mail
     pub fn get_description(&self) -> String {
         self.description.clone()
@@ -129,11 +269,223 @@ impl Ice {
 
     /// Update the status of the ICE mail
     ///
+    /// Any pending delivery is dropped, as the ICE mail is either rearmed or
+    /// done with.
+    ///
     /// # Arguments
     ///
     /// * `status` - New status for the ICE mail
     pub fn set_active(&mut self, status: bool) {
         self.active = status;
+        self.pending.clear();
+    }
+
+    /// Get the deliveries that failed since the ICE mail was triggered
+    ///
+    /// An ICE mail with pending deliveries has already been triggered, and
+    /// only those are sent on the next check.
+    pub fn get_pending(&self) -> &Vec<PendingDelivery> {
+        &self.pending
+    }
+
+    /// Update the deliveries still to be retried
+    ///
+    /// # Arguments
+    ///
+    /// * `pending` - Deliveries that failed
+    pub fn set_pending(&mut self, pending: Vec<PendingDelivery>) {
+        self.pending = pending;
+    }
+
+    /// Replace what the user can edit with the contents of another ICE mail
+    ///
+    /// The state of the ICE mail (status, date, deliveries) is kept, so that
+    /// an edit never undoes what happened while it was being made.
+    ///
+    /// # Arguments
+    ///
+    /// * `edited` - Edited copy of the ICE mail
+    pub fn set_contents(&mut self, edited: &Ice) {
+        self.description = edited.description.clone();
+        self.message = edited.message.clone();
+        self.emails = edited.emails.clone();
+        self.encrypt = edited.encrypt;
+        self.shared_secret = edited.shared_secret.clone();
+        self.actions = edited.actions.clone();
+        self.webhooks = edited.webhooks.clone();
+        self.send_mail = edited.send_mail;
+        self.account = edited.account.clone();
+    }
+
+    /// Whether the ICE mail should be encrypted with OpenPGP
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypt
+    }
+
+    /// Update the encryption setting of the ICE mail
+    ///
+    /// # Arguments
+    ///
+    /// * `encrypt` - Whether to encrypt the mail for its recipients
+    pub fn set_encrypted(&mut self, encrypt: bool) {
+        self.encrypt = encrypt;
+    }
+
+    /// Get the secret split among the recipients, if any
+    pub fn get_shared_secret(&self) -> Option<&SharedSecret> {
+        self.shared_secret.as_ref()
+    }
+
+    /// Update the secret split among the recipients
+    ///
+    /// # Arguments
+    ///
+    /// * `secret` - New shared secret (or None to send the mail as is)
+    pub fn set_shared_secret(&mut self, secret: Option<SharedSecret>) {
+        self.shared_secret = secret;
+    }
+
+    /// Get the actions run when the ICE is triggered
+    pub fn get_actions(&self) -> &Vec<Action> {
+        &self.actions
+    }
+
+    /// Get the actions run when the ICE is triggered, for updating them
+    pub fn get_actions_mut(&mut self) -> &mut Vec<Action> {
+        &mut self.actions
+    }
+
+    /// Get the webhooks called when the ICE is triggered
+    pub fn get_webhooks(&self) -> &Vec<Webhook> {
+        &self.webhooks
+    }
+
+    /// Get the webhooks called when the ICE is triggered, for updating them
+    pub fn get_webhooks_mut(&mut self) -> &mut Vec<Webhook> {
+        &mut self.webhooks
+    }
+
+    /// Whether the mail is sent when the ICE is triggered
+    ///
+    /// ICEs may only run their actions instead of sending a mail.
+    pub fn sends_mail(&self) -> bool {
+        self.send_mail
+    }
+
+    /// Update whether the mail is sent when the ICE is triggered
+    ///
+    /// # Arguments
+    ///
+    /// * `send_mail` - Whether to send the mail
+    pub fn set_send_mail(&mut self, send_mail: bool) {
+        self.send_mail = send_mail;
+    }
+
+    /// Whether triggering the ICE delivers anything
+    ///
+    /// An ICE that sends no mail, calls no webhooks and runs no actions
+    /// would be deactivated without anyone being told.
+    pub fn has_deliveries(&self) -> bool {
+        self.send_mail || !self.actions.is_empty() || !self.webhooks.is_empty()
+    }
+
+    /// Get the name of the account preferred for sending the mail, if any
+    pub fn get_account(&self) -> Option<&String> {
+        self.account.as_ref()
+    }
+
+    /// Update the account preferred for sending the mail
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - Name of the account (or None to use the default order)
+    pub fn set_account(&mut self, account: Option<String>) {
+        self.account = account;
+    }
+
+    /// Get the record of previous deliveries, oldest first
+    pub fn get_history(&self) -> &Vec<DeliveryRecord> {
+        &self.history
+    }
+
+    /// Record a delivery, discarding the oldest records if needed
+    ///
+    /// # Arguments
+    ///
+    /// * `record` - Delivery to record
+    pub fn add_history(&mut self, record: DeliveryRecord) {
+        self.history.push(record);
+
+        if self.history.len() > MAX_HISTORY {
+            let excess = self.history.len() - MAX_HISTORY;
+            self.history.drain(..excess);
+        }
+    }
+
+    /// Get the deliveries needed to send the ICE mail
+    ///
+    /// Shared secrets are split into one delivery per recipient, other mails
+    /// have a single delivery to every recipient. Once triggered, only the
+    /// pending deliveries are left.
+    pub fn get_deliveries(&self) -> Result<Vec<PendingDelivery>, &'static str> {
+        if !self.pending.is_empty() {
+            return Ok(self.pending.clone());
+        }
+
+        let shared = match self.shared_secret {
+            Some(ref v) => v,
+            None => return Ok(vec![PendingDelivery {
+                recipients: self.emails.clone(),
+                share: None
+            }])
+        };
+
+        if self.emails.len() > 255 {
+            return Err("Too many recipients to split the secret");
+        }
+
+        let shares = shamir::split(
+            shared.secret.as_bytes(), shared.threshold, self.emails.len() as u8
+        )?;
+
+        Ok(self.emails.iter().zip(shares).map(|(recipient, share)| PendingDelivery {
+            recipients: vec![recipient.clone()],
+            share: Some(share)
+        }).collect())
+    }
+
+    /// Get the ICE mail sent for a delivery
+    ///
+    /// With a shared secret, the mail includes the share of its recipient and
+    /// instructions on how to rebuild the secret.
+    ///
+    /// # Arguments
+    ///
+    /// * `delivery` - Delivery of the ICE mail
+    pub fn get_delivery(&self, delivery: &PendingDelivery) -> Ice {
+        let mut mail = self.clone();
+        mail.shared_secret = None;
+        mail.pending.clear();
+        mail.emails = delivery.recipients.clone();
+
+        if let (Some(share), Some(shared)) = (delivery.share.as_ref(), self.shared_secret.as_ref()) {
+            mail.message = format!(
+                "{}\n\n\
+                ----\n\
+                This mail contains one share of a secret that has been split among {} \
+                recipients. At least {} shares are needed to rebuild it.\n\n\
+                Share: {}\n\n\
+                To rebuild the secret, gather at least {} shares and run \
+                `simpleice combine`, entering one share per line.\n",
+                self.message,
+                self.emails.len(),
+                shared.threshold,
+                share,
+                shared.threshold
+            );
+        }
+
+        mail
     }
 
     /// Get a short line specifying the status of the ICE and the scheduled
@@ -143,7 +495,13 @@ impl Ice {
             "{} ~> {} {}",
             self.description,
             if self.active {style("Active").green()} else {style("Inactive").red()},
-            if self.active {format!("({})", self.get_date_string())} else {"".to_string()}
+            if !self.active {
+                "".to_string()
+            } else if !self.pending.is_empty() {
+                format!("({} mail(s) pending since {})", self.pending.len(), self.get_date_string())
+            } else {
+                format!("({})", self.get_date_string())
+            }
         )
     }
 
@@ -160,36 +518,177 @@ impl Ice {
     }
 }
 
+/// Obtain the path of the JSON file from the configuration
+///
+/// The default path is used when none is configured.
+///
+/// # Arguments
+///
+/// * `conf` - Application configuration
+pub fn get_store_path(conf: &Config) -> Result<PathBuf, &'static str> {
+    match conf.json.path {
+        Some(ref v) => Ok(v.clone()),
+        None => config::get_default_store_path()
+    }
+}
+
 /// Obtain a list of ICE mails from the JSON file
 ///
 /// # Arguments
 ///
 /// * `conf` - Application configuration
-pub fn get_ices(conf: &Ini) -> Result<Vec<Ice>, &'static str> {
-    let json_section = conf.section(Some("json".to_owned())).unwrap();
-    let json_path = Path::new(json_section.get("path").unwrap());
+pub fn get_ices(conf: &Config) -> Result<Vec<Ice>, &'static str> {
+    let json_path = get_store_path(conf)?;
 
     if !json_path.exists() {
         return Err("JSON file does not exist");
     }
 
-    let file = File::open(json_path).unwrap();
-    let ices: Vec<Ice> = serde_json::from_reader(file).unwrap();
+    let file = match File::open(json_path) {
+        Ok(v) => v,
+        Err(_) => return Err("Cannot read JSON file")
+    };
+
+    let mut ices: Vec<Ice> = match serde_json::from_reader(file) {
+        Ok(v) => v,
+        Err(_) => return Err("Invalid JSON file")
+    };
+
+    // Kept once the ICE mails are written back
+    for i in 0..ices.len() {
+        if ices[i].id.is_empty() {
+            let mut id = generate_id();
+            while ices.iter().any(|ice| ice.id == id) {
+                id = generate_id();
+            }
+            ices[i].id = id;
+        }
+    }
 
     Ok(ices)
 }
 
+/// Generate a short random identifier for an ICE mail
+fn generate_id() -> String {
+    let mut bytes = [0u8; 4];
+
+    // Only has to tell ICE mails apart, the clock will do otherwise
+    if rand_bytes(&mut bytes).is_err() {
+        bytes = Local::now().timestamp_subsec_nanos().to_be_bytes();
+    }
+
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
 /// Write a list of ICE mails into the JSON file
 ///
+/// The mails are written to a temporary file that replaces the JSON file
+/// once synced, so that the file is never left half-written.
+///
 /// # Arguments
 ///
 /// * `conf` - Application configuration
 /// * `ices` - List of ICE mails
-pub fn write_ices(conf: &Ini, ices: &Vec<Ice>) -> serde_json::Result<()> {
-    let json_section = conf.section(Some("json".to_owned())).unwrap();
-    let json_path = Path::new(json_section.get("path").unwrap());
+pub fn write_ices(conf: &Config, ices: &Vec<Ice>) -> serde_json::Result<()> {
+    let json_path = get_store_path(conf)
+        .map_err(|e| serde_json::Error::io(io::Error::other(e)))?;
+
+    if let Some(parent) = json_path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).map_err(serde_json::Error::io)?;
+    }
 
-    let file = File::create(json_path).unwrap();
+    let mut temp = json_path.clone().into_os_string();
+    temp.push(format!(".{}.tmp", process::id()));
+    let temp = PathBuf::from(temp);
+
+    let result = write_synced(&temp, &json_path, ices).and_then(|_| {
+        fs::rename(&temp, &json_path).map_err(serde_json::Error::io)
+    });
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+        return result;
+    }
+
+    // Make the rename itself durable
+    if let Some(parent) = json_path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        if let Ok(dir) = File::open(parent) {
+            let _ = dir.sync_all();
+        }
+    }
+
+    Ok(())
+}
 
-    serde_json::to_writer(file, &ices)
+/// Write the ICE mails to a new file and flush it to disk
+///
+/// The file gets the permissions of the JSON file it replaces, if any. New
+/// JSON files are only readable by their owner, as they hold secrets.
+fn write_synced(path: &Path, json_path: &Path, ices: &Vec<Ice>) -> serde_json::Result<()> {
+    let mode = fs::metadata(json_path).ok().map(|m| m.permissions().mode() & 0o7777);
+
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode.unwrap_or(0o600))
+        .open(path)
+        .map_err(serde_json::Error::io)?;
+
+    // The mode given to `open` is subject to the umask
+    if let Some(mode) = mode {
+        file.set_permissions(fs::Permissions::from_mode(mode)).map_err(serde_json::Error::io)?;
+    }
+
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer(&mut writer, &ices)?;
+
+    let file = writer.into_inner().map_err(|e| serde_json::Error::io(e.into_error()))?;
+    file.sync_all().map_err(serde_json::Error::io)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+
+    use config::StoreConfig;
+
+    /// Configuration using a JSON file in a new temporary directory
+    fn test_config(name: &str) -> Config {
+        let dir = env::temp_dir().join(format!("simpleice-test-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+
+        Config {
+            json: StoreConfig {path: Some(dir.join("ices.json"))},
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn write_ices_replaces_store() {
+        let conf = test_config("write");
+        let path = get_store_path(&conf).unwrap();
+
+        write_ices(&conf, &vec![Ice::new("first".to_string(), "message".to_string())]).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+
+        let ices = vec![
+            Ice::new("second".to_string(), "message".to_string()),
+            Ice::new("third".to_string(), "message".to_string())
+        ];
+        write_ices(&conf, &ices).unwrap();
+
+        let read: Vec<String> = get_ices(&conf).unwrap().iter().map(|i| i.get_description()).collect();
+        assert_eq!(read, vec!["second", "third"]);
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o640);
+
+        // Only the JSON file is left behind
+        let parent = path.parent().unwrap();
+        assert_eq!(fs::read_dir(parent).unwrap().count(), 1);
+
+        fs::remove_dir_all(parent).unwrap();
+    }
 }
@@ -0,0 +1,319 @@
+// MIT License
+//
+// Copyright (c) 2017 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Retrieval of the SMTP password
+//!
+//! The password may be obtained, in order of preference, from:
+//!
+//! - `password_command`: a command whose standard output is the password
+//!   (e.g. `pass show smtp`)
+//! - `password_keyring`: the system keyring, either `secret-service` (through
+//!   the `secret-tool` binary) or `kernel` (the user keyring of the kernel,
+//!   which does not persist across reboots, so it is only used when set by
+//!   hand)
+//! - `password`: the password itself, in plaintext
+
+use std::ffi::CString;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use libc;
+
+use config::AccountConfig;
+
+/// Service name under which passwords are stored in keyrings
+const SERVICE: &str = "simpleice";
+
+/// System keyrings supported for storing the password
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Keyring {
+    /// Secret Service API (GNOME Keyring, KWallet...)
+    SecretService,
+    /// Linux kernel user keyring
+    Kernel
+}
+
+impl Keyring {
+    /// Get the name of the keyring used in the configuration file
+    pub fn get_name(&self) -> &'static str {
+        match *self {
+            Keyring::SecretService => "secret-service",
+            Keyring::Kernel => "kernel"
+        }
+    }
+
+    /// Whether the keyring keeps its secrets across reboots
+    ///
+    /// Secrets only kept until the next reboot are not suitable for ICE
+    /// mails, which may be sent long after they were stored.
+    pub fn persists(&self) -> bool {
+        match *self {
+            Keyring::SecretService => true,
+            Keyring::Kernel => false
+        }
+    }
+
+    /// Look up the password of an account
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - Account (sender address) the password belongs to
+    pub fn lookup(&self, account: &str) -> Result<String, String> {
+        match *self {
+            Keyring::SecretService => {
+                let output = Command::new("secret-tool")
+                    .args(["lookup", "service", SERVICE, "username", account])
+                    .stderr(Stdio::null())
+                    .output()
+                    .map_err(|e| format!("Failed to run secret-tool: {}", e))?;
+
+                if !output.status.success() {
+                    return Err(format!("No password for {} in the Secret Service", account));
+                }
+
+                String::from_utf8(output.stdout)
+                    .map_err(|_| "Invalid password in the Secret Service".to_string())
+            },
+            Keyring::Kernel => kernel_lookup(account)
+        }
+    }
+
+    /// Store the password of an account
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - Account (sender address) the password belongs to
+    /// * `password` - Password to store
+    pub fn store(&self, account: &str, password: &str) -> Result<(), String> {
+        match *self {
+            Keyring::SecretService => {
+                let mut child = Command::new("secret-tool")
+                    .args(["store", "--label", &format!("simpleice ({})", account)])
+                    .args(["service", SERVICE, "username", account])
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::piped())
+                    .spawn()
+                    .map_err(|e| format!("Failed to run secret-tool: {}", e))?;
+
+                {
+                    let stdin = child.stdin.as_mut().unwrap();
+                    stdin.write_all(password.as_bytes())
+                        .map_err(|e| format!("Failed to write to secret-tool: {}", e))?;
+                }
+
+                let output = child.wait_with_output()
+                    .map_err(|e| format!("Failed to run secret-tool: {}", e))?;
+
+                if !output.status.success() {
+                    return Err(format!(
+                        "secret-tool failed: {}",
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    ));
+                }
+
+                Ok(())
+            },
+            Keyring::Kernel => kernel_store(account, password)
+        }
+    }
+}
+
+/// Where the password of an account is obtained from
+#[derive(Clone)]
+pub enum PasswordSource {
+    /// Output of a command
+    Command(String),
+    /// System keyring
+    Keyring(Keyring),
+    /// Plaintext password in the configuration file
+    Plaintext(String),
+    /// No password configured
+    None
+}
+
+impl PasswordSource {
+    /// Obtain the password
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - Account (sender address) the password belongs to
+    pub fn get(&self, account: &str) -> Result<String, String> {
+        match *self {
+            PasswordSource::Command(ref command) => run_command(command),
+            PasswordSource::Keyring(ref keyring) => keyring.lookup(account),
+            PasswordSource::Plaintext(ref password) => Ok(password.clone()),
+            PasswordSource::None => Err("No server password found".to_string())
+        }
+    }
+
+    /// Replace the password, which is only possible for keyrings
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - Account (sender address) the password belongs to
+    /// * `password` - New password
+    pub fn update(&self, account: &str, password: &str) -> Result<(), String> {
+        match *self {
+            PasswordSource::Keyring(ref keyring) => keyring.store(account, password),
+            PasswordSource::Command(_) => Err(
+                "the password is obtained from `password_command`, run `auth login` again".to_string()
+            ),
+            PasswordSource::Plaintext(_) | PasswordSource::None => Err(
+                "the password is stored in the configuration file, run `auth login` again".to_string()
+            )
+        }
+    }
+}
+
+/// Obtain the password source of a mail account
+///
+/// # Arguments
+///
+/// * `account` - Mail account of the configuration file
+pub fn get_source(account: &AccountConfig) -> PasswordSource {
+    if let Some(ref command) = account.password_command {
+        if !command.is_empty() {
+            return PasswordSource::Command(command.clone());
+        }
+    }
+
+    if let Some(keyring) = account.password_keyring {
+        return PasswordSource::Keyring(keyring);
+    }
+
+    match account.password {
+        Some(ref v) => PasswordSource::Plaintext(v.clone()),
+        None => PasswordSource::None
+    }
+}
+
+/// Run a password command and obtain the password from its output
+///
+/// Only the first line of the output is used, as done by `pass`.
+fn run_command(command: &str) -> Result<String, String> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| format!("Failed to run password command: {}", e))?;
+
+    if !output.status.success() {
+        return Err(format!("Password command failed with {}", output.status));
+    }
+
+    let stdout = String::from_utf8(output.stdout)
+        .map_err(|_| "Invalid output from password command".to_string())?;
+
+    match stdout.lines().next() {
+        Some(v) if !v.is_empty() => Ok(v.to_string()),
+        _ => Err("Password command did not output a password".to_string())
+    }
+}
+
+/// Description of the kernel key holding the password of an account
+fn kernel_description(account: &str) -> Result<CString, String> {
+    CString::new(format!("{}:{}", SERVICE, account))
+        .map_err(|_| "Invalid account name".to_string())
+}
+
+/// Look up a password in the kernel user keyring
+fn kernel_lookup(account: &str) -> Result<String, String> {
+    let key_type = CString::new("user").unwrap();
+    let description = kernel_description(account)?;
+
+    let key = unsafe {
+        libc::syscall(
+            libc::SYS_request_key,
+            key_type.as_ptr(),
+            description.as_ptr(),
+            std::ptr::null::<libc::c_char>(),
+            0
+        )
+    };
+
+    if key < 0 {
+        return Err(format!("No password for {} in the kernel keyring", account));
+    }
+
+    // Ask for the size first, then read the payload
+    let size = unsafe {
+        libc::syscall(
+            libc::SYS_keyctl,
+            libc::KEYCTL_READ,
+            key,
+            std::ptr::null_mut::<libc::c_char>(),
+            0
+        )
+    };
+
+    if size < 0 {
+        return Err(format!("Cannot read the password for {} from the kernel keyring", account));
+    }
+
+    let mut buffer = vec![0u8; size as usize];
+    let read = unsafe {
+        libc::syscall(
+            libc::SYS_keyctl,
+            libc::KEYCTL_READ,
+            key,
+            buffer.as_mut_ptr(),
+            buffer.len()
+        )
+    };
+
+    if read < 0 {
+        return Err(format!("Cannot read the password for {} from the kernel keyring", account));
+    }
+
+    buffer.truncate(read as usize);
+    String::from_utf8(buffer).map_err(|_| "Invalid password in the kernel keyring".to_string())
+}
+
+/// Store a password in the kernel user keyring
+fn kernel_store(account: &str, password: &str) -> Result<(), String> {
+    let key_type = CString::new("user").unwrap();
+    let description = kernel_description(account)?;
+
+    let key = unsafe {
+        libc::syscall(
+            libc::SYS_add_key,
+            key_type.as_ptr(),
+            description.as_ptr(),
+            password.as_ptr(),
+            password.len(),
+            libc::KEY_SPEC_USER_KEYRING
+        )
+    };
+
+    if key < 0 {
+        return Err(format!(
+            "Cannot store the password in the kernel keyring: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,471 @@
+// MIT License
+//
+// Copyright (c) 2017 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! OpenPGP encryption and signing of ICE mails
+//!
+//! The actual cryptographic operations are delegated to the `gpg` binary,
+//! using the keyring directory specified in the `[pgp]` table of the
+//! configuration file as its home directory. Messages are built following
+//! the PGP/MIME format described in RFC 3156.
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use lettre::email::EmailBuilder;
+
+use config::Config;
+use parser::Ice;
+
+/// Boundary used for the multipart PGP/MIME messages
+const BOUNDARY: &str = "simpleice-pgp-boundary";
+
+/// PGP settings obtained from the configuration file
+pub struct PgpSettings {
+    /// Directory used as home directory for `gpg`
+    keyring: PathBuf,
+    /// Key used for signing outgoing mails, if enabled
+    sign_key: Option<String>,
+}
+
+impl PgpSettings {
+    /// Whether outgoing mails should be signed
+    pub fn signs(&self) -> bool {
+        self.sign_key.is_some()
+    }
+
+    /// Build a `gpg` command using the configured keyring
+    ///
+    /// # Arguments
+    ///
+    /// * `args` - Arguments for `gpg`
+    pub fn command(&self, args: &[&str]) -> Command {
+        let mut command = gpg(self);
+        command.args(args);
+
+        command
+    }
+}
+
+/// Obtain the PGP settings from the configuration file
+///
+/// Returns `None` if there is no `[pgp]` table. The signing key defaults to
+/// the sender address when signing is enabled without a specific key.
+///
+/// # Arguments
+///
+/// * `conf` - Application configuration
+pub fn get_settings(conf: &Config) -> Result<Option<PgpSettings>, &'static str> {
+    let pgp = match conf.pgp {
+        Some(ref v) => v,
+        None => return Ok(None)
+    };
+
+    if !pgp.keyring.is_dir() {
+        return Err("PGP keyring directory does not exist");
+    }
+
+    let sign_key = if !pgp.sign {
+        None
+    } else {
+        match pgp.sign_key {
+            Some(ref v) if !v.is_empty() => Some(v.clone()),
+            _ => match conf.mail {
+                Some(ref account) => Some(account.address.clone()),
+                None => return Err("No PGP signing key found")
+            }
+        }
+    };
+
+    Ok(Some(PgpSettings {
+        keyring: pgp.keyring.clone(),
+        sign_key,
+    }))
+}
+
+/// Check that every key needed to deliver an ICE mail is in the keyring
+///
+/// Returns the list of recipients (and signing key) with no usable key.
+///
+/// # Arguments
+///
+/// * `settings` - PGP settings
+/// * `ice` - ICE mail to check
+pub fn missing_keys(settings: &PgpSettings, ice: &Ice) -> Vec<String> {
+    let mut missing = Vec::new();
+
+    if ice.is_encrypted() {
+        for recipient in ice.get_emails() {
+            if !has_key(settings, recipient, false) {
+                missing.push(recipient.clone());
+            }
+        }
+    }
+
+    if let Some(ref key) = settings.sign_key {
+        if !has_key(settings, key, true) {
+            missing.push(format!("{} (signing key)", key));
+        }
+    }
+
+    missing
+}
+
+/// Build the email for an ICE mail, encrypting and/or signing its contents
+///
+/// The mail is encrypted (and signed, if enabled) when the ICE requires
+/// encryption. Otherwise it is only signed. Recipients and body are set, the
+/// caller is responsible for the rest of the headers.
+///
+/// # Arguments
+///
+/// * `settings` - PGP settings
+/// * `ice` - ICE mail to convert
+pub fn to_email(settings: &PgpSettings, ice: &Ice) -> Result<EmailBuilder, String> {
+    let content = mime_entity(&ice.get_message());
+
+    let (content_type, body) = if ice.is_encrypted() {
+        let encrypted = encrypt(settings, ice.get_emails(), &content)?;
+
+        (
+            format!(
+                "multipart/encrypted; protocol=\"application/pgp-encrypted\"; boundary=\"{}\"",
+                BOUNDARY
+            ),
+            format!(
+                "This is an OpenPGP/MIME encrypted message (RFC 3156)\r\n\
+                --{b}\r\n\
+                Content-Type: application/pgp-encrypted\r\n\
+                Content-Description: PGP/MIME version identification\r\n\
+                \r\n\
+                Version: 1\r\n\
+                \r\n\
+                --{b}\r\n\
+                Content-Type: application/octet-stream; name=\"encrypted.asc\"\r\n\
+                Content-Description: OpenPGP encrypted message\r\n\
+                Content-Disposition: inline; filename=\"encrypted.asc\"\r\n\
+                \r\n\
+                {}\r\n\
+                --{b}--\r\n",
+                encrypted.trim_end(),
+                b = BOUNDARY
+            )
+        )
+    } else {
+        let signature = sign(settings, &content)?;
+
+        (
+            format!(
+                "multipart/signed; micalg=pgp-sha256; \
+                protocol=\"application/pgp-signature\"; boundary=\"{}\"",
+                BOUNDARY
+            ),
+            format!(
+                "This is an OpenPGP/MIME signed message (RFC 3156)\r\n\
+                --{b}\r\n\
+                {}\r\n\
+                --{b}\r\n\
+                Content-Type: application/pgp-signature; name=\"signature.asc\"\r\n\
+                Content-Description: OpenPGP digital signature\r\n\
+                Content-Disposition: attachment; filename=\"signature.asc\"\r\n\
+                \r\n\
+                {}\r\n\
+                --{b}--\r\n",
+                content,
+                signature.trim_end(),
+                b = BOUNDARY
+            )
+        )
+    };
+
+    let mut builder = EmailBuilder::new()
+        .header(("Content-Type", content_type.as_str()))
+        .body(body.as_str());
+
+    for recipient in ice.get_emails() {
+        builder.add_to(recipient.as_str());
+    }
+
+    Ok(builder)
+}
+
+/// Wrap a message in a MIME entity with canonical line endings
+fn mime_entity(message: &str) -> String {
+    let lines: Vec<&str> = message.lines().map(|l| l.trim_end()).collect();
+
+    format!(
+        "Content-Type: text/plain; charset=utf-8\r\n\
+        Content-Transfer-Encoding: 8bit\r\n\
+        \r\n\
+        {}",
+        lines.join("\r\n")
+    )
+}
+
+/// Check if the keyring contains a key for the given user ID
+fn has_key(settings: &PgpSettings, user_id: &str, secret: bool) -> bool {
+    let status = gpg(settings)
+        .arg(if secret {"--list-secret-keys"} else {"--list-keys"})
+        .arg(user_id)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+
+    match status {
+        Ok(v) => v.success(),
+        Err(_) => false
+    }
+}
+
+/// Encrypt some data for the given recipients, signing it if enabled
+fn encrypt(settings: &PgpSettings, recipients: &[String], data: &str) -> Result<String, String> {
+    let mut command = gpg(settings);
+    command.args(["--trust-model", "always", "--armor", "--encrypt"]);
+
+    for recipient in recipients {
+        command.arg("--recipient").arg(recipient);
+    }
+
+    if let Some(ref key) = settings.sign_key {
+        command.arg("--sign").arg("--local-user").arg(key);
+    }
+
+    run(command, data)
+}
+
+/// Create a detached signature of some data
+fn sign(settings: &PgpSettings, data: &str) -> Result<String, String> {
+    let key = match settings.sign_key {
+        Some(ref v) => v,
+        None => return Err("PGP signing is not enabled".to_string())
+    };
+
+    let mut command = gpg(settings);
+    command.args(["--armor", "--detach-sign", "--digest-algo", "SHA256", "--local-user"])
+        .arg(key);
+
+    run(command, data)
+}
+
+/// Base `gpg` command using the configured keyring
+fn gpg(settings: &PgpSettings) -> Command {
+    let mut command = Command::new("gpg");
+    command.arg("--homedir")
+        .arg(&settings.keyring)
+        .args(["--batch", "--quiet", "--no-tty"]);
+
+    command
+}
+
+/// Run a `gpg` command feeding `data` through stdin and return its output
+fn run(mut command: Command, data: &str) -> Result<String, String> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run gpg: {}", e))?;
+
+    {
+        let stdin = child.stdin.as_mut().unwrap();
+        stdin.write_all(data.as_bytes())
+            .map_err(|e| format!("Failed to write to gpg: {}", e))?;
+    }
+
+    let output = child.wait_with_output()
+        .map_err(|e| format!("Failed to run gpg: {}", e))?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "gpg failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    String::from_utf8(output.stdout).map_err(|_| "Invalid gpg output".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process;
+
+    /// Whether `gpg` can be run, tests needing it are skipped otherwise
+    fn has_gpg() -> bool {
+        Command::new("gpg")
+            .arg("--version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|s| s.success())
+    }
+
+    /// Settings using a new empty keyring in a temporary directory
+    fn test_settings(name: &str, sign_key: Option<&str>) -> PgpSettings {
+        let keyring = env::temp_dir().join(format!("simpleice-pgp-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&keyring);
+        fs::create_dir(&keyring).unwrap();
+        fs::set_permissions(&keyring, fs::Permissions::from_mode(0o700)).unwrap();
+
+        PgpSettings {keyring, sign_key: sign_key.map(|k| k.to_string())}
+    }
+
+    /// Generate a key without passphrase for the given user ID
+    fn generate_key(settings: &PgpSettings, user_id: &str) {
+        let status = settings
+            .command(&["--passphrase", "", "--quick-gen-key", user_id, "future-default", "default", "never"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    /// Remove the keyring of the settings, stopping its agent
+    fn remove_keyring(settings: &PgpSettings) {
+        let _ = Command::new("gpgconf")
+            .arg("--homedir")
+            .arg(&settings.keyring)
+            .args(["--kill", "gpg-agent"])
+            .status();
+        let _ = fs::remove_dir_all(&settings.keyring);
+    }
+
+    /// Build the full text of the mail for an ICE, with unfolded headers
+    fn email_text(settings: &PgpSettings, ice: &Ice) -> String {
+        to_email(settings, ice).unwrap()
+            .from("sender@example.com")
+            .subject("ICE")
+            .build()
+            .unwrap()
+            .to_string()
+            .replace("\r\n\t", " ")
+    }
+
+    fn test_ice(encrypted: bool) -> Ice {
+        let mut ice = Ice::new("test".to_string(), "First line  \nSecond line".to_string());
+        ice.set_emails(&vec!["ice@example.com".to_string()]);
+        ice.set_encrypted(encrypted);
+
+        ice
+    }
+
+    #[test]
+    fn mime_entity_uses_canonical_lines() {
+        assert_eq!(
+            mime_entity("First line  \nSecond line\r\n"),
+            "Content-Type: text/plain; charset=utf-8\r\n\
+            Content-Transfer-Encoding: 8bit\r\n\
+            \r\n\
+            First line\r\nSecond line"
+        );
+    }
+
+    #[test]
+    fn missing_keys_lists_recipients_and_signing_key() {
+        let settings = test_settings("missing", Some("sender@example.com"));
+
+        assert_eq!(
+            missing_keys(&settings, &test_ice(true)),
+            vec!["ice@example.com", "sender@example.com (signing key)"]
+        );
+
+        // Recipients do not need keys when the ICE is not encrypted
+        assert_eq!(
+            missing_keys(&settings, &test_ice(false)),
+            vec!["sender@example.com (signing key)"]
+        );
+
+        if has_gpg() {
+            generate_key(&settings, "ice@example.com");
+            assert_eq!(
+                missing_keys(&settings, &test_ice(true)),
+                vec!["sender@example.com (signing key)"]
+            );
+        }
+
+        remove_keyring(&settings);
+    }
+
+    #[test]
+    fn encrypted_email_structure() {
+        if !has_gpg() {
+            return;
+        }
+
+        let settings = test_settings("encrypt", None);
+        generate_key(&settings, "ice@example.com");
+
+        let text = email_text(&settings, &test_ice(true));
+        assert!(text.contains(
+            "Content-Type: multipart/encrypted; protocol=\"application/pgp-encrypted\"; \
+            boundary=\"simpleice-pgp-boundary\""
+        ));
+
+        // Version part followed by the encrypted part, then the closing boundary
+        let parts: Vec<&str> = text.split("--simpleice-pgp-boundary").collect();
+        assert_eq!(parts.len(), 4);
+        assert!(parts[1].starts_with("\r\nContent-Type: application/pgp-encrypted\r\n"));
+        assert!(parts[1].contains("\r\n\r\nVersion: 1\r\n"));
+        assert!(parts[2].starts_with(
+            "\r\nContent-Type: application/octet-stream; name=\"encrypted.asc\"\r\n"
+        ));
+        assert!(parts[3].starts_with("--"));
+
+        // The encrypted part holds the MIME entity of the message
+        let armored = &parts[2][parts[2].find("-----BEGIN PGP MESSAGE-----").unwrap()..];
+        let decrypted = run(settings.command(&["--decrypt"]), armored).unwrap();
+        assert_eq!(decrypted, mime_entity("First line  \nSecond line"));
+
+        remove_keyring(&settings);
+    }
+
+    #[test]
+    fn signed_email_structure() {
+        if !has_gpg() {
+            return;
+        }
+
+        let settings = test_settings("sign", Some("sender@example.com"));
+        generate_key(&settings, "sender@example.com");
+
+        let text = email_text(&settings, &test_ice(false));
+        assert!(text.contains(
+            "Content-Type: multipart/signed; micalg=pgp-sha256; \
+            protocol=\"application/pgp-signature\"; boundary=\"simpleice-pgp-boundary\""
+        ));
+
+        let parts: Vec<&str> = text.split("--simpleice-pgp-boundary").collect();
+        assert_eq!(parts.len(), 4);
+        assert_eq!(parts[1], format!("\r\n{}\r\n", mime_entity("First line  \nSecond line")));
+        assert!(parts[2].starts_with(
+            "\r\nContent-Type: application/pgp-signature; name=\"signature.asc\"\r\n"
+        ));
+        assert!(parts[2].contains("-----BEGIN PGP SIGNATURE-----"));
+
+        remove_keyring(&settings);
+    }
+}
@@ -0,0 +1,348 @@
+// MIT License
+//
+// Copyright (c) 2017 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+//! Scheduling of the `daemon` command
+//!
+//! Instead of checking at fixed intervals, the daemon sleeps until the next
+//! ICE mail is due. The JSON file is read again as soon as it changes, and at
+//! least every `max_poll_interval` seconds (see the `[daemon]` table) in case
+//! a change went unnoticed. ICE mails that could not be sent are retried on
+//! every poll. Requests on the control sockets are accepted while sleeping.
+//!
+//! Only the send date of the ICE mails is scheduled: there are no warning
+//! reminders before an ICE mail is sent, so none are taken into account.
+
+use std::fs;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{self, SystemTime};
+
+use chrono::prelude::*;
+use chrono::Duration;
+
+use clock::Clock;
+use control::{HeldIce, Listener};
+use metrics;
+use parser::Ice;
+use signals;
+use systemd;
+
+/// Default number of seconds after which the JSON file is read again
+pub const DEFAULT_MAX_POLL_INTERVAL: u64 = 3600;
+
+/// Highest allowed value of `max_poll_interval`, one day
+pub const MAX_POLL_INTERVAL: u64 = 86400;
+
+/// Milliseconds between two looks at the JSON files, sockets and signals while
+/// sleeping
+const WATCH_INTERVAL: u64 = 250;
+
+/// Reason why the daemon stopped sleeping
+pub enum Wake {
+    /// The date of the next check was reached
+    Deadline,
+    /// A JSON file was modified, created or removed
+    StoreChanged,
+    /// A signal has to be handled
+    Signal,
+    /// A request arrived on the control socket of a JSON file
+    Request(PathBuf, UnixStream)
+}
+
+/// Obtain when an ICE mail has to be checked next
+///
+/// Returns `None` for mails that are not scheduled.
+///
+/// # Arguments
+///
+/// * `ice` - ICE mail to schedule
+/// * `last_check` - Date of the last check of the ICE mails, if any
+/// * `max_poll` - Maximum time between two reads of the JSON file
+pub fn get_due_date(
+    ice: &Ice,
+    last_check: Option<DateTime<Local>>,
+    max_poll: Duration
+) -> Option<DateTime<Local>> {
+    if !ice.is_active() {
+        return None;
+    }
+
+    let date = ice.get_date()?;
+    match last_check {
+        // Already due on the last check, sending failed and is retried later
+        Some(last) if date <= last => Some(last + max_poll),
+        _ => Some(date)
+    }
+}
+
+/// Obtain when the ICE mails have to be checked next
+///
+/// The check is due when the returned date is not in the future.
+///
+/// # Arguments
+///
+/// * `ices` - ICE mails of a JSON file
+/// * `last_check` - Date of the last check of the ICE mails, if any
+/// * `now` - Current date
+/// * `max_poll` - Maximum time between two reads of the JSON file
+pub fn get_next_check(
+    ices: &[Ice],
+    last_check: Option<DateTime<Local>>,
+    now: DateTime<Local>,
+    max_poll: Duration
+) -> DateTime<Local> {
+    ices.iter()
+        .filter_map(|ice| get_due_date(ice, last_check, max_poll))
+        .fold(now + max_poll, |next, date| next.min(date))
+}
+
+/// Obtain the active ICE mail with the earliest date
+///
+/// # Arguments
+///
+/// * `ices` - ICE mails of a JSON file
+pub fn get_next_ice(ices: &[Ice]) -> Option<&Ice> {
+    ices.iter()
+        .filter(|ice| ice.is_active() && ice.get_date().is_some())
+        .min_by_key(|ice| ice.get_date())
+}
+
+/// Obtain the active ICE mails that became due only because the clock jumped
+///
+/// # Arguments
+///
+/// * `ices` - ICE mails of a JSON file
+/// * `now` - Current date
+/// * `jump` - How far the clock jumped since the previous look, forward when
+///   positive
+pub fn get_jumped_ices(ices: &[Ice], now: DateTime<Local>, jump: Duration) -> Vec<&Ice> {
+    ices.iter()
+        .filter(|ice| ice.is_active())
+        .filter(|ice| ice.get_date().is_some_and(|d| d <= now && d > now - jump))
+        .collect()
+}
+
+/// Whether an ICE mail held after a clock jump has to stay held
+///
+/// Held ICE mails are released once they are removed, deactivated or moved
+/// to another date, and when the clock is set back so that they are not due
+/// anymore.
+///
+/// # Arguments
+///
+/// * `held` - ICE mail held by the daemon
+/// * `ices` - ICE mails of its JSON file
+/// * `now` - Current date
+pub fn is_still_held(held: &HeldIce, ices: &[Ice], now: DateTime<Local>) -> bool {
+    held.date <= now && ices.iter().any(|ice| {
+        ice.get_id() == held.id && ice.is_active() && ice.get_date() == Some(held.date)
+    })
+}
+
+/// Sleep until a date is reached, a JSON file changes, a signal arrives or a
+/// request is received
+///
+/// The wall clock is looked at regularly, so suspending the system does not
+/// delay the wake up.
+///
+/// # Arguments
+///
+/// * `clock` - Source of the current date
+/// * `date` - Date to wake up at
+/// * `stores` - JSON files to watch
+/// * `listeners` - Control sockets to accept requests on
+pub fn sleep_until(
+    clock: &dyn Clock,
+    date: DateTime<Local>,
+    stores: &[PathBuf],
+    listeners: &[Listener]
+) -> Wake {
+    let modified: Vec<Option<SystemTime>> = stores.iter().map(|p| get_modified(p)).collect();
+
+    loop {
+        let remaining = match (date - clock.now()).to_std() {
+            Ok(v) if !v.is_zero() => v,
+            _ => return Wake::Deadline
+        };
+
+        if signals::is_pending() {
+            return Wake::Signal;
+        }
+
+        for listener in listeners {
+            if let Some(stream) = listener.accept() {
+                return Wake::Request(listener.get_store().to_path_buf(), stream);
+            }
+        }
+
+        keep_alive();
+
+        thread::sleep(remaining.min(time::Duration::from_millis(WATCH_INTERVAL)));
+
+        let changed = stores.iter()
+            .zip(modified.iter())
+            .any(|(path, before)| get_modified(path) != *before);
+        if changed {
+            return Wake::StoreChanged;
+        }
+    }
+}
+
+/// Tell the systemd watchdog and the health check that the daemon is working
+///
+/// Checks call it between deliveries and retries too, so that one taking
+/// long because of timeouts does not get the daemon restarted halfway.
+pub fn keep_alive() {
+    systemd::ping_watchdog();
+    metrics::touch();
+}
+
+/// Obtain the modification date of a file, if it exists
+fn get_modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use clock::FixedClock;
+
+    /// Current date of every test
+    fn clock() -> FixedClock {
+        FixedClock(Local.with_ymd_and_hms(2026, 10, 18, 12, 0, 0).unwrap())
+    }
+
+    /// Active ICE mail due at a date
+    fn active(description: &str, date: DateTime<Local>) -> Ice {
+        let mut ice = Ice::new(description.to_string(), "message".to_string());
+        ice.set_date(Some(date));
+        ice.set_active(true);
+
+        ice
+    }
+
+    fn descriptions(ices: &[&Ice]) -> Vec<String> {
+        ices.iter().map(|ice| ice.get_description()).collect()
+    }
+
+    #[test]
+    fn due_date_of_active_ices_only() {
+        let now = clock().now();
+        let max_poll = Duration::hours(1);
+
+        let inactive = Ice::new("inactive".to_string(), "message".to_string());
+        assert_eq!(get_due_date(&inactive, None, max_poll), None);
+
+        let mut undated = Ice::new("undated".to_string(), "message".to_string());
+        undated.set_active(true);
+        assert_eq!(get_due_date(&undated, None, max_poll), None);
+
+        let ice = active("later", now + Duration::minutes(10));
+        assert_eq!(get_due_date(&ice, None, max_poll), Some(now + Duration::minutes(10)));
+        assert_eq!(get_due_date(&ice, Some(now), max_poll), Some(now + Duration::minutes(10)));
+    }
+
+    #[test]
+    fn failed_ices_are_retried_after_max_poll() {
+        let now = clock().now();
+        let max_poll = Duration::hours(1);
+        let ice = active("failed", now - Duration::minutes(10));
+
+        // Due but never checked, it is checked right away
+        assert_eq!(get_due_date(&ice, None, max_poll), Some(now - Duration::minutes(10)));
+        // Already due on the last check
+        assert_eq!(get_due_date(&ice, Some(now), max_poll), Some(now + max_poll));
+    }
+
+    #[test]
+    fn next_check_is_earliest_due_date() {
+        let now = clock().now();
+        let max_poll = Duration::hours(1);
+
+        assert_eq!(get_next_check(&[], None, now, max_poll), now + max_poll);
+
+        let mut inactive = active("inactive", now + Duration::minutes(5));
+        inactive.set_active(false);
+        let ices = vec![
+            active("later", now + Duration::minutes(30)),
+            inactive,
+            active("soon", now + Duration::minutes(20)),
+            active("far", now + Duration::hours(3))
+        ];
+        assert_eq!(get_next_check(&ices, None, now, max_poll), now + Duration::minutes(20));
+
+        let ices = vec![active("far", now + Duration::hours(3))];
+        assert_eq!(get_next_check(&ices, None, now, max_poll), now + max_poll);
+    }
+
+    #[test]
+    fn jumped_ices_became_due_during_jump() {
+        let now = clock().now();
+
+        let mut inactive = active("inactive", now - Duration::hours(1));
+        inactive.set_active(false);
+        let ices = vec![
+            active("jumped", now - Duration::hours(1)),
+            active("now", now),
+            active("before", now - Duration::hours(3)),
+            active("future", now + Duration::hours(1)),
+            inactive
+        ];
+
+        let jumped = get_jumped_ices(&ices, now, Duration::hours(2));
+        assert_eq!(descriptions(&jumped), vec!["jumped", "now"]);
+
+        assert!(get_jumped_ices(&ices, now, -Duration::hours(2)).is_empty());
+    }
+
+    #[test]
+    fn held_until_changed_or_not_due() {
+        let now = clock().now();
+        let date = now - Duration::hours(1);
+        let ices = vec![active("jumped", date)];
+        let ice = &ices[0];
+        let held = HeldIce {
+            id: ice.get_id().to_string(),
+            description: ice.get_description(),
+            date
+        };
+
+        assert!(is_still_held(&held, &ices, now));
+
+        // The clock was set back
+        assert!(!is_still_held(&held, &ices, date - Duration::minutes(1)));
+
+        // The ICE mail was moved to another date
+        let mut moved = ice.clone();
+        moved.set_date(Some(now + Duration::hours(1)));
+        assert!(!is_still_held(&held, &[moved], now));
+
+        // The ICE mail was deactivated or removed
+        let mut deactivated = ice.clone();
+        deactivated.set_active(false);
+        assert!(!is_still_held(&held, &[deactivated], now));
+        assert!(!is_still_held(&held, &[], now));
+    }
+}
@@ -0,0 +1,286 @@
+// MIT License
+//
+// Copyright (c) 2017 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Shamir's secret sharing over GF(256)
+//!
+//! Each byte of the secret is split independently using a random polynomial
+//! of degree `threshold - 1`. Shares are encoded as text so that they can be
+//! delivered by mail and pasted back into the `combine` command:
+//!
+//! `<threshold>-<index>-<hex encoded bytes>`
+
+use std::fs::File;
+use std::io::Read;
+
+/// Split a secret into `shares` shares, `threshold` of which are needed to
+/// reconstruct it
+///
+/// # Arguments
+///
+/// * `secret` - Secret to split
+/// * `threshold` - Minimum number of shares needed to rebuild the secret
+/// * `shares` - Number of shares to generate
+pub fn split(secret: &[u8], threshold: u8, shares: u8) -> Result<Vec<String>, &'static str> {
+    if threshold < 2 {
+        return Err("At least two shares must be required to rebuild the secret");
+    }
+
+    if shares < threshold {
+        return Err("Not enough recipients for the required number of shares");
+    }
+
+    if secret.is_empty() {
+        return Err("Secret cannot be empty");
+    }
+
+    // Random coefficients for every byte of the secret
+    let mut coefficients = vec![0u8; secret.len() * (threshold as usize - 1)];
+    let mut random = File::open("/dev/urandom").map_err(|_| "Cannot access random source")?;
+    random.read_exact(&mut coefficients).map_err(|_| "Cannot access random source")?;
+
+    let mut result = Vec::new();
+
+    for x in 1..(shares as u16 + 1) {
+        let x = x as u8;
+        let mut encoded = format!("{}-{}-", threshold, x);
+
+        for (i, byte) in secret.iter().enumerate() {
+            let poly = &coefficients[i * (threshold as usize - 1)..(i + 1) * (threshold as usize - 1)];
+
+            // Horner's method, highest degree first
+            let mut y = 0u8;
+            for coefficient in poly.iter().rev() {
+                y = gf_mul(y, x) ^ coefficient;
+            }
+            y = gf_mul(y, x) ^ byte;
+
+            encoded.push_str(&format!("{:02x}", y));
+        }
+
+        result.push(encoded);
+    }
+
+    Ok(result)
+}
+
+/// Rebuild a secret from its shares
+///
+/// # Arguments
+///
+/// * `shares` - Shares in the format produced by `split()`
+pub fn combine(shares: &[String]) -> Result<Vec<u8>, &'static str> {
+    let mut points: Vec<(u8, Vec<u8>)> = Vec::new();
+    let mut threshold = None;
+
+    for share in shares {
+        let (t, x, y) = parse_share(share.trim())?;
+
+        if threshold.is_some() && threshold != Some(t) {
+            return Err("Shares belong to different secrets");
+        }
+        threshold = Some(t);
+
+        if points.iter().any(|p| p.0 == x) {
+            continue;
+        }
+        if !points.is_empty() && points[0].1.len() != y.len() {
+            return Err("Shares belong to different secrets");
+        }
+
+        points.push((x, y));
+    }
+
+    match threshold {
+        Some(t) if points.len() >= t as usize => (),
+        Some(_) => return Err("Not enough shares to rebuild the secret"),
+        None => return Err("No shares provided")
+    }
+
+    // Lagrange interpolation at x = 0
+    let mut secret = vec![0u8; points[0].1.len()];
+
+    for (i, &(xi, ref yi)) in points.iter().enumerate() {
+        let mut basis = 1u8;
+
+        for (j, &(xj, _)) in points.iter().enumerate() {
+            if i != j {
+                basis = gf_mul(basis, gf_div(xj, xj ^ xi));
+            }
+        }
+
+        for (byte, y) in secret.iter_mut().zip(yi.iter()) {
+            *byte ^= gf_mul(*y, basis);
+        }
+    }
+
+    Ok(secret)
+}
+
+/// Parse a single share into its threshold, index and values
+fn parse_share(share: &str) -> Result<(u8, u8, Vec<u8>), &'static str> {
+    let parts: Vec<&str> = share.splitn(3, '-').collect();
+    if parts.len() != 3 {
+        return Err("Invalid share format");
+    }
+
+    let threshold = parts[0].parse::<u8>().map_err(|_| "Invalid share format")?;
+    let index = parts[1].parse::<u8>().map_err(|_| "Invalid share format")?;
+
+    if index == 0 || parts[2].is_empty() || !parts[2].is_ascii()
+        || !parts[2].len().is_multiple_of(2) {
+        return Err("Invalid share format");
+    }
+
+    let mut values = Vec::new();
+    for i in 0..parts[2].len() / 2 {
+        let byte = u8::from_str_radix(&parts[2][i * 2..i * 2 + 2], 16)
+            .map_err(|_| "Invalid share format")?;
+        values.push(byte);
+    }
+
+    Ok((threshold, index, values))
+}
+
+/// Multiplication in GF(256) using the AES reduction polynomial
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut result = 0u8;
+
+    while b != 0 {
+        if b & 1 != 0 {
+            result ^= a;
+        }
+
+        let carry = a & 0x80 != 0;
+        a <<= 1;
+        if carry {
+            a ^= 0x1b;
+        }
+
+        b >>= 1;
+    }
+
+    result
+}
+
+/// Division in GF(256), computed as `a * b^254`
+fn gf_div(a: u8, b: u8) -> u8 {
+    let mut inverse = 1u8;
+    for _ in 0..254 {
+        inverse = gf_mul(inverse, b);
+    }
+
+    gf_mul(a, inverse)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"correct horse battery staple";
+
+    /// Every subset of `shares` with exactly `size` elements
+    fn subsets(shares: &[String], size: usize) -> Vec<Vec<String>> {
+        (0u32..1 << shares.len())
+            .filter(|mask| mask.count_ones() as usize == size)
+            .map(|mask| {
+                shares.iter().enumerate()
+                    .filter(|&(i, _)| mask & (1 << i) != 0)
+                    .map(|(_, share)| share.clone())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn combine_every_subset_at_threshold() {
+        for &(threshold, count) in &[(2, 2), (2, 3), (3, 5), (4, 6), (5, 5)] {
+            let shares = split(SECRET, threshold, count).unwrap();
+            assert_eq!(shares.len(), count as usize);
+
+            for size in threshold as usize..count as usize + 1 {
+                for subset in subsets(&shares, size) {
+                    assert_eq!(combine(&subset).unwrap(), SECRET, "{}-of-{} with {:?}",
+                               threshold, count, subset);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn combine_below_threshold() {
+        let shares = split(SECRET, 3, 5).unwrap();
+
+        for size in 1..3 {
+            for subset in subsets(&shares, size) {
+                assert_eq!(combine(&subset), Err("Not enough shares to rebuild the secret"));
+            }
+        }
+
+        assert_eq!(combine(&[]), Err("No shares provided"));
+    }
+
+    #[test]
+    fn combine_duplicate_shares() {
+        let shares = split(SECRET, 2, 3).unwrap();
+
+        let duplicates = vec![shares[0].clone(), shares[0].clone()];
+        assert_eq!(combine(&duplicates), Err("Not enough shares to rebuild the secret"));
+
+        let padded = vec![shares[1].clone(), shares[1].clone(), shares[2].clone()];
+        assert_eq!(combine(&padded).unwrap(), SECRET);
+    }
+
+    #[test]
+    fn combine_mismatched_shares() {
+        let first = split(SECRET, 2, 3).unwrap();
+        let second = split(SECRET, 3, 3).unwrap();
+        assert_eq!(combine(&[first[0].clone(), second[1].clone()]),
+                   Err("Shares belong to different secrets"));
+
+        let shorter = split(b"short", 2, 3).unwrap();
+        assert_eq!(combine(&[first[0].clone(), shorter[1].clone()]),
+                   Err("Shares belong to different secrets"));
+    }
+
+    #[test]
+    fn split_invalid_arguments() {
+        assert!(split(SECRET, 1, 3).is_err());
+        assert!(split(SECRET, 4, 3).is_err());
+        assert!(split(b"", 2, 3).is_err());
+    }
+
+    #[test]
+    fn parse_invalid_shares() {
+        for share in &["", "2-1", "2-0-ab", "2-1-abc", "2-1-zz", "x-1-ab", "2-1-"] {
+            assert_eq!(parse_share(share), Err("Invalid share format"), "{}", share);
+        }
+    }
+
+    #[test]
+    fn gf_division_inverts_multiplication() {
+        for a in 0..=255u8 {
+            for b in 1..=255u8 {
+                assert_eq!(gf_div(gf_mul(a, b), b), a);
+            }
+        }
+    }
+}
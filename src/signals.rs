@@ -0,0 +1,99 @@
+// MIT License
+//
+// Copyright (c) 2017 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+//! Signal handling of the `daemon` command
+//!
+//! - SIGTERM and SIGINT stop the daemon once the current check is finished
+//! - SIGHUP reloads the configuration and the JSON files
+//! - SIGUSR1 checks every profile immediately
+//!
+//! Handlers only set flags, which the daemon looks at between checks and
+//! while sleeping.
+
+use std::io;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use libc;
+
+/// Set when the daemon has to stop
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+/// Set when the configuration has to be reloaded
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+/// Set when a check was requested
+static CHECK: AtomicBool = AtomicBool::new(false);
+
+/// Install the handlers of the signals used by the daemon
+pub fn install() -> Result<(), String> {
+    for signal in &[libc::SIGTERM, libc::SIGINT, libc::SIGHUP, libc::SIGUSR1] {
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            // Interrupted reads and writes are resumed, the flags are enough
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+
+            if libc::sigaction(*signal, &action, ptr::null_mut()) != 0 {
+                return Err(format!(
+                    "Cannot install signal handler: {}", io::Error::last_os_error()
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the daemon has to stop
+pub fn is_shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+/// Whether the configuration has to be reloaded, clearing the request
+pub fn take_reload() -> bool {
+    RELOAD.swap(false, Ordering::SeqCst)
+}
+
+/// Whether a check was requested, clearing the request
+pub fn take_check() -> bool {
+    CHECK.swap(false, Ordering::SeqCst)
+}
+
+/// Whether any request is waiting to be handled
+pub fn is_pending() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst) || RELOAD.load(Ordering::SeqCst)
+        || CHECK.load(Ordering::SeqCst)
+}
+
+/// Record a signal, only async-signal-safe operations are allowed here
+extern "C" fn handle(signal: libc::c_int) {
+    match signal {
+        libc::SIGTERM | libc::SIGINT => SHUTDOWN.store(true, Ordering::SeqCst),
+        libc::SIGHUP => RELOAD.store(true, Ordering::SeqCst),
+        libc::SIGUSR1 => CHECK.store(true, Ordering::SeqCst),
+        _ => ()
+    }
+}
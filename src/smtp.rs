@@ -0,0 +1,975 @@
+// MIT License
+//
+// Copyright (c) 2017 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Minimal SMTP client
+//!
+//! Mails are sent through the account in the `[mail]` table and any other
+//! account in an `[accounts.<name>]` table, which take the same keys. Accounts
+//! are tried in ascending order of their `priority` key (default 0, `[mail]`
+//! first on ties), moving on to the next one when delivery fails.
+//!
+//! The following keys of an account control how the connection to the
+//! server is made:
+//!
+//! - `tls`: `implicit` (TLS from the start, port 465 by default), `starttls`
+//!   (default, STARTTLS is required, port 587 by default), `opportunistic`
+//!   (STARTTLS if offered) or `none`
+//! - `auth`: `plain`, `login`, `cram-md5`, `xoauth2` (see the `oauth` module)
+//!   or `none`. When not set, the first mechanism supported by the server is
+//!   used (only `cram-md5` is used over unencrypted connections)
+//! - `hello_name`: name sent in the EHLO command (default `localhost`)
+//! - `connect_timeout` and `timeout`: timeouts in seconds for establishing
+//!   the connection and for every command (default 30 and 60)
+//! - `ca_file`: PEM bundle of certificate authorities trusted for the server
+//!   certificate, instead of the system ones
+
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use lettre::email::SendableEmail;
+
+use config::{AccountConfig, Config};
+use logger;
+use logger::Level;
+use oauth;
+use oauth::OAuthSettings;
+use password;
+use password::PasswordSource;
+use openssl::base64;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use openssl::ssl::{SslConnector, SslMethod, SslStream};
+
+/// Default timeout for establishing connections, in seconds
+pub const DEFAULT_CONNECT_TIMEOUT: u64 = 30;
+
+/// Default timeout for every command, in seconds
+pub const DEFAULT_TIMEOUT: u64 = 60;
+
+/// Name of the account in the `[mail]` table
+pub const DEFAULT_ACCOUNT: &str = "default";
+
+/// How the connection to the server is encrypted
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsMode {
+    /// TLS from the start of the connection
+    Implicit,
+    /// STARTTLS, failing if the server does not support it
+    #[default]
+    StartTls,
+    /// STARTTLS if supported by the server
+    Opportunistic,
+    /// No encryption
+    None
+}
+
+/// Authentication setting of an account
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMode {
+    Plain,
+    Login,
+    #[serde(rename = "cram-md5")]
+    CramMd5,
+    XOAuth2,
+    /// Do not authenticate
+    None
+}
+
+/// Authentication mechanism used with the server
+#[derive(Clone, Copy, PartialEq)]
+pub enum AuthMechanism {
+    Plain,
+    Login,
+    CramMd5,
+    XOAuth2
+}
+
+impl fmt::Display for AuthMechanism {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AuthMechanism::Plain => write!(f, "PLAIN"),
+            AuthMechanism::Login => write!(f, "LOGIN"),
+            AuthMechanism::CramMd5 => write!(f, "CRAM-MD5"),
+            AuthMechanism::XOAuth2 => write!(f, "XOAUTH2")
+        }
+    }
+}
+
+/// Connection settings for an SMTP server
+#[derive(Clone)]
+pub struct SmtpSettings {
+    name: String,
+    priority: i64,
+    server: String,
+    port: u16,
+    username: String,
+    tls: TlsMode,
+    /// Mechanism to use, `None` to pick one from the server, or no
+    /// authentication at all if `auth = none`
+    auth: Option<AuthMechanism>,
+    use_auth: bool,
+    hello_name: String,
+    connect_timeout: Duration,
+    timeout: Duration,
+    ca_file: Option<PathBuf>,
+    password: PasswordSource,
+    oauth: Option<OAuthSettings>
+}
+
+impl SmtpSettings {
+    /// Get the name of the account
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get the address mails are sent from
+    pub fn get_address(&self) -> &str {
+        &self.username
+    }
+
+    /// Get where the password (or refresh token) is obtained from
+    pub fn get_password_source(&self) -> &PasswordSource {
+        &self.password
+    }
+
+    /// Get the OAuth2 settings, for accounts using XOAUTH2
+    pub fn get_oauth(&self) -> Option<&OAuthSettings> {
+        self.oauth.as_ref()
+    }
+
+    /// Get a short description of the server
+    pub fn get_description(&self) -> String {
+        format!("{}:{}", self.server, self.port)
+    }
+}
+
+/// Reply sent by the server to a command
+pub struct Response {
+    pub code: u16,
+    pub lines: Vec<String>
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.code, self.lines.join(" "))
+    }
+}
+
+/// Obtain the settings of every account, in the order they should be tried
+///
+/// # Arguments
+///
+/// * `conf` - Application configuration
+pub fn get_accounts(conf: &Config) -> Result<Vec<SmtpSettings>, String> {
+    let mut accounts = Vec::new();
+
+    for (name, account) in conf.get_accounts() {
+        let settings = parse_settings(&name, account)
+            .map_err(|e| format!("{} (account '{}')", e, name))?;
+        accounts.push(settings);
+    }
+
+    if accounts.is_empty() {
+        return Err("No mail configuration found".to_string());
+    }
+
+    // Stable sort, so that `[mail]` goes first on ties
+    accounts.sort_by_key(|a| a.priority);
+
+    Ok(accounts)
+}
+
+/// Validate the settings of every account
+///
+/// This goes beyond the checks of the configuration file, loading the
+/// certificates and OAuth2 settings.
+///
+/// # Arguments
+///
+/// * `conf` - Application configuration
+pub fn validate(conf: &Config) -> Result<(), String> {
+    for (name, account) in conf.get_accounts() {
+        parse_settings(&name, account).map_err(|e| format!("{} (account '{}')", e, name))?;
+    }
+
+    Ok(())
+}
+
+/// Obtain the connection settings of an account
+fn parse_settings(name: &str, account: &AccountConfig) -> Result<SmtpSettings, String> {
+    let (auth, use_auth) = match account.auth {
+        Some(AuthMode::Plain) => (Some(AuthMechanism::Plain), true),
+        Some(AuthMode::Login) => (Some(AuthMechanism::Login), true),
+        Some(AuthMode::CramMd5) => (Some(AuthMechanism::CramMd5), true),
+        Some(AuthMode::XOAuth2) => (Some(AuthMechanism::XOAuth2), true),
+        Some(AuthMode::None) => (None, false),
+        None => (None, true)
+    };
+
+    if let Some(ref path) = account.ca_file {
+        let mut builder = SslConnector::builder(SslMethod::tls()).map_err(|e| e.to_string())?;
+        builder.set_ca_file(path).map_err(|e| {
+            format!("Invalid value for `ca_file`: cannot load '{}': {}", path.display(), e)
+        })?;
+    }
+
+    let oauth = if auth == Some(AuthMechanism::XOAuth2) {
+        Some(oauth::get_settings(account)?)
+    } else {
+        None
+    };
+
+    Ok(SmtpSettings {
+        name: name.to_string(),
+        priority: account.priority,
+        server: account.server.clone(),
+        port: account.get_port(),
+        username: account.address.clone(),
+        tls: account.tls,
+        auth,
+        use_auth,
+        hello_name: account.hello_name.clone(),
+        connect_timeout: Duration::from_secs(account.connect_timeout),
+        timeout: Duration::from_secs(account.timeout),
+        ca_file: account.ca_file.clone(),
+        password: password::get_source(account),
+        oauth
+    })
+}
+
+/// Underlying connection to the server
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<SslStream<TcpStream>>)
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut s) => s.read(buf),
+            Stream::Tls(ref mut s) => s.read(buf)
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut s) => s.write(buf),
+            Stream::Tls(ref mut s) => s.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match *self {
+            Stream::Plain(ref mut s) => s.flush(),
+            Stream::Tls(ref mut s) => s.flush()
+        }
+    }
+}
+
+/// Established and authenticated session with an SMTP server
+pub struct Session {
+    stream: BufReader<Stream>,
+    extensions: Vec<String>
+}
+
+impl Session {
+    /// Connect to the server, encrypt the connection and authenticate
+    ///
+    /// # Arguments
+    ///
+    /// * `settings` - Connection settings
+    /// * `password` - Password (or access token) used to authenticate
+    pub fn connect(settings: &SmtpSettings, password: &str) -> Result<Session, String> {
+        let address = (settings.server.as_str(), settings.port)
+            .to_socket_addrs()
+            .map_err(|e| format!("Cannot resolve {}: {}", settings.server, e))?
+            .next()
+            .ok_or_else(|| format!("Cannot resolve {}", settings.server))?;
+
+        let tcp = TcpStream::connect_timeout(&address, settings.connect_timeout)
+            .map_err(|e| format!("Cannot connect to {}: {}", settings.get_description(), e))?;
+        tcp.set_read_timeout(Some(settings.timeout)).map_err(|e| e.to_string())?;
+        tcp.set_write_timeout(Some(settings.timeout)).map_err(|e| e.to_string())?;
+
+        let stream = if settings.tls == TlsMode::Implicit {
+            Stream::Tls(Box::new(tls_connect(settings, tcp)?))
+        } else {
+            Stream::Plain(tcp)
+        };
+
+        let mut session = Session {
+            stream: BufReader::new(stream),
+            extensions: Vec::new()
+        };
+
+        session.expect("greeting", None, &[220])?;
+        session.ehlo(settings)?;
+
+        let encrypted = match settings.tls {
+            TlsMode::Implicit => true,
+            TlsMode::None => false,
+            TlsMode::StartTls | TlsMode::Opportunistic => {
+                if session.supports("STARTTLS") {
+                    session.expect("STARTTLS", Some("STARTTLS"), &[220])?;
+                    session.upgrade(settings)?;
+                    session.ehlo(settings)?;
+                    true
+                } else if settings.tls == TlsMode::StartTls {
+                    return Err("Server does not support STARTTLS".to_string());
+                } else {
+                    false
+                }
+            }
+        };
+
+        if settings.use_auth {
+            session.authenticate(settings, password, encrypted)?;
+        }
+
+        Ok(session)
+    }
+
+    /// Send an email
+    ///
+    /// Returns the final reply of the server, which accepted the mail.
+    ///
+    /// # Arguments
+    ///
+    /// * `email` - Email to send
+    pub fn send<T: SendableEmail>(&mut self, email: &T) -> Result<Response, String> {
+        let mut options = String::new();
+        if self.supports("8BITMIME") {
+            options.push_str(" BODY=8BITMIME");
+        }
+        if self.supports("SMTPUTF8") {
+            options.push_str(" SMTPUTF8");
+        }
+
+        let result = self.transaction(email, &options);
+        if result.is_err() {
+            // Leave the session ready for another mail
+            let _ = self.command("RSET");
+        }
+
+        result
+    }
+
+    /// Close the session
+    pub fn quit(mut self) {
+        let _ = self.command("QUIT");
+    }
+
+    /// Run a mail transaction
+    fn transaction<T: SendableEmail>(&mut self, email: &T, options: &str) -> Result<Response, String> {
+        self.expect(
+            "MAIL FROM", Some(&format!("MAIL FROM:<{}>{}", email.from_address(), options)), &[250]
+        )?;
+
+        for recipient in email.to_addresses() {
+            // 251 means the server forwards the mail elsewhere
+            self.expect("RCPT TO", Some(&format!("RCPT TO:<{}>", recipient)), &[250, 251])?;
+        }
+
+        self.expect("DATA", Some("DATA"), &[354])?;
+
+        // Normalize line endings and escape lines starting with a dot
+        let mut data = String::new();
+        for line in email.message().lines() {
+            if line.starts_with('.') {
+                data.push('.');
+            }
+            data.push_str(line);
+            data.push_str("\r\n");
+        }
+        data.push('.');
+
+        self.expect("message", Some(&data), &[250])
+    }
+
+    /// Send EHLO and record the extensions supported by the server
+    fn ehlo(&mut self, settings: &SmtpSettings) -> Result<(), String> {
+        let response = self.expect("EHLO", Some(&format!("EHLO {}", settings.hello_name)), &[250])?;
+
+        self.extensions = response.lines.iter()
+            .skip(1)
+            .map(|l| l.to_uppercase())
+            .collect();
+
+        Ok(())
+    }
+
+    /// Check if the server supports an extension
+    fn supports(&self, extension: &str) -> bool {
+        self.extensions.iter()
+            .any(|e| e.split_whitespace().next() == Some(extension))
+    }
+
+    /// Check if the server supports an authentication mechanism
+    fn supports_auth(&self, mechanism: AuthMechanism) -> bool {
+        let name = mechanism.to_string();
+
+        self.extensions.iter().any(|e| {
+            let mut words = e.split(|c: char| c.is_whitespace() || c == '=');
+            words.next() == Some("AUTH") && words.any(|w| w == name)
+        })
+    }
+
+    /// Upgrade the connection to TLS after STARTTLS
+    fn upgrade(&mut self, settings: &SmtpSettings) -> Result<(), String> {
+        let tcp = match *self.stream.get_ref() {
+            Stream::Plain(ref s) => s.try_clone().map_err(|e| e.to_string())?,
+            Stream::Tls(_) => return Ok(())
+        };
+
+        self.stream = BufReader::new(Stream::Tls(Box::new(tls_connect(settings, tcp)?)));
+        Ok(())
+    }
+
+    /// Authenticate with the server
+    fn authenticate(&mut self, settings: &SmtpSettings, password: &str, encrypted: bool) -> Result<(), String> {
+        let mechanism = match settings.auth {
+            Some(v) => v,
+            None => {
+                let candidates = if encrypted {
+                    vec![AuthMechanism::Plain, AuthMechanism::Login, AuthMechanism::CramMd5]
+                } else {
+                    // Do not send passwords in clear text unless asked to
+                    vec![AuthMechanism::CramMd5]
+                };
+
+                match candidates.into_iter().find(|m| self.supports_auth(*m)) {
+                    Some(v) => v,
+                    None if !self.supports("AUTH") => return Ok(()),
+                    None => return Err("No supported authentication mechanism available".to_string())
+                }
+            }
+        };
+
+        let username = settings.username.as_str();
+
+        match mechanism {
+            AuthMechanism::Plain => {
+                let credentials = format!("\0{}\0{}", username, password);
+                self.expect(
+                    "authentication",
+                    Some(&format!("AUTH PLAIN {}", base64::encode_block(credentials.as_bytes()))),
+                    &[235]
+                )?;
+            },
+            AuthMechanism::Login => {
+                self.expect("authentication", Some("AUTH LOGIN"), &[334])?;
+                self.expect(
+                    "authentication", Some(&base64::encode_block(username.as_bytes())), &[334]
+                )?;
+                self.expect(
+                    "authentication", Some(&base64::encode_block(password.as_bytes())), &[235]
+                )?;
+            },
+            AuthMechanism::CramMd5 => {
+                let challenge = self.expect("authentication", Some("AUTH CRAM-MD5"), &[334])?;
+                let challenge = challenge.lines.first()
+                    .and_then(|l| base64::decode_block(l.trim()).ok())
+                    .ok_or_else(|| "Invalid CRAM-MD5 challenge".to_string())?;
+
+                let key = PKey::hmac(password.as_bytes()).map_err(|e| e.to_string())?;
+                let mut signer = Signer::new(MessageDigest::md5(), &key).map_err(|e| e.to_string())?;
+                signer.update(&challenge).map_err(|e| e.to_string())?;
+                let digest = signer.sign_to_vec().map_err(|e| e.to_string())?;
+
+                let hex: Vec<String> = digest.iter().map(|b| format!("{:02x}", b)).collect();
+                let answer = format!("{} {}", username, hex.concat());
+                self.expect("authentication", Some(&base64::encode_block(answer.as_bytes())), &[235])?;
+            },
+            AuthMechanism::XOAuth2 => {
+                let response = self.command(
+                    &format!("AUTH XOAUTH2 {}", oauth::xoauth2_response(username, password))
+                )?;
+
+                // Errors come as a challenge that must be answered with an
+                // empty line before the final reply
+                let response = if response.code == 334 {
+                    self.command("")?
+                } else {
+                    response
+                };
+
+                if response.code != 235 {
+                    return Err(format!("Server rejected authentication: {}", response));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Send a command (if any) and require one of the expected replies
+    ///
+    /// # Arguments
+    ///
+    /// * `step` - Name of the step, for error messages
+    /// * `command` - Command to send, or None to only read a reply
+    /// * `codes` - Reply codes accepted for the command
+    fn expect(&mut self, step: &str, command: Option<&str>, codes: &[u16]) -> Result<Response, String> {
+        let response = match command {
+            Some(v) => self.command(v)?,
+            None => self.read_response()?
+        };
+
+        if !codes.contains(&response.code) {
+            return Err(format!("Server rejected {}: {}", step, response));
+        }
+
+        Ok(response)
+    }
+
+    /// Send a command and read the reply
+    fn command(&mut self, command: &str) -> Result<Response, String> {
+        let stream = self.stream.get_mut();
+        stream.write_all(command.as_bytes())
+            .and_then(|_| stream.write_all(b"\r\n"))
+            .and_then(|_| stream.flush())
+            .map_err(|e| format!("Write error: {}", e))?;
+
+        self.read_response()
+    }
+
+    /// Read a (possibly multiline) reply
+    fn read_response(&mut self) -> Result<Response, String> {
+        let mut lines = Vec::new();
+
+        loop {
+            let mut line = String::new();
+            match self.stream.read_line(&mut line) {
+                Ok(0) => return Err("Connection closed by the server".to_string()),
+                Ok(_) => (),
+                Err(e) => return Err(format!("Read error: {}", e))
+            }
+
+            let line = line.trim_end();
+            if line.len() < 3 || !line.is_char_boundary(3) {
+                return Err(format!("Invalid reply from the server: {}", line));
+            }
+
+            let code = line[..3].parse::<u16>()
+                .map_err(|_| format!("Invalid reply from the server: {}", line))?;
+            let last = !line[3..].starts_with('-');
+            lines.push(line.get(4..).unwrap_or("").to_string());
+
+            if last {
+                return Ok(Response { code, lines });
+            }
+        }
+    }
+}
+
+/// Establish a TLS connection with the server
+fn tls_connect(settings: &SmtpSettings, tcp: TcpStream) -> Result<SslStream<TcpStream>, String> {
+    let mut builder = SslConnector::builder(SslMethod::tls()).map_err(|e| e.to_string())?;
+
+    if let Some(ref path) = settings.ca_file {
+        builder.set_ca_file(path).map_err(|e| format!("Cannot load CA bundle: {}", e))?;
+    }
+
+    builder.build()
+        .connect(&settings.server, tcp)
+        .map_err(|e| format!("TLS error: {}", e))
+}
+
+/// Sends mails through an account, connecting to the server when first
+/// needed
+pub struct Mailer {
+    settings: SmtpSettings,
+    password: Option<String>,
+    session: Option<Session>
+}
+
+impl Mailer {
+    /// Create a new mailer
+    ///
+    /// # Arguments
+    ///
+    /// * `settings` - Connection settings of the account
+    pub fn new(settings: SmtpSettings) -> Mailer {
+        Mailer {
+            settings,
+            password: None,
+            session: None
+        }
+    }
+
+    /// Get the settings of the account
+    pub fn get_settings(&self) -> &SmtpSettings {
+        &self.settings
+    }
+
+    /// Send an email, reusing the current session if possible
+    ///
+    /// # Arguments
+    ///
+    /// * `email` - Email to send
+    pub fn send<T: SendableEmail>(&mut self, email: &T) -> Result<Response, String> {
+        self.connect()?;
+
+        let result = self.session.as_mut().unwrap().send(email);
+
+        // Do not reuse sessions that may be broken
+        if result.is_err() {
+            self.close();
+        }
+
+        result
+    }
+
+    /// Connect and authenticate to the server, unless already connected
+    pub fn connect(&mut self) -> Result<(), String> {
+        if self.session.is_some() {
+            return Ok(());
+        }
+
+        // The password is only obtained once, and only if needed
+        if self.password.is_none() && self.settings.use_auth {
+            self.password = Some(self.settings.password.get(&self.settings.username)?);
+        }
+
+        let mut password = self.password.clone().unwrap_or_default();
+
+        // The stored secret is a refresh token, get a fresh access token
+        if let Some(ref oauth) = self.settings.oauth {
+            let tokens = oauth::refresh(oauth, &password)?;
+
+            // The old refresh token may no longer work once rotated
+            if let Some(refresh_token) = tokens.refresh_token {
+                if let Err(e) = self.settings.password.update(&self.settings.username, &refresh_token) {
+                    logger::log(Level::Warn, &format!(
+                        "Cannot store the new refresh token of account '{}': {}",
+                        self.settings.name,
+                        e
+                    ));
+                }
+                self.password = Some(refresh_token);
+            }
+
+            password = tokens.access_token;
+        }
+
+        self.session = Some(Session::connect(&self.settings, &password)?);
+        Ok(())
+    }
+
+    /// Close the current session, if any
+    pub fn close(&mut self) {
+        if let Some(session) = self.session.take() {
+            session.quit();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::net::TcpListener;
+    use std::thread;
+    use std::thread::JoinHandle;
+
+    use lettre::email::{Email, EmailBuilder};
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::{PKey, Private};
+    use openssl::rsa::Rsa;
+    use openssl::ssl::{SslAcceptor, SslMethod};
+    use openssl::x509::{X509, X509NameBuilder};
+    use openssl::x509::extension::SubjectAlternativeName;
+
+    const PASSWORD: &str = "hunter2";
+
+    /// Behaviour of the SMTP stand-in
+    #[derive(Clone, Default)]
+    struct Behaviour {
+        starttls: bool,
+        mail_reply: Option<&'static str>,
+        rejected: Vec<&'static str>
+    }
+
+    /// Stream of the stand-in, plain or TLS
+    trait Io: Read + Write {}
+    impl<T: Read + Write> Io for T {}
+
+    /// Self-signed certificate for `localhost`, written to a CA file
+    ///
+    /// # Arguments
+    ///
+    /// * `port` - Port of the stand-in, to keep the file unique
+    fn certificate(port: u16) -> (X509, PKey<Private>, PathBuf) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        let san = SubjectAlternativeName::new().dns("localhost")
+            .build(&builder.x509v3_context(None, None)).unwrap();
+        builder.append_extension(san).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        let cert = builder.build();
+
+        let path = env::temp_dir().join(
+            format!("simpleice-test-{}-{}.pem", std::process::id(), port)
+        );
+        fs::write(&path, cert.to_pem().unwrap()).unwrap();
+
+        (cert, key, path)
+    }
+
+    /// Serve a single SMTP session on a local port, returning the commands
+    /// received
+    fn serve(behaviour: Behaviour) -> (u16, PathBuf, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (cert, key, path) = certificate(port);
+        let ca_file = path.clone();
+
+        let handle = thread::spawn(move || {
+            let (tcp, _) = listener.accept().unwrap();
+            let raw = tcp.try_clone().unwrap();
+            let mut stream: BufReader<Box<dyn Io>> = BufReader::new(Box::new(tcp));
+            let mut encrypted = false;
+            let mut commands = Vec::new();
+
+            let read = |stream: &mut BufReader<Box<dyn Io>>| {
+                let mut line = String::new();
+                stream.read_line(&mut line).unwrap();
+                line.trim_end().to_string()
+            };
+            let reply = |stream: &mut BufReader<Box<dyn Io>>, text: &str| {
+                stream.get_mut().write_all(format!("{}\r\n", text).as_bytes()).unwrap();
+                stream.get_mut().flush().unwrap();
+            };
+
+            reply(&mut stream, "220 localhost ESMTP");
+
+            loop {
+                let line = read(&mut stream);
+                if line.is_empty() {
+                    break;
+                }
+                commands.push(line.clone());
+
+                let upper = line.to_uppercase();
+                if upper.starts_with("EHLO") {
+                    if behaviour.starttls && !encrypted {
+                        reply(&mut stream, "250-localhost\r\n250-STARTTLS\r\n250 AUTH PLAIN LOGIN");
+                    } else {
+                        reply(&mut stream, "250-localhost\r\n250-8BITMIME\r\n250 AUTH PLAIN LOGIN");
+                    }
+                } else if upper == "STARTTLS" {
+                    reply(&mut stream, "220 Ready to start TLS");
+
+                    let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+                    acceptor.set_private_key(&key).unwrap();
+                    acceptor.set_certificate(&cert).unwrap();
+                    let tls = acceptor.build().accept(raw.try_clone().unwrap()).unwrap();
+                    stream = BufReader::new(Box::new(tls));
+                    encrypted = true;
+                } else if let Some(credentials) = line.strip_prefix("AUTH PLAIN ") {
+                    let expected = format!("\0me@example.com\0{}", PASSWORD);
+                    let valid = base64::decode_block(credentials).unwrap() == expected.as_bytes();
+                    reply(&mut stream, if valid {"235 Authenticated"} else {"535 Invalid"});
+                } else if upper == "AUTH LOGIN" {
+                    reply(&mut stream, "334 VXNlcm5hbWU6");
+                    let username = read(&mut stream);
+                    reply(&mut stream, "334 UGFzc3dvcmQ6");
+                    let password = read(&mut stream);
+
+                    let valid = base64::decode_block(&username).unwrap() == b"me@example.com"
+                        && base64::decode_block(&password).unwrap() == PASSWORD.as_bytes();
+                    reply(&mut stream, if valid {"235 Authenticated"} else {"535 Invalid"});
+                } else if upper.starts_with("MAIL FROM") {
+                    reply(&mut stream, behaviour.mail_reply.unwrap_or("250 OK"));
+                } else if upper.starts_with("RCPT TO") {
+                    if behaviour.rejected.iter().any(|r| line.contains(r)) {
+                        reply(&mut stream, "550 No such user");
+                    } else {
+                        reply(&mut stream, "250 OK");
+                    }
+                } else if upper == "DATA" {
+                    reply(&mut stream, "354 End data with <CR><LF>.<CR><LF>");
+                    while read(&mut stream) != "." {}
+                    reply(&mut stream, "250 2.0.0 queued as ABC123");
+                } else if upper == "RSET" {
+                    reply(&mut stream, "250 OK");
+                } else if upper == "QUIT" {
+                    reply(&mut stream, "221 Bye");
+                    break;
+                } else {
+                    reply(&mut stream, "502 Command not implemented");
+                }
+            }
+
+            fs::remove_file(&ca_file).unwrap();
+            commands
+        });
+
+        (port, path, handle)
+    }
+
+    /// Settings of an account using the stand-in
+    fn settings(port: u16, tls: TlsMode, auth: AuthMode, ca_file: PathBuf) -> SmtpSettings {
+        let mut account = AccountConfig::new("me@example.com", "localhost");
+        account.port = Some(port);
+        account.tls = tls;
+        account.auth = Some(auth);
+        account.ca_file = Some(ca_file);
+        account.password = Some(PASSWORD.to_string());
+
+        parse_settings(DEFAULT_ACCOUNT, &account).unwrap()
+    }
+
+    fn email(to: &[&str]) -> Email {
+        let mut builder = EmailBuilder::new()
+            .from("me@example.com")
+            .subject("Test")
+            .body("Hello");
+        for recipient in to {
+            builder.add_to(*recipient);
+        }
+
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn send_over_starttls() {
+        let (port, ca_file, handle) = serve(Behaviour {starttls: true, ..Behaviour::default()});
+        let settings = settings(port, TlsMode::StartTls, AuthMode::Login, ca_file);
+
+        let mut session = Session::connect(&settings, PASSWORD).unwrap();
+        let response = session.send(&email(&["a@example.com", "b@example.com"])).unwrap();
+        assert_eq!(response.code, 250);
+        session.quit();
+
+        let commands = handle.join().unwrap();
+        let position = |prefix: &str| commands.iter().position(|c| c.starts_with(prefix)).unwrap();
+        assert_eq!(commands[0], "EHLO localhost");
+        assert_eq!(commands[1], "STARTTLS");
+        assert_eq!(commands[2], "EHLO localhost");
+        assert!(position("AUTH LOGIN") > position("STARTTLS"));
+        assert!(commands.contains(&"MAIL FROM:<me@example.com> BODY=8BITMIME".to_string()));
+        assert!(commands.contains(&"RCPT TO:<a@example.com>".to_string()));
+        assert!(commands.contains(&"RCPT TO:<b@example.com>".to_string()));
+        assert_eq!(commands.last().unwrap(), "QUIT");
+    }
+
+    #[test]
+    fn send_with_auth_plain() {
+        let (port, ca_file, handle) = serve(Behaviour::default());
+        let settings = settings(port, TlsMode::None, AuthMode::Plain, ca_file);
+
+        let mut session = Session::connect(&settings, PASSWORD).unwrap();
+        assert!(session.send(&email(&["a@example.com"])).is_ok());
+        session.quit();
+
+        let commands = handle.join().unwrap();
+        assert!(commands.iter().any(|c| c.starts_with("AUTH PLAIN ")));
+        assert!(!commands.contains(&"STARTTLS".to_string()));
+    }
+
+    #[test]
+    fn require_starttls() {
+        let (port, ca_file, handle) = serve(Behaviour::default());
+        let settings = settings(port, TlsMode::StartTls, AuthMode::Plain, ca_file);
+
+        match Session::connect(&settings, PASSWORD) {
+            Err(e) => assert_eq!(e, "Server does not support STARTTLS"),
+            Ok(_) => panic!("connected without STARTTLS")
+        }
+
+        assert!(!handle.join().unwrap().iter().any(|c| c.starts_with("AUTH")));
+    }
+
+    #[test]
+    fn reject_invalid_password() {
+        let (port, ca_file, handle) = serve(Behaviour {starttls: true, ..Behaviour::default()});
+        let settings = settings(port, TlsMode::StartTls, AuthMode::Plain, ca_file);
+
+        match Session::connect(&settings, "wrong") {
+            Err(e) => assert_eq!(e, "Server rejected authentication: 535 Invalid"),
+            Ok(_) => panic!("authenticated with a wrong password")
+        }
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn reject_recipient() {
+        let (port, ca_file, handle) = serve(Behaviour {
+            rejected: vec!["b@example.com"],
+            ..Behaviour::default()
+        });
+        let settings = settings(port, TlsMode::None, AuthMode::Plain, ca_file);
+
+        let mut session = Session::connect(&settings, PASSWORD).unwrap();
+        match session.send(&email(&["a@example.com", "b@example.com"])) {
+            Err(e) => assert_eq!(e, "Server rejected RCPT TO: 550 No such user"),
+            Ok(_) => panic!("mail sent to a rejected recipient")
+        }
+        session.quit();
+
+        let commands = handle.join().unwrap();
+        assert!(commands.contains(&"RSET".to_string()));
+        assert!(!commands.contains(&"DATA".to_string()));
+    }
+
+    #[test]
+    fn reject_unexpected_reply() {
+        let (port, ca_file, handle) = serve(Behaviour {
+            mail_reply: Some("354 Go ahead"),
+            ..Behaviour::default()
+        });
+        let settings = settings(port, TlsMode::None, AuthMode::Plain, ca_file);
+
+        let mut session = Session::connect(&settings, PASSWORD).unwrap();
+        match session.send(&email(&["a@example.com"])) {
+            Err(e) => assert_eq!(e, "Server rejected MAIL FROM: 354 Go ahead"),
+            Ok(_) => panic!("mail sent after an unexpected reply")
+        }
+        session.quit();
+
+        assert!(!handle.join().unwrap().iter().any(|c| c.starts_with("RCPT")));
+    }
+}
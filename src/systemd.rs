@@ -0,0 +1,303 @@
+// MIT License
+//
+// Copyright (c) 2017 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+//! systemd integration
+//!
+//! The `daemon` command reports its state through the notification socket of
+//! services with `Type=notify`: readiness, reloads, a status line with the
+//! next ICE mail and watchdog pings. Nothing is sent when not running under
+//! systemd.
+//!
+//! Unit files for the daemon and for a timer running `check` are generated by
+//! the `install-service` command.
+
+use std::env;
+use std::ffi::CStr;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use libc;
+
+/// Seconds without a watchdog ping after which systemd restarts the daemon
+///
+/// Checks ping it between deliveries, retries and while actions run, so it
+/// only has to cover a single step, such as an SMTP session timing out.
+const WATCHDOG_SEC: u64 = 600;
+
+/// How often the timer unit runs `check`
+const TIMER_CALENDAR: &str = "*:0/5";
+
+/// Name of the unit running the daemon
+pub const SERVICE_UNIT: &str = "simpleice.service";
+
+/// Name of the unit running `check` once
+pub const CHECK_UNIT: &str = "simpleice-check.service";
+
+/// Name of the timer starting the `check` unit
+pub const TIMER_UNIT: &str = "simpleice-check.timer";
+
+/// Watchdog interval requested by systemd, if any
+static WATCHDOG: OnceLock<Option<Duration>> = OnceLock::new();
+
+/// Last time the watchdog was pinged
+static LAST_PING: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Send a state change to systemd
+///
+/// Returns whether the message was sent.
+///
+/// # Arguments
+///
+/// * `state` - Newline separated assignments, such as `READY=1`
+pub fn notify(state: &str) -> bool {
+    let socket = match env::var_os("NOTIFY_SOCKET") {
+        Some(v) => PathBuf::from(v),
+        None => return false
+    };
+
+    send(&socket, state).is_ok()
+}
+
+/// Tell systemd the daemon is ready
+///
+/// # Arguments
+///
+/// * `status` - Status line to show in `systemctl status`
+pub fn notify_ready(status: &str) {
+    notify(&format!("READY=1\nSTATUS={}", status));
+}
+
+/// Tell systemd the configuration is being reloaded
+///
+/// `notify_ready` has to be called once done.
+pub fn notify_reloading() {
+    notify(&format!("RELOADING=1\nMONOTONIC_USEC={}", get_monotonic_usec()));
+}
+
+/// Tell systemd the daemon is stopping
+pub fn notify_stopping() {
+    notify("STOPPING=1");
+}
+
+/// Update the status line of the service
+///
+/// # Arguments
+///
+/// * `status` - Status line to show in `systemctl status`
+pub fn notify_status(status: &str) {
+    notify(&format!("STATUS={}", status));
+}
+
+/// Ping the watchdog when half of its interval has passed
+///
+/// Meant to be called often, through `scheduler::keep_alive`, does nothing
+/// when systemd did not enable the watchdog.
+pub fn ping_watchdog() {
+    let interval = match *WATCHDOG.get_or_init(get_watchdog_interval) {
+        Some(v) => v,
+        None => return
+    };
+
+    let mut last_ping = LAST_PING.lock().unwrap();
+    if last_ping.is_some_and(|l| l.elapsed() < interval / 2) {
+        return;
+    }
+
+    if notify("WATCHDOG=1") {
+        *last_ping = Some(Instant::now());
+    }
+}
+
+/// Send a datagram to the notification socket
+///
+/// Names starting with `@` are in the abstract namespace.
+fn send(socket: &Path, state: &str) -> io::Result<()> {
+    let name = socket.as_os_str().to_string_lossy();
+    let datagram = UnixDatagram::unbound()?;
+
+    match name.strip_prefix('@') {
+        Some(abstract_name) => {
+            let address = SocketAddr::from_abstract_name(abstract_name.as_bytes())?;
+            datagram.send_to_addr(state.as_bytes(), &address)?;
+        },
+        None => {
+            datagram.send_to(state.as_bytes(), socket)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Obtain the watchdog interval from the environment
+///
+/// The interval only applies to the process in `WATCHDOG_PID`, when set.
+fn get_watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+
+    env::var("WATCHDOG_USEC").ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|v| *v > 0)
+        .map(Duration::from_micros)
+}
+
+/// Obtain the monotonic clock used by systemd, in microseconds
+fn get_monotonic_usec() -> u64 {
+    let mut time = libc::timespec {tv_sec: 0, tv_nsec: 0};
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time);
+    }
+
+    time.tv_sec as u64 * 1_000_000 + time.tv_nsec as u64 / 1000
+}
+
+/// Obtain the directory where unit files are installed
+///
+/// # Arguments
+///
+/// * `system` - Whether to use the system units instead of the user ones
+pub fn get_unit_dir(system: bool) -> Result<PathBuf, &'static str> {
+    if system {
+        return Ok(PathBuf::from("/etc/systemd/system"));
+    }
+
+    // Relative paths are invalid and must be ignored
+    match env::var_os("XDG_CONFIG_HOME").map(PathBuf::from) {
+        Some(ref v) if v.is_absolute() => Ok(v.join("systemd/user")),
+        _ => match env::var_os("HOME").filter(|v| !v.is_empty()) {
+            Some(home) => Ok(PathBuf::from(home).join(".config/systemd/user")),
+            None => Err("Cannot find home directory")
+        }
+    }
+}
+
+/// Generate the unit files
+///
+/// Returns the name and contents of each unit.
+///
+/// # Arguments
+///
+/// * `command` - Binary and options to run simpleice with, before the command
+/// * `system` - Whether the units are system units instead of user ones
+pub fn get_units(command: &[String], system: bool) -> Vec<(&'static str, String)> {
+    let command_line = command.iter()
+        .map(|a| quote(a))
+        .collect::<Vec<String>>()
+        .join(" ");
+
+    // System units run as the user that installed them
+    let user = if system {
+        match get_user_name() {
+            Some(name) => format!("User={}\n", name),
+            None => String::new()
+        }
+    } else {
+        String::new()
+    };
+
+    let service = format!(
+        "[Unit]\n\
+        Description=Schedule emails in case of emergency\n\
+        Wants=network-online.target\n\
+        After=network-online.target\n\
+        \n\
+        [Service]\n\
+        Type=notify\n\
+        {}\
+        ExecStart={} daemon\n\
+        ExecReload=/bin/kill -HUP $MAINPID\n\
+        WatchdogSec={}\n\
+        Restart=on-failure\n\
+        \n\
+        [Install]\n\
+        WantedBy={}\n",
+        user,
+        command_line,
+        WATCHDOG_SEC,
+        if system {"multi-user.target"} else {"default.target"}
+    );
+
+    let check = format!(
+        "[Unit]\n\
+        Description=Send the ICE mails that are due\n\
+        Wants=network-online.target\n\
+        After=network-online.target\n\
+        \n\
+        [Service]\n\
+        Type=oneshot\n\
+        {}\
+        ExecStart={} check\n",
+        user,
+        command_line
+    );
+
+    let timer = format!(
+        "[Unit]\n\
+        Description=Periodically send the ICE mails that are due\n\
+        \n\
+        [Timer]\n\
+        OnCalendar={}\n\
+        Persistent=true\n\
+        \n\
+        [Install]\n\
+        WantedBy=timers.target\n",
+        TIMER_CALENDAR
+    );
+
+    vec![(SERVICE_UNIT, service), (CHECK_UNIT, check), (TIMER_UNIT, timer)]
+}
+
+/// Quote an argument of a command line in a unit file
+///
+/// # Arguments
+///
+/// * `arg` - Argument to quote
+fn quote(arg: &str) -> String {
+    // Specifiers and variables are expanded by systemd otherwise
+    let escaped = arg.replace('%', "%%").replace('$', "$$");
+
+    if !escaped.is_empty() && !escaped.contains(|c: char| c.is_whitespace() || "\"'\\;".contains(c)) {
+        return escaped;
+    }
+
+    format!("\"{}\"", escaped.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Obtain the name of the current user
+fn get_user_name() -> Option<String> {
+    unsafe {
+        let passwd = libc::getpwuid(libc::getuid());
+        if passwd.is_null() || (*passwd).pw_name.is_null() {
+            return None;
+        }
+
+        Some(CStr::from_ptr((*passwd).pw_name).to_string_lossy().into_owned())
+    }
+}
@@ -0,0 +1,358 @@
+// MIT License
+//
+// Copyright (c) 2017 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+
+//! Self-monitoring of the scheduler
+//!
+//! Every check writes the time it went through to `<file>.heartbeat` next to
+//! the JSON file, and the daemon does so every time it reads the JSON file.
+//! The `watchdog` command, which may run on another host or from another
+//! crontab, alerts the owner when the heartbeat is stale or when active ICE
+//! mails were not sent in time. The last alert is kept in `<file>.alert`, so
+//! the same problems are only alerted again after a while.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::prelude::*;
+use chrono::Duration;
+
+use parser::Ice;
+
+/// Shortest default number of seconds after which the heartbeat is stale,
+/// enough for the default `max_poll_interval` of the daemon
+pub const DEFAULT_MAX_AGE: u64 = 7200;
+
+/// Default number of seconds an ICE mail may stay unsent after its date
+pub const DEFAULT_GRACE: u64 = 900;
+
+/// Default number of seconds before the same problems are alerted again
+pub const DEFAULT_REPEAT: u64 = 86400;
+
+/// Alert sent to the owner
+pub struct Alert {
+    pub date: DateTime<Local>,
+    pub problems: Vec<String>
+}
+
+/// Record that the scheduler went through the JSON file
+///
+/// The file is replaced at once, so readers never see it half written.
+///
+/// # Arguments
+///
+/// * `store` - Path of the JSON file
+/// * `date` - Date of the heartbeat
+pub fn write_heartbeat(store: &Path, date: DateTime<Local>) -> Result<(), String> {
+    let path = get_heartbeat_path(store);
+    let mut temp = path.clone().into_os_string();
+    temp.push(".tmp");
+
+    fs::write(&temp, format!("{}\n", date.to_rfc3339()))
+        .and_then(|_| fs::rename(&temp, &path))
+        .map_err(|e| format!("Cannot write {}: {}", path.display(), e))
+}
+
+/// Read the last heartbeat of the scheduler
+///
+/// Returns `None` when no check ever went through.
+///
+/// # Arguments
+///
+/// * `store` - Path of the JSON file
+pub fn read_heartbeat(store: &Path) -> Result<Option<DateTime<Local>>, String> {
+    let path = get_heartbeat_path(store);
+    let content = match fs::read_to_string(&path) {
+        Ok(v) => v,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Cannot read {}: {}", path.display(), e))
+    };
+
+    DateTime::parse_from_rfc3339(content.trim())
+        .map(|d| Some(d.with_timezone(&Local)))
+        .map_err(|e| format!("Invalid heartbeat in {}: {}", path.display(), e))
+}
+
+/// Record the alert sent to the owner
+///
+/// Problems are stored one per line, line breaks within them are replaced by
+/// spaces.
+///
+/// # Arguments
+///
+/// * `store` - Path of the JSON file
+/// * `alert` - Alert sent
+pub fn write_alert(store: &Path, alert: &Alert) -> Result<(), String> {
+    let path = get_alert_path(store);
+    let mut temp = path.clone().into_os_string();
+    temp.push(".tmp");
+
+    let mut content = format!("{}\n", alert.date.to_rfc3339());
+    for problem in &alert.problems {
+        content.push_str(&format!("{}\n", to_line(problem)));
+    }
+
+    fs::write(&temp, content)
+        .and_then(|_| fs::rename(&temp, &path))
+        .map_err(|e| format!("Cannot write {}: {}", path.display(), e))
+}
+
+/// Read the last alert sent to the owner
+///
+/// Returns `None` when the problems were solved since.
+///
+/// # Arguments
+///
+/// * `store` - Path of the JSON file
+pub fn read_alert(store: &Path) -> Result<Option<Alert>, String> {
+    let path = get_alert_path(store);
+    let content = match fs::read_to_string(&path) {
+        Ok(v) => v,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Cannot read {}: {}", path.display(), e))
+    };
+
+    let mut lines = content.lines();
+    let date = DateTime::parse_from_rfc3339(lines.next().unwrap_or_default().trim())
+        .map_err(|e| format!("Invalid alert in {}: {}", path.display(), e))?;
+
+    Ok(Some(Alert {
+        date: date.with_timezone(&Local),
+        problems: lines.map(String::from).collect()
+    }))
+}
+
+/// Forget the last alert once its problems are solved
+///
+/// # Arguments
+///
+/// * `store` - Path of the JSON file
+pub fn clear_alert(store: &Path) -> Result<(), String> {
+    let path = get_alert_path(store);
+
+    match fs::remove_file(&path) {
+        Err(ref e) if e.kind() != io::ErrorKind::NotFound => Err(
+            format!("Cannot remove {}: {}", path.display(), e)
+        ),
+        _ => Ok(())
+    }
+}
+
+/// Whether the same problems were alerted recently
+///
+/// # Arguments
+///
+/// * `last` - Last alert sent
+/// * `problems` - Problems found now
+/// * `now` - Current date
+/// * `repeat` - Time before the same problems are alerted again
+pub fn is_repeated(
+    last: &Alert,
+    problems: &[String],
+    now: DateTime<Local>,
+    repeat: Duration
+) -> bool {
+    last.problems.len() == problems.len()
+        && last.problems.iter().zip(problems).all(|(l, p)| *l == to_line(p))
+        && now - last.date < repeat
+}
+
+/// Find what is wrong with the scheduler
+///
+/// Returns a description of every problem found.
+///
+/// # Arguments
+///
+/// * `heartbeat` - Last heartbeat of the scheduler
+/// * `ices` - ICE mails of the JSON file
+/// * `now` - Current date
+/// * `max_age` - Time after which the heartbeat is stale
+/// * `grace` - Time an ICE mail may stay unsent after its date
+pub fn get_problems(
+    heartbeat: Option<DateTime<Local>>,
+    ices: &[Ice],
+    now: DateTime<Local>,
+    max_age: Duration,
+    grace: Duration
+) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    match heartbeat {
+        Some(date) if now - date > max_age => problems.push(format!(
+            "No check went through since {}", date.format("%F %R")
+        )),
+        None => problems.push("No check ever went through".to_string()),
+        _ => ()
+    }
+
+    for ice in ices {
+        if let Some(date) = ice.get_date() {
+            if ice.is_active() && now - date > grace {
+                problems.push(format!(
+                    "ICE mail '{}' was due at {} and is still not sent",
+                    ice.get_description(), date.format("%F %R")
+                ));
+            }
+        }
+    }
+
+    problems
+}
+
+/// Replace the line breaks of a problem, as stored in the last alert
+fn to_line(problem: &str) -> String {
+    problem.replace(['\r', '\n'], " ")
+}
+
+/// Obtain the path of the heartbeat of a JSON file
+fn get_heartbeat_path(store: &Path) -> PathBuf {
+    let mut name = store.as_os_str().to_os_string();
+    name.push(".heartbeat");
+
+    PathBuf::from(name)
+}
+
+/// Obtain the path of the last alert of a JSON file
+fn get_alert_path(store: &Path) -> PathBuf {
+    let mut name = store.as_os_str().to_os_string();
+    name.push(".alert");
+
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::process;
+
+    fn problems(list: &[&str]) -> Vec<String> {
+        list.iter().map(|p| p.to_string()).collect()
+    }
+
+    fn test_ice(description: &str, active: bool, date: Option<DateTime<Local>>) -> Ice {
+        let mut ice = Ice::new(description.to_string(), "message".to_string());
+        ice.set_date(date);
+        ice.set_active(active);
+
+        ice
+    }
+
+    #[test]
+    fn stale_heartbeat_is_a_problem() {
+        let now = Local.with_ymd_and_hms(2026, 10, 18, 12, 0, 0).unwrap();
+        let max_age = Duration::hours(2);
+        let grace = Duration::minutes(15);
+
+        assert!(get_problems(Some(now - Duration::hours(1)), &[], now, max_age, grace).is_empty());
+        assert!(get_problems(Some(now - max_age), &[], now, max_age, grace).is_empty());
+        assert_eq!(
+            get_problems(Some(now - Duration::hours(3)), &[], now, max_age, grace),
+            problems(&["No check went through since 2026-10-18 09:00"])
+        );
+        assert_eq!(
+            get_problems(None, &[], now, max_age, grace),
+            problems(&["No check ever went through"])
+        );
+    }
+
+    #[test]
+    fn overdue_active_ices_are_problems() {
+        let now = Local.with_ymd_and_hms(2026, 10, 18, 12, 0, 0).unwrap();
+        let grace = Duration::minutes(15);
+        let ices = vec![
+            test_ice("overdue", true, Some(now - Duration::hours(1))),
+            test_ice("within grace", true, Some(now - Duration::minutes(10))),
+            test_ice("future", true, Some(now + Duration::hours(1))),
+            test_ice("inactive", false, Some(now - Duration::hours(1))),
+            test_ice("undated", true, None)
+        ];
+
+        assert_eq!(
+            get_problems(Some(now), &ices, now, Duration::hours(2), grace),
+            problems(&["ICE mail 'overdue' was due at 2026-10-18 11:00 and is still not sent"])
+        );
+    }
+
+    #[test]
+    fn repeated_until_interval_passes() {
+        let now = Local.with_ymd_and_hms(2026, 10, 18, 12, 0, 0).unwrap();
+        let alert = Alert {
+            date: now - Duration::hours(2),
+            problems: problems(&["No check ever went through"])
+        };
+        let found = problems(&["No check ever went through"]);
+
+        assert!(is_repeated(&alert, &found, now, Duration::hours(3)));
+        assert!(!is_repeated(&alert, &found, now, Duration::hours(2)));
+    }
+
+    #[test]
+    fn new_problems_are_not_repeated() {
+        let now = Local.with_ymd_and_hms(2026, 10, 18, 12, 0, 0).unwrap();
+        let alert = Alert {
+            date: now - Duration::minutes(5),
+            problems: problems(&["No check ever went through"])
+        };
+        let found = problems(&[
+            "No check ever went through",
+            "ICE mail 'a' was due at 2026-10-18 11:00 and is still not sent"
+        ]);
+
+        assert!(!is_repeated(&alert, &found, now, Duration::hours(3)));
+    }
+
+    #[test]
+    fn alert_round_trip() {
+        let dir = env::temp_dir().join(format!("simpleice-test-{}-alert", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let store = dir.join("ices.json");
+
+        assert!(read_alert(&store).unwrap().is_none());
+
+        let date = Local.with_ymd_and_hms(2026, 10, 18, 12, 0, 0).unwrap();
+        let alert = Alert {date, problems: problems(&["first", "second"])};
+        write_alert(&store, &alert).unwrap();
+
+        let read = read_alert(&store).unwrap().unwrap();
+        assert_eq!(read.date, date);
+        assert_eq!(read.problems, alert.problems);
+
+        clear_alert(&store).unwrap();
+        assert!(read_alert(&store).unwrap().is_none());
+        clear_alert(&store).unwrap();
+
+        // Line breaks would split a problem in two when read back
+        let found = problems(&["ICE mail 'first\nsecond' was due", "third"]);
+        write_alert(&store, &Alert {date, problems: found.clone()}).unwrap();
+
+        let read = read_alert(&store).unwrap().unwrap();
+        assert_eq!(read.problems, problems(&["ICE mail 'first second' was due", "third"]));
+        assert!(is_repeated(&read, &found, date, Duration::hours(3)));
+        clear_alert(&store).unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
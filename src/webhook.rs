@@ -0,0 +1,429 @@
+// MIT License
+//
+// Copyright (c) 2017 Rafael Medina García <rafamedgar@gmail.com>
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Webhook delivery channel
+//!
+//! The body of a webhook is a template in which the following placeholders
+//! are replaced with the (JSON escaped) fields of the ICE:
+//!
+//! - `{{description}}`
+//! - `{{message}}`
+//! - `{{recipients}}` (comma-separated)
+//! - `{{send_date}}`
+//!
+//! When a secret is set, the body is signed with HMAC-SHA256 and the
+//! signature is sent in the `X-Simpleice-Signature` header as
+//! `sha256=<hex digest>`.
+
+use std::thread;
+use std::time::Duration;
+
+use chrono::prelude::*;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use serde_json;
+
+use http;
+use parser::Ice;
+use scheduler;
+
+/// Default body template for new webhooks
+pub const DEFAULT_TEMPLATE: &str = "{\n\
+    \"description\": \"{{description}}\",\n\
+    \"message\": \"{{message}}\",\n\
+    \"recipients\": \"{{recipients}}\",\n\
+    \"send_date\": \"{{send_date}}\"\n\
+}";
+
+/// Timeout for each webhook request
+const TIMEOUT: u64 = 30;
+
+/// Maximum number of bytes of the response body kept
+const MAX_RESPONSE: usize = 4096;
+
+/// Highest number of retries, every check waits for them
+pub const MAX_RETRIES: u32 = 5;
+
+/// Response obtained from the last delivery of a webhook
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WebhookResponse {
+    date: DateTime<Local>,
+    status: Option<u16>,
+    body: String
+}
+
+impl WebhookResponse {
+    /// Whether the webhook was delivered successfully
+    pub fn is_success(&self) -> bool {
+        match self.status {
+            Some(v) => (200..300).contains(&v),
+            None => false
+        }
+    }
+
+    /// Get a short line describing the response
+    pub fn get_status_line(&self) -> String {
+        match self.status {
+            Some(v) => format!("HTTP {} ({})", v, self.date.format("%F %R")),
+            None => format!("{} ({})", self.body, self.date.format("%F %R"))
+        }
+    }
+
+    /// Get the body of the response
+    pub fn get_body(&self) -> &str {
+        &self.body
+    }
+}
+
+/// Webhook called when an ICE is triggered
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Webhook {
+    url: String,
+    method: String,
+    headers: Vec<(String, String)>,
+    template: String,
+    secret: Option<String>,
+    retries: u32,
+    last_response: Option<WebhookResponse>
+}
+
+impl Webhook {
+    /// Create a new webhook
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - URL to call
+    /// * `method` - HTTP method to use
+    /// * `headers` - Additional headers to send
+    /// * `template` - Template for the body of the request
+    /// * `secret` - Secret used to sign the body, if any
+    /// * `retries` - Number of retries if the delivery fails
+    pub fn new(
+        url: String,
+        method: String,
+        headers: Vec<(String, String)>,
+        template: String,
+        secret: Option<String>,
+        retries: u32
+    ) -> Webhook {
+        Webhook {
+            url,
+            method,
+            headers,
+            template,
+            secret,
+            retries,
+            last_response: None
+        }
+    }
+
+    /// Get a short description of the webhook
+    pub fn get_description(&self) -> String {
+        format!("{} {}", self.method, self.url)
+    }
+
+    /// Get the response of the last delivery, if any
+    pub fn get_last_response(&self) -> Option<&WebhookResponse> {
+        self.last_response.as_ref()
+    }
+
+    /// Render the body of the request for an ICE
+    ///
+    /// # Arguments
+    ///
+    /// * `ice` - ICE to render
+    pub fn render(&self, ice: &Ice) -> String {
+        self.template
+            .replace("{{description}}", &escape(&ice.get_description()))
+            .replace("{{message}}", &escape(&ice.get_message()))
+            .replace("{{recipients}}", &escape(&ice.get_emails().join(",")))
+            .replace("{{send_date}}", &escape(&ice.get_date_string()))
+    }
+
+    /// Deliver the webhook for an ICE and record the response
+    ///
+    /// Failed deliveries (connection errors and non 2xx responses) are
+    /// retried with an exponential backoff, at most `MAX_RETRIES` times.
+    ///
+    /// # Arguments
+    ///
+    /// * `ice` - ICE that triggered the webhook
+    pub fn deliver(&mut self, ice: &Ice) -> &WebhookResponse {
+        let body = self.render(ice);
+
+        let mut headers = self.headers.clone();
+        if !headers.iter().any(|h| h.0.eq_ignore_ascii_case("Content-Type")) {
+            headers.push(("Content-Type".to_string(), "application/json".to_string()));
+        }
+
+        if let Some(ref secret) = self.secret {
+            match sign(secret, &body) {
+                Ok(v) => headers.push(("X-Simpleice-Signature".to_string(), v)),
+                Err(e) => {
+                    self.last_response = Some(WebhookResponse {
+                        date: Local::now(),
+                        status: None,
+                        body: e
+                    });
+
+                    return self.last_response.as_ref().unwrap();
+                }
+            }
+        }
+
+        let mut attempt = 0;
+        loop {
+            scheduler::keep_alive();
+
+            let response = match http::request(
+                &self.method, &self.url, &headers, &body, Duration::from_secs(TIMEOUT)
+            ) {
+                Ok(v) => {
+                    let mut body = v.body;
+                    if body.len() > MAX_RESPONSE {
+                        let mut end = MAX_RESPONSE;
+                        while !body.is_char_boundary(end) {
+                            end -= 1;
+                        }
+                        body.truncate(end);
+                    }
+
+                    WebhookResponse {
+                        date: Local::now(),
+                        status: Some(v.status),
+                        body
+                    }
+                },
+                Err(e) => WebhookResponse {
+                    date: Local::now(),
+                    status: None,
+                    body: e
+                }
+            };
+
+            let success = response.is_success();
+            self.last_response = Some(response);
+
+            if success || attempt >= self.retries.min(MAX_RETRIES) {
+                break;
+            }
+
+            thread::sleep(Duration::from_secs(2u64.pow(attempt.min(6))));
+            attempt += 1;
+        }
+
+        self.last_response.as_ref().unwrap()
+    }
+}
+
+/// Parse a header given as `Name: value`
+///
+/// # Arguments
+///
+/// * `line` - Header line
+pub fn parse_header(line: &str) -> Result<(String, String), &'static str> {
+    let mut parts = line.splitn(2, ':');
+    let name = parts.next().unwrap_or("").trim();
+    let value = match parts.next() {
+        Some(v) => v.trim(),
+        None => return Err("Headers must be given as `Name: value`")
+    };
+
+    http::check_header(name, value)?;
+
+    Ok((name.to_string(), value.to_string()))
+}
+
+/// Escape a value to be placed inside a JSON string
+fn escape(value: &str) -> String {
+    let quoted = serde_json::to_string(value).unwrap();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+/// Compute the HMAC-SHA256 signature of a body
+fn sign(secret: &str, body: &str) -> Result<String, String> {
+    let key = PKey::hmac(secret.as_bytes()).map_err(|e| e.to_string())?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key).map_err(|e| e.to_string())?;
+    signer.update(body.as_bytes()).map_err(|e| e.to_string())?;
+    let digest = signer.sign_to_vec().map_err(|e| e.to_string())?;
+
+    let hex: Vec<String> = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    Ok(format!("sha256={}", hex.concat()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+
+    /// Serve one request per status on a local port, returning the raw
+    /// requests received
+    fn serve(statuses: Vec<u16>) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request = String::new();
+                let mut length = 0;
+
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(v) = line.strip_prefix("Content-Length: ") {
+                        length = v.trim().parse().unwrap();
+                    }
+                    request.push_str(&line);
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+
+                let mut body = vec![0u8; length];
+                reader.read_exact(&mut body).unwrap();
+                request.push_str(&String::from_utf8(body).unwrap());
+                requests.push(request);
+
+                reader.get_mut().write_all(format!(
+                    "HTTP/1.1 {} Status\r\nContent-Length: 2\r\n\r\nok", status
+                ).as_bytes()).unwrap();
+            }
+
+            requests
+        });
+
+        (url, handle)
+    }
+
+    #[test]
+    fn deliver_signed_request() {
+        let (url, handle) = serve(vec![200]);
+        let mut webhook = Webhook::new(
+            url,
+            "POST".to_string(),
+            vec![parse_header("Accept: text/plain, application/json").unwrap()],
+            "The quick brown fox jumps over the lazy dog".to_string(),
+            Some("key".to_string()),
+            0
+        );
+
+        let ice = Ice::new("test".to_string(), "message".to_string());
+        assert!(webhook.deliver(&ice).is_success());
+        assert_eq!(webhook.get_last_response().unwrap().get_body(), "ok");
+
+        let requests = handle.join().unwrap();
+        let request = &requests[0];
+        assert!(request.starts_with("POST /hook HTTP/1.1\r\n"));
+        assert!(request.contains("\r\nAccept: text/plain, application/json\r\n"));
+        assert!(request.contains("\r\nContent-Type: application/json\r\n"));
+        // Known HMAC-SHA256 test vector
+        assert!(request.contains("\r\nX-Simpleice-Signature: sha256=\
+            f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8\r\n"));
+        assert!(request.ends_with("\r\n\r\nThe quick brown fox jumps over the lazy dog"));
+    }
+
+    #[test]
+    fn deliver_renders_template() {
+        let (url, handle) = serve(vec![204]);
+        let mut webhook = Webhook::new(
+            url, "PUT".to_string(), Vec::new(), DEFAULT_TEMPLATE.to_string(), None, 0
+        );
+
+        let ice = Ice::new("say \"hi\"".to_string(), "line\nbreak".to_string());
+        assert!(webhook.deliver(&ice).is_success());
+
+        let request = handle.join().unwrap().remove(0);
+        assert!(request.starts_with("PUT /hook HTTP/1.1\r\n"));
+        assert!(!request.contains("X-Simpleice-Signature"));
+        assert!(request.contains("\"description\": \"say \\\"hi\\\"\""));
+        assert!(request.contains("\"message\": \"line\\nbreak\""));
+    }
+
+    #[test]
+    fn deliver_retries_failures() {
+        let (url, handle) = serve(vec![500, 202]);
+        let mut webhook = Webhook::new(
+            url, "POST".to_string(), Vec::new(), "{}".to_string(), None, 1
+        );
+
+        let ice = Ice::new("test".to_string(), "message".to_string());
+        assert!(webhook.deliver(&ice).is_success());
+        assert_eq!(handle.join().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn deliver_reports_failures() {
+        let (url, handle) = serve(vec![503]);
+        let mut webhook = Webhook::new(
+            url, "POST".to_string(), Vec::new(), "{}".to_string(), None, 0
+        );
+
+        let ice = Ice::new("test".to_string(), "message".to_string());
+        assert!(!webhook.deliver(&ice).is_success());
+        assert!(webhook.get_last_response().unwrap().get_status_line().starts_with("HTTP 503"));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn deliver_refuses_injected_headers() {
+        let mut webhook = Webhook::new(
+            "http://127.0.0.1:9/hook".to_string(),
+            "POST".to_string(),
+            vec![("X-Test".to_string(), "a\r\nX-Injected: b".to_string())],
+            "{}".to_string(),
+            None,
+            0
+        );
+
+        let ice = Ice::new("test".to_string(), "message".to_string());
+        assert!(!webhook.deliver(&ice).is_success());
+        assert_eq!(
+            webhook.get_last_response().unwrap().get_body(),
+            "Header values cannot contain control characters: X-Test"
+        );
+    }
+
+    #[test]
+    fn parse_headers() {
+        assert_eq!(
+            parse_header(" Accept :  a, b ").unwrap(),
+            ("Accept".to_string(), "a, b".to_string())
+        );
+        assert_eq!(
+            parse_header("Authorization: Bearer a:b").unwrap(),
+            ("Authorization".to_string(), "Bearer a:b".to_string())
+        );
+        assert!(parse_header("Accept").is_err());
+        assert!(parse_header(": value").is_err());
+        assert!(parse_header("Bad Name: value").is_err());
+        assert!(parse_header("X-Test: a\rb").is_err());
+        assert!(parse_header("X-Test: a\nX-Injected: b").is_err());
+    }
+}